@@ -0,0 +1,445 @@
+use std::{collections::HashMap, fmt, net::IpAddr, path::Path};
+
+use anyhow::{Context, Result};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+/// Destinations nobody should reach through a modem: loopback, RFC1918, CGNAT,
+/// link-local (cloud metadata endpoints), IETF and benchmarking ranges, the modem's own
+/// admin UI on 192.168.8.1, and the NAT64/6to4 prefixes that embed IPv4 addresses.
+const DEFAULT_DENY_CIDRS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "64:ff9b::/96",
+    "2002::/16",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+const DEFAULT_DENY_HOSTS: &[&str] = &["localhost", "*.localhost", "*.local", "*.internal"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// Inclusive port range, written as `443` or `8000-8999`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortRange(pub u16, pub u16);
+
+impl PortRange {
+    fn contains(&self, port: u16) -> bool {
+        self.0 <= port && port <= self.1
+    }
+}
+
+impl std::str::FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |p: &str| {
+            p.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("invalid port `{}`", p))
+        };
+        match s.split_once('-') {
+            Some((lo, hi)) => {
+                let (lo, hi) = (parse(lo)?, parse(hi)?);
+                if lo > hi {
+                    return Err(format!("invalid port range `{}`", s));
+                }
+                Ok(PortRange(lo, hi))
+            }
+            None => parse(s).map(|p| PortRange(p, p)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Num(u16),
+            Str(String),
+        }
+        match Raw::deserialize(d)? {
+            Raw::Num(p) => Ok(PortRange(p, p)),
+            Raw::Str(s) => s.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+impl Serialize for PortRange {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        if self.0 == self.1 {
+            s.serialize_u16(self.0)
+        } else {
+            s.serialize_str(&format!("{}-{}", self.0, self.1))
+        }
+    }
+}
+
+/// A single policy rule. Every non-empty criterion has to match for the rule to apply.
+///
+/// Host patterns are matched case-insensitively against the requested domain name:
+/// `*` is a wildcard (`*.example.com`), a leading dot is a suffix match (`.example.com`
+/// matches the domain and all subdomains), anything else is an exact match.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Rule {
+    pub action: Action,
+    #[serde(default)]
+    pub cidrs: Vec<IpNet>,
+    #[serde(default)]
+    pub ports: Vec<PortRange>,
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+impl Rule {
+    /// Without an `ip` (a name left for an upstream to resolve) rules with CIDRs never match.
+    fn matches(&self, host: Option<&str>, ip: Option<IpAddr>, port: u16) -> bool {
+        (self.cidrs.is_empty()
+            || ip.is_some_and(|ip| self.cidrs.iter().any(|net| net.contains(&ip))))
+            && (self.ports.is_empty() || self.ports.iter().any(|r| r.contains(port)))
+            && (self.hosts.is_empty()
+                || host.is_some_and(|h| self.hosts.iter().any(|p| host_matches(p, h))))
+    }
+
+    fn describe(&self) -> String {
+        if let Some(reason) = &self.reason {
+            return reason.clone();
+        }
+        let mut parts = Vec::new();
+        if !self.cidrs.is_empty() {
+            parts.push(format!("cidrs={:?}", self.cidrs));
+        }
+        if !self.ports.is_empty() {
+            parts.push(format!("ports={:?}", self.ports));
+        }
+        if !self.hosts.is_empty() {
+            parts.push(format!("hosts={:?}", self.hosts));
+        }
+        format!("{:?} rule {}", self.action, parts.join(" "))
+    }
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    if let Some(suffix) = pattern.strip_prefix('.') {
+        return host == suffix || host.ends_with(&pattern);
+    }
+    glob(pattern.as_bytes(), host.as_bytes())
+}
+
+/// Minimal `*` glob matcher.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|i| glob(rest, &text[i..])),
+        Some((c, rest)) => text.first() == Some(c) && glob(rest, &text[1..]),
+    }
+}
+
+/// Treat `::ffff:a.b.c.d` like `a.b.c.d` so mapped addresses can't dodge IPv4 rules.
+pub(crate) fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        v4 => v4,
+    }
+}
+
+/// On-disk ACL configuration.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AclConfig {
+    /// Append the built-in deny-list after the configured rules.
+    #[serde(default = "default_true")]
+    pub default_deny: bool,
+    /// Rules checked for every user.
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    /// Rules checked before the global ones for a single user.
+    #[serde(default)]
+    pub users: HashMap<String, Vec<Rule>>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for AclConfig {
+    fn default() -> Self {
+        AclConfig {
+            default_deny: true,
+            rules: Vec::new(),
+            users: HashMap::new(),
+        }
+    }
+}
+
+/// Why a destination was refused.
+#[derive(Debug, Clone)]
+pub struct Denied {
+    pub target: String,
+    pub reason: String,
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.target, self.reason)
+    }
+}
+
+/// Destination policy: per-user rules, then global rules, then the built-in
+/// deny-list. The first matching rule wins; no match means allow.
+#[derive(Clone, Debug)]
+pub struct AclPolicy {
+    rules: Vec<Rule>,
+    users: HashMap<String, Vec<Rule>>,
+}
+
+impl Default for AclPolicy {
+    fn default() -> Self {
+        AclPolicy::new(AclConfig::default())
+    }
+}
+
+impl AclPolicy {
+    pub fn new(cfg: AclConfig) -> Self {
+        let mut rules = cfg.rules;
+        if cfg.default_deny {
+            rules.push(Rule {
+                action: Action::Deny,
+                cidrs: DEFAULT_DENY_CIDRS
+                    .iter()
+                    .map(|c| c.parse().expect("valid built-in CIDR"))
+                    .collect(),
+                ports: Vec::new(),
+                hosts: Vec::new(),
+                reason: Some("private or reserved address".to_string()),
+            });
+            rules.push(Rule {
+                action: Action::Deny,
+                cidrs: Vec::new(),
+                ports: Vec::new(),
+                hosts: DEFAULT_DENY_HOSTS.iter().map(|h| h.to_string()).collect(),
+                reason: Some("local hostname".to_string()),
+            });
+        }
+        AclPolicy {
+            rules,
+            users: cfg.users,
+        }
+    }
+
+    /// Load the policy from a JSON file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data =
+            std::fs::read(path).with_context(|| format!("read ACL file {}", path.display()))?;
+        let cfg: AclConfig = serde_json::from_slice(&data)
+            .with_context(|| format!("parse ACL file {}", path.display()))?;
+        Ok(AclPolicy::new(cfg))
+    }
+
+    /// Check one resolved destination. `host` is the requested domain name, if any.
+    pub fn check(
+        &self,
+        user: &str,
+        host: Option<&str>,
+        ip: IpAddr,
+        port: u16,
+    ) -> Result<(), Denied> {
+        let ip = canonical(ip);
+        self.first_match(user, host, Some(ip), port, || match host {
+            Some(h) => format!("{}:{} ({})", h, port, ip),
+            None => format!("{}", std::net::SocketAddr::new(ip, port)),
+        })
+    }
+
+    /// Check a domain that is not resolved here, only by host and port rules.
+    pub fn check_host(&self, user: &str, host: &str, port: u16) -> Result<(), Denied> {
+        self.first_match(user, Some(host), None, port, || {
+            format!("{}:{}", host, port)
+        })
+    }
+
+    fn first_match(
+        &self,
+        user: &str,
+        host: Option<&str>,
+        ip: Option<IpAddr>,
+        port: u16,
+        target: impl FnOnce() -> String,
+    ) -> Result<(), Denied> {
+        let user_rules = self.users.get(user).into_iter().flatten();
+        match user_rules
+            .chain(&self.rules)
+            .find(|r| r.matches(host, ip, port))
+        {
+            Some(rule) if rule.action == Action::Deny => Err(Denied {
+                target: target(),
+                reason: rule.describe(),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Client source addresses allowed to use a listener. An empty list allows everyone.
+#[derive(Clone, Debug, Default)]
+pub struct SourceAllowlist(Vec<IpNet>);
+
+impl SourceAllowlist {
+    pub fn new(nets: Vec<IpNet>) -> Self {
+        SourceAllowlist(nets)
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let ip = canonical(ip);
+        self.0.is_empty() || self.0.iter().any(|net| net.contains(&ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(json: &str) -> AclPolicy {
+        AclPolicy::new(serde_json::from_str(json).unwrap())
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_host_patterns() {
+        assert!(host_matches("*.example.com", "api.example.com"));
+        assert!(host_matches("*.example.com", "a.b.example.com"));
+        assert!(!host_matches("*.example.com", "example.com"));
+        assert!(host_matches("api-*.example.com", "api-eu.example.com"));
+        assert!(!host_matches("api-*.example.com", "web.example.com"));
+
+        assert!(host_matches(".example.com", "example.com"));
+        assert!(host_matches(".example.com", "www.example.com"));
+        assert!(!host_matches(".example.com", "badexample.com"));
+
+        assert!(host_matches("Example.COM", "example.com."));
+        assert!(!host_matches("example.com", "www.example.com"));
+    }
+
+    #[test]
+    fn default_deny_list() {
+        let acl = AclPolicy::default();
+        for denied in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.8.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "::1",
+            "fe80::1",
+            "::ffff:192.168.8.1",
+            "::ffff:169.254.169.254",
+            "64:ff9b::a9fe:a9fe",
+        ] {
+            assert!(acl.check("u", None, ip(denied), 80).is_err(), "{}", denied);
+        }
+        for allowed in ["1.1.1.1", "2606:4700:4700::1111", "::ffff:1.1.1.1"] {
+            assert!(
+                acl.check("u", None, ip(allowed), 443).is_ok(),
+                "{}",
+                allowed
+            );
+        }
+
+        assert!(acl.check_host("u", "localhost", 80).is_err());
+        assert!(acl.check_host("u", "printer.local", 80).is_err());
+        assert!(acl.check_host("u", "metadata.google.internal", 80).is_err());
+        assert!(acl.check_host("u", "example.com", 80).is_ok());
+
+        let acl = policy(r#"{"default_deny": false}"#);
+        assert!(acl.check("u", None, ip("192.168.8.1"), 80).is_ok());
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let acl = policy(
+            r#"{"rules": [
+                {"action": "allow", "cidrs": ["192.168.8.1/32"], "ports": [80]},
+                {"action": "deny", "ports": ["25", "6660-6669"], "reason": "no mail or IRC"},
+                {"action": "allow", "ports": [25]}
+            ]}"#,
+        );
+        // the allow comes before the built-in deny-list
+        assert!(acl.check("u", None, ip("192.168.8.1"), 80).is_ok());
+        assert!(acl.check("u", None, ip("192.168.8.1"), 443).is_err());
+
+        let denied = acl.check("u", None, ip("1.1.1.1"), 25).unwrap_err();
+        assert_eq!(denied.reason, "no mail or IRC");
+        assert_eq!(denied.target, "1.1.1.1:25");
+        assert!(acl.check("u", None, ip("1.1.1.1"), 6667).is_err());
+        assert!(acl.check("u", None, ip("1.1.1.1"), 6670).is_ok());
+    }
+
+    #[test]
+    fn user_rules_come_first() {
+        let acl = policy(
+            r#"{
+                "rules": [{"action": "deny", "hosts": [".example.com"]}],
+                "users": {
+                    "alice": [
+                        {"action": "allow", "hosts": ["api.example.com"]},
+                        {"action": "deny", "cidrs": ["203.0.113.0/24"]}
+                    ]
+                }
+            }"#,
+        );
+        assert!(acl.check_host("alice", "api.example.com", 443).is_ok());
+        assert!(acl.check_host("alice", "www.example.com", 443).is_err());
+        assert!(acl.check_host("bob", "api.example.com", 443).is_err());
+
+        assert!(acl.check("alice", None, ip("203.0.113.9"), 443).is_err());
+        assert!(acl.check("bob", None, ip("203.0.113.9"), 443).is_ok());
+    }
+
+    #[test]
+    fn unresolved_hosts_skip_cidr_rules() {
+        let acl = policy(
+            r#"{"rules": [
+                {"action": "deny", "cidrs": ["0.0.0.0/0"], "ports": [443]},
+                {"action": "deny", "hosts": ["blocked.example"], "ports": [443]}
+            ]}"#,
+        );
+        // without an address only host and port rules apply; the upstream resolves
+        assert!(acl.check_host("u", "example.com", 443).is_ok());
+        assert!(acl.check_host("u", "blocked.example", 443).is_err());
+        assert!(acl
+            .check("u", Some("example.com"), ip("1.1.1.1"), 443)
+            .is_err());
+
+        let denied = acl
+            .check("u", Some("example.com"), ip("::ffff:10.0.0.1"), 80)
+            .unwrap_err();
+        assert_eq!(denied.target, "example.com:80 (10.0.0.1)");
+        assert_eq!(denied.reason, "private or reserved address");
+    }
+}
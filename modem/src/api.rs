@@ -2,24 +2,46 @@ use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
 use anyhow::{Context, Result};
 use axum::{
-    extract::{Path, State},
+    extract::{
+        ws::{rejection::WebSocketUpgradeRejection, Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, Path, Query, Request, State,
+    },
     http::StatusCode,
-    response::{IntoResponse, Response as AxumResponse},
+    middleware::{self, Next},
+    response::{
+        sse::{Event as SseEvent, KeepAlive, Sse},
+        IntoResponse, Response as AxumResponse,
+    },
     routing::{get, post},
+    serve::ListenerExt,
     Json, Router,
 };
 use derive_builder::Builder;
+use futures_util::{stream, Stream, StreamExt};
 use get_if_addrs::get_if_addrs;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use slog::{error, info, Logger};
-use tokio::{net::TcpListener, sync::Mutex};
-use uuid::Uuid;
+use slog::{error, info, warn, Logger};
+use time::OffsetDateTime;
+use tokio::sync::broadcast;
+use tokio_rustls::TlsAcceptor;
 
 use crate::{
-    device::{get_default_interface, Device},
-    modem::Modem,
-    modem_huaweie337::HuaweiE337,
+    acl::SourceAllowlist,
+    auth::{require_role, HttpAuth, Role},
+    config::ModemDriver,
+    device::{get_default_interface, Device, LinkInfo, Signal},
+    events::{Event, EventBus, EventKind},
+    health::HealthRegistry,
+    identity::{DeviceIds, Interfaces},
+    ip_history::{IpHistory, IpSighting},
+    modem::{Modems, SharedModem, Sms},
+    rotation::{DeviceRotation, RotationRegistry},
+    shutdown::CancellationToken,
+    telemetry::TelemetryRegistry,
+    tls::HttpListener,
+    usage::{Quota, Usage, UsageStore},
+    users::{User, UserError, UserStore},
 };
 
 #[derive(Debug)]
@@ -40,6 +62,10 @@ impl ApiError {
         ApiError::new(StatusCode::NOT_FOUND, msg)
     }
 
+    fn bad_request(msg: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::BAD_REQUEST, msg)
+    }
+
     fn internal(msg: impl Into<String>) -> Self {
         ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, msg)
     }
@@ -58,24 +84,143 @@ pub struct SmsMessage {
     content: String,
 }
 
+/// Query of `GET /api/v1/devices/{id}/sms`.
+#[derive(Debug, Deserialize)]
+pub struct SmsListQuery {
+    #[serde(default = "default_sms_count")]
+    count: u32,
+}
+
+fn default_sms_count() -> u32 {
+    20
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UssdRequest {
+    code: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SmsResponse {
     id: String,
     status: String,
 }
 
+#[derive(Debug, Serialize)]
+pub struct UsageResponse {
+    #[serde(flatten)]
+    usage: Usage,
+    quota: Quota,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RotationRequest {
+    paused: bool,
+}
+
+/// Query filters for `GET /api/v1/events`.
+#[derive(Debug, Default, Deserialize)]
+pub struct EventFilter {
+    /// Only events of this device
+    device: Option<String>,
+    /// Comma-separated event types, e.g. `health_changed,rotation_finished`
+    types: Option<String>,
+}
+
+/// Query filters for `GET /api/v1/devices`; all given ones must match.
+#[derive(Debug, Default, Deserialize)]
+pub struct DeviceFilter {
+    /// Devices without health checks count as healthy
+    healthy: Option<bool>,
+    /// Case-insensitive
+    operator: Option<String>,
+    /// `LTE`, `3G`, ... (case-insensitive)
+    network_type: Option<String>,
+}
+
+impl DeviceFilter {
+    fn matches(&self, device: &Device) -> bool {
+        let same = |want: &Option<String>, have: Option<&str>| {
+            want.as_ref()
+                .is_none_or(|w| have.is_some_and(|h| h.eq_ignore_ascii_case(w)))
+        };
+        let healthy = device.health.as_ref().is_none_or(|h| h.healthy);
+
+        self.healthy.is_none_or(|want| want == healthy)
+            && same(&self.operator, device.operator.as_deref())
+            && same(
+                &self.network_type,
+                device.signal.as_ref().map(|s| s.network_type.as_str()),
+            )
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Builder)]
 #[builder(pattern = "mutable")]
 pub struct API {
     addr: SocketAddr,
-    modem: Arc<Mutex<dyn Modem + Send + Sync>>,
+    /// Resolves aliases in paths.
+    ids: Arc<DeviceIds>,
+    /// Current device id to interface name map.
+    #[builder(default)]
+    interfaces: Arc<Interfaces>,
     #[builder(default)]
     logger: Option<Logger>,
+    #[builder(default)]
+    usage: Arc<UsageStore>,
+    /// Client addresses allowed to call the API (empty = anyone).
+    #[builder(default)]
+    allowed_sources: SourceAllowlist,
+    #[builder(default)]
+    users: Arc<UserStore>,
+    /// Accepted credentials and their roles; without any the API refuses to start
+    /// unless `insecure` is set.
+    #[builder(default)]
+    auth: Arc<HttpAuth>,
+    /// Serve the API without credentials, letting anyone who can reach it in.
+    #[builder(default)]
+    insecure: bool,
+    /// Serve HTTPS instead of HTTP.
+    #[builder(default)]
+    tls: Option<TlsAcceptor>,
+    /// Finish in-flight requests and return once cancelled.
+    #[builder(default)]
+    shutdown: CancellationToken,
+    /// Probe results reported with each device.
+    #[builder(default)]
+    health: Arc<HealthRegistry>,
+    /// Rotation state reported and paused per device.
+    #[builder(default)]
+    rotation: Arc<RotationRegistry>,
+    /// Exit IPs seen per device.
+    #[builder(default)]
+    ip_history: Arc<IpHistory>,
+    /// Latest modem readings (operator, signal) reported with each device.
+    #[builder(default)]
+    telemetry: Arc<TelemetryRegistry>,
+    /// Modem of each device, also reported with it; devices without one cannot be rebooted
+    #[builder(default)]
+    modems: Arc<Modems>,
+    /// Streamed to `GET /api/v1/events` subscribers; API reboots are published here too.
+    #[builder(default)]
+    events: Arc<EventBus>,
 }
 
 pub struct AppState {
-    modem: Arc<Mutex<dyn Modem + Send + Sync>>,
+    ids: Arc<DeviceIds>,
+    interfaces: Arc<Interfaces>,
     logger: Logger,
+    usage: Arc<UsageStore>,
+    users: Arc<UserStore>,
+    health: Arc<HealthRegistry>,
+    rotation: Arc<RotationRegistry>,
+    ip_history: Arc<IpHistory>,
+    telemetry: Arc<TelemetryRegistry>,
+    modems: Arc<Modems>,
+    events: Arc<EventBus>,
+    /// Ends event streams so a graceful shutdown does not wait for subscribers
+    shutdown: CancellationToken,
 }
 
 impl API {
@@ -91,70 +236,234 @@ impl API {
         let logger = self.logger.unwrap();
 
         let state = Arc::new(AppState {
-            modem: self.modem,
+            ids: self.ids,
+            interfaces: self.interfaces,
             logger: logger.clone(),
+            usage: self.usage,
+            users: self.users,
+            health: self.health,
+            rotation: self.rotation,
+            ip_history: self.ip_history,
+            telemetry: self.telemetry,
+            modems: self.modems,
+            events: self.events,
+            shutdown: self.shutdown.clone(),
         });
 
-        let app = Router::new()
+        let auth = if self.auth.is_enabled() {
+            self.auth
+        } else if self.insecure {
+            error!(logger, "API INSECURE: no credentials configured, anyone who can reach it may reboot modems, send SMS and edit users";
+                "addr" => %self.addr,
+            );
+            Arc::new((*self.auth).clone().allow_anonymous())
+        } else {
+            return Err(anyhow::anyhow!(
+                "API has no credentials: set --api-tokens-file, or --api-insecure to serve it open"
+            ));
+        };
+
+        let read = Router::new()
             .route("/api/v1/devices", get(handle_list_devices))
+            .route("/api/v1/devices/{id}", get(handle_get_device))
+            .route("/api/v1/devices/{id}/usage", get(handle_device_usage))
+            .route("/api/v1/devices/{id}/rotation", get(handle_get_rotation))
+            .route("/api/v1/devices/{id}/ip-history", get(handle_ip_history))
+            .route("/api/v1/events", get(handle_events))
+            .route("/api/v1/users/{name}/usage", get(handle_user_usage));
+        let operator = Router::new()
             .route("/api/v1/devices/{id}/reboot", post(handle_reboot_interface))
+            .route("/api/v1/devices/{id}/rotation", post(handle_set_rotation))
+            .route("/api/v1/devices/{id}/rotate", post(handle_rotate))
+            .route(
+                "/api/v1/devices/{id}/sms",
+                get(handle_list_sms).post(handle_send_sms),
+            )
+            .route("/api/v1/devices/{id}/ussd", post(handle_ussd));
+        let admin = Router::new()
+            .route("/api/v1/users", get(handle_list_users))
+            .route(
+                "/api/v1/users/{name}",
+                get(handle_get_user)
+                    .put(handle_put_user)
+                    .delete(handle_delete_user),
+            );
+
+        let app = Router::new()
+            .merge(with_role(read, Role::Read, &auth, &logger))
+            .merge(with_role(operator, Role::Operator, &auth, &logger))
+            .merge(with_role(admin, Role::Admin, &auth, &logger))
             .with_state(state);
 
-        let api_listener = TcpListener::bind(self.addr)
+        let allowed = Arc::new(self.allowed_sources);
+        let allowlist_logger = logger.clone();
+        let app = app.layer(middleware::from_fn(
+            move |peer: ConnectInfo<SocketAddr>, req: Request, next: Next| {
+                let allowed = allowed.clone();
+                let logger = allowlist_logger.clone();
+                async move { source_allowlist(req, next, peer, allowed, logger).await }
+            },
+        ));
+
+        let api_listener = HttpListener::bind(self.addr, self.tls, logger.clone())
             .await
-            .context("Failed to bind API listener")?;
+            .context("Failed to bind API listener")?
+            .tap_io(|_| {}); // keeps ConnectInfo<SocketAddr> available
 
         // Use axum::Server for graceful shutdown
-        axum::serve(api_listener, app)
-            .with_graceful_shutdown(async {
-                tokio::signal::ctrl_c()
-                    .await
-                    .expect("install Ctrl+C handler");
-            })
-            .await?;
+        axum::serve(
+            api_listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(self.shutdown.cancelled_owned())
+        .await?;
 
         Ok(())
     }
 }
 
-pub fn list_interfaces() -> HashMap<String, String> {
-    let mut ifaces = HashMap::new();
-    for iface in get_if_addrs().context("list interfaces").unwrap() {
-        if iface.name.starts_with("enx") {
-            let id = Uuid::new_v5(&Uuid::NAMESPACE_URL, iface.name.as_bytes()).to_string();
-            ifaces.insert(id, iface.name);
-        }
+/// Require credentials with at least `role` on every route of `router`.
+fn with_role(
+    router: Router<Arc<AppState>>,
+    role: Role,
+    auth: &Arc<HttpAuth>,
+    logger: &Logger,
+) -> Router<Arc<AppState>> {
+    let auth = auth.clone();
+    let logger = logger.clone();
+    router.route_layer(middleware::from_fn(move |req: Request, next: Next| {
+        let auth = auth.clone();
+        let logger = logger.clone();
+        async move { require_role(req, next, auth, role, logger).await }
+    }))
+}
+
+/// Reject requests from clients outside the API allowlist.
+async fn source_allowlist(
+    req: Request,
+    next: Next,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    allowed: Arc<SourceAllowlist>,
+    logger: Logger,
+) -> Result<AxumResponse, StatusCode> {
+    if !allowed.allows(peer.ip()) {
+        warn!(logger, "API client rejected by source allowlist"; "peer" => %peer);
+        return Err(StatusCode::FORBIDDEN);
     }
+    Ok(next.run(req).await)
+}
+
+/// The current id (aliases resolved) and interface name of device `id`.
+async fn find_interface(state: &AppState, id: &str) -> Result<(String, String), ApiError> {
+    let id = state.ids.resolve(id).to_string();
+    let ifname = state
+        .interfaces
+        .get()
+        .get(&id)
+        .cloned()
+        .ok_or_else(|| ApiError::not_found(format!("Interface with ID {} not found", id)))?;
+    Ok((id, ifname))
+}
 
-    ifaces
+/// The current id, interface name and modem of device `id`. Devices whose modem has no
+/// driver have none (409).
+async fn find_modem(state: &AppState, id: &str) -> Result<(String, String, SharedModem), ApiError> {
+    let (id, ifname) = find_interface(state, id).await?;
+    let modem = state.modems.get(&id).ok_or_else(|| {
+        ApiError::new(StatusCode::CONFLICT, format!("No modem for device {}", id))
+    })?;
+    Ok((id, ifname, modem))
 }
 
 async fn handle_list_devices(
     State(state): State<Arc<AppState>>,
+    Query(filter): Query<DeviceFilter>,
 ) -> Result<Json<Vec<Device>>, ApiError> {
     info!(state.logger, "Listing interfaces");
 
     // 1) figure out your "main" interface
-    let main = get_default_interface()
+    let _main = get_default_interface()
         .map_err(|e| ApiError::internal(format!("detect default iface: {}", e)))?;
 
-    // 2) enumerate all IP‐up interfaces
+    // 2) enumerate all modem interfaces and keep the ones matching the filter
+    let devices = modem_devices(&state)
+        .await?
+        .into_iter()
+        .filter(|device| filter.matches(device))
+        .collect();
+
+    Ok(Json(devices))
+}
+
+async fn handle_get_device(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<Device>, ApiError> {
+    let id = state.ids.resolve(&id).to_string();
+    modem_devices(&state)
+        .await?
+        .into_iter()
+        .find(|device| device.id == id)
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("Interface with ID {} not found", id)))
+}
+
+/// Every IP-up modem interface with what sysfs, the probes and the modem report about it.
+async fn modem_devices(state: &AppState) -> Result<Vec<Device>, ApiError> {
+    let ids: HashMap<String, String> = state
+        .interfaces
+        .get()
+        .iter()
+        .map(|(id, ifname)| (ifname.clone(), id.clone()))
+        .collect();
     let all_ifs = get_if_addrs().map_err(|e| ApiError::internal(e.to_string()))?;
-    let devices: Vec<Device> = all_ifs
+    let devices = all_ifs
         .into_iter()
-        // keep only the default iface + any ppp*/wwan* (Huawei)
-        .filter(|iface| iface.name.starts_with("enx"))
-        .map(|iface| {
-            let id = Uuid::new_v5(&Uuid::NAMESPACE_URL, iface.name.as_bytes());
+        // keep only the Huawei HiLink sticks that got an id
+        .filter_map(|iface| Some((ids.get(&iface.name)?.clone(), iface)))
+        .map(|(id, iface)| {
+            let health = state.health.get(&id);
+            let telemetry = state.telemetry.get(&id);
+            let rotation = state.rotation.get(&id).unwrap_or_default();
+
+            let exit_ip = health
+                .as_ref()
+                .and_then(|h| h.exit_ip)
+                .or_else(|| state.ip_history.latest(&id).map(|s| s.ip));
+            let uptime_secs = match (rotation.last_rotation, &telemetry) {
+                (Some(at), _) => Some((OffsetDateTime::now_utc() - at).whole_seconds().max(0) as u64),
+                (None, Some(t)) if t.connected => Some(t.connected_seconds),
+                _ => None,
+            };
+
             Device {
+                modem_api: state.modems.api_url(&id),
+                modem_driver: if state.modems.get(&id).is_some() {
+                    ModemDriver::HuaweiE3372
+                } else {
+                    ModemDriver::None
+                },
                 id,
+                link: LinkInfo::read(&iface.name),
+                health,
+                exit_ip,
+                operator: telemetry.as_ref().map(|t| t.operator.clone()),
+                signal: telemetry.map(|t| Signal {
+                    network_type: t.network_type,
+                    rsrp_dbm: t.rsrp_dbm,
+                    rsrq_db: t.rsrq_db,
+                    sinr_db: t.sinr_db,
+                    rssi_dbm: t.rssi_dbm,
+                }),
+                uptime_secs,
+                active_connections: rotation.active_tunnels,
                 name: iface.name,
                 ip: iface.addr.ip().to_string(),
             }
         })
         .collect();
 
-    Ok(Json(devices))
+    Ok(devices)
 }
 
 async fn handle_reboot_interface(
@@ -163,25 +472,329 @@ async fn handle_reboot_interface(
 ) -> Result<Json<serde_json::Value>, ApiError> {
     info!(state.logger, "Restarting interface"; "id" => &id);
 
-    // Find the interface by ID
-    let interfaces = list_interfaces();
-    let interface_name = interfaces
-        .get(&id)
-        .ok_or_else(|| ApiError::not_found(format!("Interface with ID {} not found", id)))?;
-
-    // Implement the actual restart logic here
-    // For now, we'll just return a success message
+    let (id, interface_name, modem) = find_modem(&state, &id).await?;
 
-    state
-        .modem
+    let rebooted = modem
         .lock()
         .await
         .reboot()
         .await
-        .map_err(|e| ApiError::internal(e.to_string()))?;
+        .map_err(|e| e.to_string());
+    state.events.publish(
+        Some(&id),
+        EventKind::Reboot {
+            source: "api",
+            error: rebooted.as_ref().err().cloned(),
+        },
+    );
+    rebooted.map_err(ApiError::internal)?;
 
     Ok(Json(json!({
         "status": "success",
         "message": format!("Interface {} restarted successfully", interface_name)
     })))
 }
+
+async fn handle_device_usage(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<UsageResponse>, ApiError> {
+    let (id, _) = find_interface(&state, &id).await?;
+
+    Ok(Json(UsageResponse {
+        usage: state.usage.device(&id).unwrap_or_default(),
+        quota: state.usage.device_quota(&id),
+    }))
+}
+
+async fn handle_get_rotation(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<DeviceRotation>, ApiError> {
+    let (id, _) = find_interface(&state, &id).await?;
+
+    Ok(Json(state.rotation.get(&id).unwrap_or_default()))
+}
+
+async fn handle_set_rotation(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Json(req): Json<RotationRequest>,
+) -> Result<Json<DeviceRotation>, ApiError> {
+    info!(state.logger, "Setting rotation"; "id" => &id, "paused" => req.paused);
+
+    let (id, _) = find_interface(&state, &id).await?;
+
+    state
+        .rotation
+        .set_paused(&id, req.paused)
+        .map(Json)
+        .ok_or_else(|| ApiError::bad_request(format!("Device {} has no rotation policy", id)))
+}
+
+async fn handle_rotate(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<DeviceRotation>, ApiError> {
+    info!(state.logger, "Rotation requested"; "id" => &id);
+
+    let (id, _) = find_interface(&state, &id).await?;
+
+    state.rotation.request(&id).map(Json).ok_or_else(|| {
+        ApiError::new(
+            StatusCode::CONFLICT,
+            format!("Device {} has no modem to rotate", id),
+        )
+    })
+}
+
+async fn handle_list_sms(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<SmsListQuery>,
+) -> Result<Json<Vec<Sms>>, ApiError> {
+    let (_, _, modem) = find_modem(&state, &id).await?;
+
+    let messages = modem.lock().await.list_sms(query.count).await;
+    messages
+        .map(Json)
+        .map_err(|e| ApiError::internal(e.to_string()))
+}
+
+async fn handle_send_sms(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Json(sms): Json<SmsMessage>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    info!(state.logger, "Sending SMS"; "id" => &id, "recipient" => &sms.recipient);
+
+    if sms.recipient.is_empty() || sms.content.is_empty() {
+        return Err(ApiError::bad_request("recipient and content are required"));
+    }
+    let (_, _, modem) = find_modem(&state, &id).await?;
+
+    let sent = modem
+        .lock()
+        .await
+        .send_sms(&sms.recipient, &sms.content)
+        .await
+        .map_err(|e| e.to_string());
+    sent.map_err(ApiError::internal)?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "message": format!("SMS sent to {}", sms.recipient)
+    })))
+}
+
+async fn handle_ussd(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Json(req): Json<UssdRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    info!(state.logger, "Running USSD code"; "id" => &id, "code" => &req.code);
+
+    let (_, _, modem) = find_modem(&state, &id).await?;
+
+    let reply = modem
+        .lock()
+        .await
+        .ussd(&req.code)
+        .await
+        .map_err(|e| e.to_string());
+    let reply = reply.map_err(ApiError::internal)?;
+
+    Ok(Json(json!({ "code": req.code, "reply": reply })))
+}
+
+async fn handle_ip_history(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<IpSighting>>, ApiError> {
+    let (id, _) = find_interface(&state, &id).await?;
+
+    Ok(Json(state.ip_history.device(&id)))
+}
+
+/// Device and proxy events as server-sent events, or as JSON text frames when the
+/// client asks for a WebSocket upgrade.
+async fn handle_events(
+    State(state): State<Arc<AppState>>,
+    Query(filter): Query<EventFilter>,
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+) -> AxumResponse {
+    let device = filter.device.map(|d| state.ids.resolve(&d).to_string());
+    let types: Option<Vec<String>> = filter.types.map(|types| {
+        types
+            .split(',')
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect()
+    });
+    let wanted = move |event: &Event| {
+        // subscribers always learn that they missed something
+        matches!(event.kind, EventKind::Lagged { .. })
+            || (device.as_ref().is_none_or(|d| event.device.as_ref() == Some(d))
+                && types
+                    .as_ref()
+                    .is_none_or(|t| t.iter().any(|t| t == event.kind.name())))
+    };
+    let events = event_stream(state.events.subscribe(), state.shutdown.clone(), wanted);
+
+    match ws {
+        Ok(ws) => ws.on_upgrade(move |socket| forward_events(socket, events)),
+        Err(_) => {
+            let events = events.map(|event| {
+                let sse = SseEvent::default().event(event.kind.name());
+                let sse = match event.kind {
+                    EventKind::Lagged { .. } => sse,
+                    _ => sse.id(event.id.to_string()),
+                };
+                sse.json_data(&event)
+            });
+            Sse::new(events)
+                .keep_alive(KeepAlive::default())
+                .into_response()
+        }
+    }
+}
+
+/// Events from `rx` that pass `wanted`, until `shutdown` is cancelled.
+fn event_stream(
+    rx: broadcast::Receiver<Event>,
+    shutdown: CancellationToken,
+    wanted: impl Fn(&Event) -> bool + Send + 'static,
+) -> impl Stream<Item = Event> + Send + 'static {
+    stream::unfold((rx, shutdown, wanted), |(mut rx, shutdown, wanted)| async move {
+        loop {
+            let received = tokio::select! {
+                received = rx.recv() => received,
+                _ = shutdown.cancelled() => return None,
+            };
+            let event = match received {
+                Ok(event) if wanted(&event) => event,
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(missed)) => Event {
+                    id: 0,
+                    at: OffsetDateTime::now_utc(),
+                    device: None,
+                    kind: EventKind::Lagged { missed },
+                },
+                Err(broadcast::error::RecvError::Closed) => return None,
+            };
+            return Some((event, (rx, shutdown, wanted)));
+        }
+    })
+}
+
+async fn forward_events(mut socket: WebSocket, events: impl Stream<Item = Event> + Send) {
+    let mut events = std::pin::pin!(events);
+    loop {
+        tokio::select! {
+            event = events.next() => {
+                let Some(event) = event else {
+                    break;
+                };
+                let Ok(json) = serde_json::to_string(&event) else {
+                    continue;
+                };
+                if socket.send(Message::Text(json.into())).await.is_err() {
+                    return;
+                }
+            }
+            // pings are answered by axum; anything else from the client is ignored
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    let _ = socket.send(Message::Close(None)).await;
+}
+
+async fn handle_user_usage(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<UsageResponse>, ApiError> {
+    let usage = state
+        .usage
+        .user(&name)
+        .ok_or_else(|| ApiError::not_found(format!("No usage recorded for user {}", name)))?;
+
+    Ok(Json(UsageResponse {
+        usage,
+        quota: state.usage.user_quota(&name),
+    }))
+}
+
+async fn handle_list_users(State(state): State<Arc<AppState>>) -> Json<Vec<User>> {
+    Json(state.users.list())
+}
+
+async fn handle_get_user(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<User>, ApiError> {
+    state
+        .users
+        .get(&name)
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("User {} not found", name)))
+}
+
+fn user_error(e: UserError) -> ApiError {
+    match e {
+        UserError::Invalid(_) => ApiError::bad_request(e.to_string()),
+        UserError::Save(_) => ApiError::internal(e.to_string()),
+    }
+}
+
+async fn handle_put_user(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(mut user): Json<User>,
+) -> Result<Json<User>, ApiError> {
+    info!(state.logger, "Saving user"; "name" => &name);
+
+    if state.users.is_read_only() {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "Users are managed in the config file's [users] section".to_string(),
+        ));
+    }
+
+    user.name = name;
+    state.users.upsert(user.clone()).map_err(user_error)?;
+
+    Ok(Json(user))
+}
+
+async fn handle_delete_user(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    info!(state.logger, "Deleting user"; "name" => &name);
+
+    if state.users.is_read_only() {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "Users are managed in the config file's [users] section".to_string(),
+        ));
+    }
+
+    let existed = state.users.remove(&name).map_err(user_error)?;
+    if !existed && state.users.get(&name).is_some() {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            format!(
+                "User {} is built in; it is dropped once other users exist, unless --default-user is set",
+                name
+            ),
+        ));
+    }
+    if !existed {
+        return Err(ApiError::not_found(format!("User {} not found", name)));
+    }
+
+    Ok(Json(json!({ "status": "success" })))
+}
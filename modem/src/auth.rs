@@ -0,0 +1,253 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::Request,
+    http::{header, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use base64::{engine::general_purpose, Engine as _};
+use openssl::{memcmp, sha::sha256};
+use serde::{Deserialize, Serialize};
+use slog::{warn, Logger};
+use time::OffsetDateTime;
+
+/// What an API token may do. Higher roles include the lower ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// List devices, status and usage.
+    Read,
+    /// Reboot/rotate devices and send SMS.
+    Operator,
+    /// Manage users and configuration.
+    Admin,
+}
+
+/// An API token as stored on disk: only the SHA-256 of the secret is kept.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub name: String,
+    /// Hex-encoded SHA-256 of the bearer token.
+    pub sha256: String,
+    pub role: Role,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub expires_at: Option<OffsetDateTime>,
+}
+
+impl ApiToken {
+    fn expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|at| at <= OffsetDateTime::now_utc())
+    }
+}
+
+#[derive(Default, Deserialize)]
+struct TokensFile {
+    #[serde(default)]
+    tokens: Vec<ApiToken>,
+}
+
+/// Hex-encoded SHA-256 of a token, as written to the tokens file.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(sha256(token.as_bytes()))
+}
+
+/// Bearer tokens accepted by an HTTP listener.
+#[derive(Default)]
+pub struct TokenStore {
+    tokens: Vec<(ApiToken, [u8; 32])>,
+}
+
+impl TokenStore {
+    pub fn new(tokens: Vec<ApiToken>) -> Result<Self> {
+        let tokens = tokens
+            .into_iter()
+            .map(|t| {
+                let digest: [u8; 32] = hex::decode(&t.sha256)
+                    .ok()
+                    .and_then(|d| d.try_into().ok())
+                    .with_context(|| format!("token `{}`: sha256 must be 64 hex chars", t.name))?;
+                Ok((t, digest))
+            })
+            .collect::<Result<_>>()?;
+        Ok(TokenStore { tokens })
+    }
+
+    /// Load tokens from a JSON file of the form `{"tokens": [...]}`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data =
+            std::fs::read(path).with_context(|| format!("read tokens file {}", path.display()))?;
+        let file: TokensFile = serde_json::from_slice(&data)
+            .with_context(|| format!("parse tokens file {}", path.display()))?;
+        TokenStore::new(file.tokens)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// The unexpired token matching `secret`, compared in constant time.
+    pub fn authenticate(&self, secret: &str) -> Option<&ApiToken> {
+        let digest = sha256(secret.as_bytes());
+        // check every token so timing doesn't reveal which one matched
+        let mut found = None;
+        for (token, expected) in &self.tokens {
+            if memcmp::eq(&digest, expected) && found.is_none() {
+                found = Some(token);
+            }
+        }
+        found.filter(|t| !t.expired())
+    }
+}
+
+/// HTTP Basic credentials. Only the SHA-256 of the password is kept in memory.
+#[derive(Clone)]
+pub struct BasicAuth {
+    username: String,
+    password_sha256: [u8; 32],
+    role: Role,
+}
+
+impl BasicAuth {
+    /// Credentials from a plain-text password.
+    pub fn new(username: impl Into<String>, password: &str, role: Role) -> Self {
+        BasicAuth {
+            username: username.into(),
+            password_sha256: sha256(password.as_bytes()),
+            role,
+        }
+    }
+
+    /// Credentials from a hex-encoded SHA-256 of the password.
+    pub fn from_sha256(
+        username: impl Into<String>,
+        password_sha256: &str,
+        role: Role,
+    ) -> Result<Self> {
+        let password_sha256 = hex::decode(password_sha256.trim())
+            .ok()
+            .and_then(|d| d.try_into().ok())
+            .context("password sha256 must be 64 hex chars")?;
+        Ok(BasicAuth {
+            username: username.into(),
+            password_sha256,
+            role,
+        })
+    }
+
+    fn verify(&self, username: &str, password: &str) -> bool {
+        // hash both sides so the comparison length never depends on the input
+        let user_ok = memcmp::eq(
+            &sha256(username.as_bytes()),
+            &sha256(self.username.as_bytes()),
+        );
+        let pass_ok = memcmp::eq(&sha256(password.as_bytes()), &self.password_sha256);
+        user_ok & pass_ok
+    }
+}
+
+/// Read a secret from a file, dropping the trailing newline editors and `echo` add.
+pub fn read_secret_file(path: impl AsRef<Path>) -> Result<String> {
+    let path = path.as_ref();
+    let secret =
+        std::fs::read_to_string(path).with_context(|| format!("read secret {}", path.display()))?;
+    Ok(secret.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Credentials accepted by an HTTP listener: Basic auth, bearer tokens, or both.
+/// With neither configured every request is refused, unless anonymous access was
+/// explicitly allowed.
+#[derive(Clone, Default)]
+pub struct HttpAuth {
+    basic: Option<BasicAuth>,
+    tokens: Arc<TokenStore>,
+    anonymous: bool,
+}
+
+/// Who made a request, as established by [`HttpAuth`].
+struct Principal {
+    name: String,
+    role: Role,
+}
+
+impl HttpAuth {
+    pub fn new(basic: Option<BasicAuth>, tokens: Arc<TokenStore>) -> Self {
+        HttpAuth {
+            basic,
+            tokens,
+            anonymous: false,
+        }
+    }
+
+    /// Let every request through while no credentials are configured.
+    pub fn allow_anonymous(mut self) -> Self {
+        self.anonymous = true;
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.basic.is_some() || !self.tokens.is_empty()
+    }
+
+    fn authenticate(&self, req: &Request) -> Option<Principal> {
+        let auth = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|h| h.to_str().ok());
+
+        if let Some(encoded) = auth.and_then(|a| a.strip_prefix("Basic ")) {
+            let basic = self.basic.as_ref()?;
+            let decoded = general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+            let decoded = String::from_utf8(decoded).ok()?;
+            let (username, password) = decoded.split_once(':')?;
+            return basic.verify(username, password).then(|| Principal {
+                name: username.to_string(),
+                role: basic.role,
+            });
+        }
+
+        let secret = match auth {
+            Some(a) => a.strip_prefix("Bearer ")?.trim(),
+            None => req.headers().get("x-api-key")?.to_str().ok()?,
+        };
+        self.tokens.authenticate(secret).map(|t| Principal {
+            name: t.name.clone(),
+            role: t.role,
+        })
+    }
+}
+
+// Basic auth / bearer token middleware
+pub async fn require_role(
+    req: Request,
+    next: Next,
+    auth: Arc<HttpAuth>,
+    role: Role,
+    logger: Logger,
+) -> Result<Response, StatusCode> {
+    if !auth.is_enabled() {
+        if auth.anonymous {
+            return Ok(next.run(req).await);
+        }
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let Some(principal) = auth.authenticate(&req) else {
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+
+    if principal.role < role {
+        warn!(logger, "credentials lack role";
+            "principal" => &principal.name,
+            "role" => ?principal.role,
+            "required" => ?role,
+            "path" => req.uri().path(),
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    Ok(next.run(req).await)
+}
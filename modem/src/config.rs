@@ -0,0 +1,534 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use derive_builder::Builder;
+use lazy_static::lazy_static;
+use prometheus::{register_gauge, register_int_counter_vec, Gauge, IntCounterVec};
+use serde::{Deserialize, Serialize};
+use slog::{error, info, warn, Logger};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    time::{interval, MissedTickBehavior},
+};
+
+use crate::{
+    acl::{AclConfig, AclPolicy},
+    live::Live,
+    rotation::{RotationConfig, RotationPolicies},
+    tcp::OsFingerprint,
+    usage::{Limits, Quota, UsageStore},
+    users::{User, UserStore},
+};
+
+lazy_static! {
+    static ref CONFIG_RELOADS: IntCounterVec = register_int_counter_vec!(
+        "config_reloads_total",
+        "Config reloads by trigger and outcome",
+        &["trigger", "result"]
+    )
+    .unwrap();
+    static ref CONFIG_LAST_RELOAD: Gauge = register_gauge!(
+        "config_last_reload_success_timestamp_seconds",
+        "Unix time the settings were last loaded without errors"
+    )
+    .unwrap();
+}
+
+/// Which management API a modem speaks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ModemDriver {
+    #[default]
+    #[serde(rename = "huawei-e3372")]
+    HuaweiE3372,
+    /// No management API: the device only routes traffic; no reboots, rotation,
+    /// telemetry or SMS
+    #[serde(rename = "none")]
+    None,
+}
+
+/// Settings of one device from the `[devices."<id>"]` section. Read at startup only.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceSettings {
+    /// Address of the modem's web API (default `--ip-modem-api`)
+    #[serde(default)]
+    pub modem_host: Option<String>,
+    #[serde(default)]
+    pub driver: ModemDriver,
+    /// Used when the proxy username asks for no fingerprint
+    #[serde(default)]
+    pub fingerprint: Option<OsFingerprint>,
+}
+
+/// `[limits]`: quotas overriding the `--quota-*` flags.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LimitsSection {
+    #[serde(default)]
+    user: Option<Quota>,
+    #[serde(default)]
+    device: Option<Quota>,
+    #[serde(default)]
+    users: HashMap<String, Quota>,
+    #[serde(default)]
+    devices: HashMap<String, Quota>,
+}
+
+impl LimitsSection {
+    fn over(self, flags: &Limits) -> Limits {
+        Limits {
+            user: self.user.unwrap_or(flags.user),
+            device: self.device.unwrap_or(flags.device),
+            users: self.users,
+            devices: self.devices,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RawConfig {
+    #[serde(default)]
+    users: Option<Vec<User>>,
+    #[serde(default)]
+    acl: Option<AclConfig>,
+    #[serde(default)]
+    limits: Option<LimitsSection>,
+    #[serde(default)]
+    rotation: Option<RotationConfig>,
+    #[serde(default)]
+    devices: HashMap<String, DeviceSettings>,
+    /// Everything else sets a flag, by its long name with `_` (e.g. `port_socks5 = 1080`)
+    #[serde(flatten)]
+    flags: toml::Table,
+}
+
+/// A parsed and validated TOML config file.
+pub struct ConfigFile {
+    path: PathBuf,
+    flags: toml::Table,
+    pub devices: HashMap<String, DeviceSettings>,
+    users: Option<Vec<User>>,
+    acl: Option<AclPolicy>,
+    limits: Option<LimitsSection>,
+    rotation: Option<RotationPolicies>,
+}
+
+impl ConfigFile {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("read config file {}", path.display()))?;
+        let raw: RawConfig = toml::from_str(&data)
+            .with_context(|| format!("parse config file {}", path.display()))?;
+        let invalid = |section: &str| format!("config file {}: [{}]", path.display(), section);
+
+        if let Some(users) = &raw.users {
+            UserStore::default()
+                .replace(users.clone())
+                .with_context(|| invalid("users"))?;
+        }
+        if let Some(limits) = &raw.limits {
+            // checked on its own so a zero flag quota is not blamed on the file
+            Limits {
+                user: limits.user.unwrap_or_default(),
+                device: limits.device.unwrap_or_default(),
+                users: limits.users.clone(),
+                devices: limits.devices.clone(),
+            }
+            .validate()
+            .with_context(|| invalid("limits"))?;
+        }
+        let rotation = raw
+            .rotation
+            .map(RotationPolicies::new)
+            .transpose()
+            .with_context(|| invalid("rotation"))?;
+
+        let file = ConfigFile {
+            path: path.to_path_buf(),
+            flags: raw.flags,
+            devices: raw.devices,
+            users: raw.users,
+            acl: raw.acl.map(AclPolicy::new),
+            limits: raw.limits,
+            rotation,
+        };
+        file.flags()?;
+        Ok(file)
+    }
+
+    /// Flag values set by the file, by flag name. Lists are given one value per entry.
+    pub fn flags(&self) -> Result<Vec<(String, Vec<String>)>> {
+        let scalar = |name: &str, value: &toml::Value| match value {
+            toml::Value::String(s) => Ok(s.clone()),
+            toml::Value::Integer(i) => Ok(i.to_string()),
+            toml::Value::Float(f) => Ok(f.to_string()),
+            toml::Value::Boolean(b) => Ok(b.to_string()),
+            _ => Err(anyhow::anyhow!(
+                "config file {}: `{}` must be a string, number, boolean or a list of them",
+                self.path.display(),
+                name
+            )),
+        };
+
+        self.flags
+            .iter()
+            .map(|(name, value)| {
+                let values = match value {
+                    toml::Value::Array(items) => items
+                        .iter()
+                        .map(|item| scalar(name, item))
+                        .collect::<Result<_>>()?,
+                    // only top-level tables are sections; tables inside lists are bad values
+                    toml::Value::Table(_) => {
+                        return Err(anyhow::anyhow!(
+                            "config file {}: unknown section [{}]",
+                            self.path.display(),
+                            name
+                        ))
+                    }
+                    value => vec![scalar(name, value)?],
+                };
+                Ok((name.clone(), values))
+            })
+            .collect()
+    }
+}
+
+/// What a reload was started by, as reported in logs and metrics.
+#[derive(Clone, Copy)]
+enum Trigger {
+    Startup,
+    Signal,
+    FileChange,
+}
+
+impl Trigger {
+    fn as_str(self) -> &'static str {
+        match self {
+            Trigger::Startup => "startup",
+            Trigger::Signal => "sighup",
+            Trigger::FileChange => "file_change",
+        }
+    }
+}
+
+/// Keeps the settings that can change at runtime (users, ACL, quotas, rotation
+/// policies) in sync with their sources: the config file's sections, or the
+/// `--users-file`, `--acl-file` and `--rotation-file` JSON files. A source that
+/// fails to load or validate leaves the previous settings in place.
+#[derive(Builder)]
+#[builder(pattern = "owned")]
+pub struct Reloader {
+    #[builder(default)]
+    config: Option<PathBuf>,
+    #[builder(default)]
+    users_file: Option<PathBuf>,
+    #[builder(default)]
+    acl_file: Option<PathBuf>,
+    #[builder(default)]
+    rotation_file: Option<PathBuf>,
+    /// Quotas from the flags; `[limits]` overrides them
+    #[builder(default)]
+    limits: Limits,
+    users: Arc<UserStore>,
+    acl: Arc<Live<AclPolicy>>,
+    usage: Arc<UsageStore>,
+    rotation: Arc<Live<RotationPolicies>>,
+    logger: Logger,
+    /// How often the source files are checked for changes (`None` = SIGHUP only)
+    #[builder(default = "Some(Duration::from_secs(5))")]
+    watch_interval: Option<Duration>,
+    /// Flags and device settings last loaded, to point out changes that need a restart
+    #[builder(setter(skip))]
+    restart_only: Mutex<Option<(toml::Table, HashMap<String, DeviceSettings>)>>,
+}
+
+impl Reloader {
+    /// Load and apply every source; meant for startup, where an error should stop the process.
+    pub fn load(&self) -> Result<()> {
+        self.reload(Trigger::Startup)
+    }
+
+    fn reload(&self, trigger: Trigger) -> Result<()> {
+        let applied = self.apply(trigger);
+        let result = if applied.is_ok() { "ok" } else { "error" };
+        CONFIG_RELOADS
+            .with_label_values(&[trigger.as_str(), result])
+            .inc();
+        if applied.is_ok() {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            CONFIG_LAST_RELOAD.set(now.as_secs_f64());
+        }
+        applied
+    }
+
+    /// Load every source first and swap the settings in only if all of them are valid.
+    fn apply(&self, trigger: Trigger) -> Result<()> {
+        let file = self.config.as_ref().map(ConfigFile::load).transpose()?;
+        let (users, acl, limits, rotation) = match file {
+            Some(ConfigFile {
+                users,
+                acl,
+                limits,
+                rotation,
+                ..
+            }) => (users, acl, limits, rotation),
+            None => (None, None, None, None),
+        };
+        // a section can only conflict when there is a config file
+        let config = self.config.as_deref().unwrap_or(Path::new(""));
+        let conflict = |section: &str, flag: &str| {
+            anyhow::anyhow!(
+                "config file {} has [{}] and --{} is set too; use one of them",
+                config.display(),
+                section,
+                flag
+            )
+        };
+
+        let users = match (&self.users_file, users) {
+            (Some(_), Some(_)) => return Err(conflict("users", "users-file")),
+            (Some(path), None) => Some(UserStore::from_file(path)?.list()),
+            // without a [users] section the users stay as the API left them
+            (None, users) => users,
+        };
+        let from_section = self.users_file.is_none() && users.is_some();
+        let acl = match (&self.acl_file, acl) {
+            (Some(_), Some(_)) => return Err(conflict("acl", "acl-file")),
+            (Some(path), None) => AclPolicy::from_file(path)?,
+            (None, acl) => acl.unwrap_or_default(),
+        };
+        let rotation = match (&self.rotation_file, rotation) {
+            (Some(_), Some(_)) => return Err(conflict("rotation", "rotation-file")),
+            (Some(path), None) => RotationPolicies::from_file(path)?,
+            (None, rotation) => rotation.unwrap_or_default(),
+        };
+        let limits = match limits {
+            Some(section) => section.over(&self.limits),
+            None => self.limits.clone(),
+        };
+
+        let user_count = users.as_ref().map(Vec::len);
+        if let Some(users) = users {
+            self.users.replace(users)?;
+        }
+        self.users.set_read_only(from_section);
+        self.acl.set(acl);
+        info!(self.logger, "settings loaded";
+            "trigger" => trigger.as_str(),
+            "users" => user_count,
+            "user_quotas" => limits.users.len(),
+            "device_quotas" => limits.devices.len(),
+            "rotation_policies" => rotation.len(),
+        );
+        self.usage.set_limits(limits);
+        self.rotation.set(rotation);
+        Ok(())
+    }
+
+    /// Warn about flag and device settings that changed but only apply after a restart.
+    fn check_restart_only(&self) {
+        let Some(path) = &self.config else {
+            return;
+        };
+        let Ok(file) = ConfigFile::load(path) else {
+            return;
+        };
+        let current = (file.flags, file.devices);
+        let mut last = self.restart_only.lock().unwrap();
+        if let Some((flags, devices)) = last.as_ref() {
+            let mut changed: Vec<String> = flags
+                .keys()
+                .chain(current.0.keys())
+                .filter(|name| flags.get(*name) != current.0.get(*name))
+                .cloned()
+                .collect();
+            changed.extend(
+                devices
+                    .keys()
+                    .chain(current.1.keys())
+                    .filter(|id| devices.get(*id) != current.1.get(*id))
+                    .map(|id| format!("devices.{}", id)),
+            );
+            changed.sort();
+            changed.dedup();
+            if !changed.is_empty() {
+                warn!(self.logger, "config changes that only apply after a restart";
+                    "settings" => changed.join(","),
+                );
+            }
+        }
+        *last = Some(current);
+    }
+
+    /// Reload on SIGHUP and whenever a source file changes.
+    pub fn spawn(self) -> Result<()> {
+        let mut hangup = signal(SignalKind::hangup()).context("install SIGHUP handler")?;
+        self.check_restart_only();
+        let paths: Vec<PathBuf> = [
+            &self.config,
+            &self.users_file,
+            &self.acl_file,
+            &self.rotation_file,
+        ]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect();
+
+        tokio::spawn(async move {
+            // only polled when watching is on
+            let mut ticker = interval(self.watch_interval.unwrap_or(Duration::from_secs(60)));
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut stamps = modified(&paths);
+
+            loop {
+                let trigger = tokio::select! {
+                    received = hangup.recv() => match received {
+                        Some(()) => Trigger::Signal,
+                        None => return,
+                    },
+                    _ = ticker.tick(), if self.watch_interval.is_some() => {
+                        let now = modified(&paths);
+                        if now == stamps {
+                            continue;
+                        }
+                        stamps = now;
+                        Trigger::FileChange
+                    }
+                };
+
+                match self.reload(trigger) {
+                    Ok(()) => self.check_restart_only(),
+                    Err(e) => error!(self.logger, "config reload failed, previous settings kept";
+                        "trigger" => trigger.as_str(),
+                        "error" => format!("{:#}", e),
+                    ),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Modification times of `paths`; `None` for files that cannot be read right now.
+fn modified(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    /// Writes `toml` to a fresh file under the temp dir and loads it.
+    fn load(toml: &str) -> Result<ConfigFile> {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "proxymodem-config-{}-{}.toml",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, toml).unwrap();
+        let file = ConfigFile::load(&path);
+        let _ = std::fs::remove_file(&path);
+        file
+    }
+
+    fn flag(file: &ConfigFile, name: &str) -> Option<Vec<String>> {
+        file.flags()
+            .unwrap()
+            .into_iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, values)| values)
+    }
+
+    #[test]
+    fn reads_flags_next_to_sections() {
+        let file = load(
+            r#"
+port_socks5 = 1080
+health_check_url = "http://ifconfig.me/ip"
+allowed_sources = ["10.0.0.0/8", "192.168.0.0/16"]
+proxy_http_connect = true
+reboot_backoff = 1.5
+
+[devices."dev-1"]
+driver = "none"
+
+[[users]]
+name = "alice"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(flag(&file, "port_socks5"), Some(vec!["1080".to_string()]));
+        assert_eq!(
+            flag(&file, "health_check_url"),
+            Some(vec!["http://ifconfig.me/ip".to_string()])
+        );
+        assert_eq!(
+            flag(&file, "allowed_sources"),
+            Some(vec!["10.0.0.0/8".to_string(), "192.168.0.0/16".to_string()])
+        );
+        assert_eq!(
+            flag(&file, "proxy_http_connect"),
+            Some(vec!["true".to_string()])
+        );
+        assert_eq!(flag(&file, "reboot_backoff"), Some(vec!["1.5".to_string()]));
+        // sections are not flags
+        assert_eq!(file.flags().unwrap().len(), 5);
+        assert_eq!(file.devices["dev-1"].driver, ModemDriver::None);
+    }
+
+    #[test]
+    fn empty_file_sets_no_flags() {
+        assert!(load("").unwrap().flags().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_sections() {
+        let err = load("[proxy]\nport = 1080\n").err().unwrap();
+        assert!(
+            err.to_string().contains("unknown section [proxy]"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn rejects_values_that_are_not_flags() {
+        for toml in [
+            "started = 2025-05-15T10:00:00Z\n",
+            "allowed_sources = [[\"10.0.0.0/8\"]]\n",
+            "allowed_sources = [{ cidr = \"10.0.0.0/8\" }]\n",
+        ] {
+            let err = load(toml).err().unwrap();
+            assert!(
+                err.to_string().contains("must be a string"),
+                "{}: {}",
+                toml,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_file() {
+        assert!(load("port_socks5 = ").is_err());
+        assert!(load("allowed_sources = [\"10.0.0.0/8\"").is_err());
+        assert!(load("[devices.\"dev-1\"\n").is_err());
+    }
+}
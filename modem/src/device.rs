@@ -1,12 +1,24 @@
+use std::{fs, net::IpAddr, path::Path};
+
 use anyhow::Result;
 use serde::{ser::SerializeStruct, Serialize};
-use uuid::Uuid;
+
+use crate::{config::ModemDriver, health::DeviceHealth};
 
 #[derive(Clone, Debug)]
 pub struct Device {
-    pub(crate) id: Uuid,
+    pub(crate) id: String,
     pub(crate) name: String, // interface name, e.g. "eth0", "ppp0"
     pub(crate) ip: String,   // IP address of the interface
+    pub(crate) link: LinkInfo,
+    pub(crate) modem_api: Option<String>, // e.g. "http://192.168.8.1/api"
+    pub(crate) modem_driver: ModemDriver, // `none` when the device has no modem handle
+    pub(crate) health: Option<DeviceHealth>, // None when health checks are off
+    pub(crate) exit_ip: Option<IpAddr>, // last public IP seen through the device
+    pub(crate) operator: Option<String>,
+    pub(crate) signal: Option<Signal>, // None until the modem answered a telemetry poll
+    pub(crate) uptime_secs: Option<u64>, // since the last rotation, else the data session age
+    pub(crate) active_connections: u64,
 }
 
 impl Serialize for Device {
@@ -14,14 +26,90 @@ impl Serialize for Device {
     where
         S: serde::Serializer,
     {
-        let mut state = serializer.serialize_struct("Device", 3)?;
-        state.serialize_field("id", &self.id.to_string())?;
+        let mut state = serializer.serialize_struct("Device", 18)?;
+        state.serialize_field("id", &self.id)?;
         state.serialize_field("name", &self.name)?;
         state.serialize_field("ip", &self.ip)?;
+        state.serialize_field("mac", &self.link.mac)?;
+        state.serialize_field("link", &self.link.operstate)?;
+        state.serialize_field("mtu", &self.link.mtu)?;
+        state.serialize_field("kernel_driver", &self.link.kernel_driver)?;
+        state.serialize_field("usb_path", &self.link.usb_path)?;
+        state.serialize_field("usb_vendor", &self.link.usb_vendor)?;
+        state.serialize_field("usb_product", &self.link.usb_product)?;
+        state.serialize_field("modem_api", &self.modem_api)?;
+        state.serialize_field("modem_driver", &self.modem_driver)?;
+        state.serialize_field("health", &self.health)?;
+        state.serialize_field("exit_ip", &self.exit_ip)?;
+        state.serialize_field("operator", &self.operator)?;
+        state.serialize_field("signal", &self.signal)?;
+        state.serialize_field("uptime_secs", &self.uptime_secs)?;
+        state.serialize_field("active_connections", &self.active_connections)?;
         state.end()
     }
 }
 
+/// Radio summary from the modem's last telemetry poll.
+#[derive(Clone, Debug, Serialize)]
+pub struct Signal {
+    pub network_type: String,
+    pub rsrp_dbm: Option<f64>,
+    pub rsrq_db: Option<f64>,
+    pub sinr_db: Option<f64>,
+    pub rssi_dbm: Option<f64>,
+}
+
+/// What the kernel reports about an interface in sysfs. Fields it does not
+/// expose (e.g. the USB ids of a virtual interface) are `None`.
+#[derive(Clone, Debug, Default)]
+pub struct LinkInfo {
+    pub mac: Option<String>,
+    /// `up`, `down`, `dormant`, `unknown`, ...
+    pub operstate: Option<String>,
+    pub mtu: Option<u32>,
+    /// Kernel driver, e.g. `cdc_ether` (HiLink) or `rndis_host`
+    pub kernel_driver: Option<String>,
+    /// USB bus and port chain, e.g. `1-1.2`; stays the same for a given port
+    pub usb_path: Option<String>,
+    pub usb_vendor: Option<String>,
+    pub usb_product: Option<String>,
+}
+
+impl LinkInfo {
+    /// Read `/sys/class/net/<ifname>`.
+    pub fn read(ifname: &str) -> Self {
+        let net = Path::new("/sys/class/net").join(ifname);
+        let attr = |path: &Path| {
+            fs::read_to_string(path)
+                .ok()
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+        };
+        let file_name = |path: &Path| {
+            path.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        };
+
+        // `device` points at the USB interface (e.g. `1-1.2:1.0`), whose parent is the USB device
+        let usb_device = fs::canonicalize(net.join("device"))
+            .ok()
+            .and_then(|iface| iface.parent().map(Path::to_path_buf))
+            .filter(|dev| dev.join("idVendor").exists());
+
+        LinkInfo {
+            mac: attr(&net.join("address")),
+            operstate: attr(&net.join("operstate")),
+            mtu: attr(&net.join("mtu")).and_then(|mtu| mtu.parse().ok()),
+            kernel_driver: fs::read_link(net.join("device/driver"))
+                .ok()
+                .and_then(|driver| file_name(&driver)),
+            usb_path: usb_device.as_deref().and_then(file_name),
+            usb_vendor: usb_device.as_ref().and_then(|dev| attr(&dev.join("idVendor"))),
+            usb_product: usb_device.as_ref().and_then(|dev| attr(&dev.join("idProduct"))),
+        }
+    }
+}
+
 /// Helper: read /proc/net/route and return the iface whose Destination is 0.0.0.0
 pub fn get_default_interface() -> Result<String> {
     let data = std::fs::read_to_string("/proc/net/route")?;
@@ -0,0 +1,188 @@
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use serde::Serialize;
+use slog::{info, warn, Logger};
+use time::OffsetDateTime;
+use tokio::{
+    sync::broadcast,
+    time::{interval, MissedTickBehavior},
+};
+
+use crate::{
+    identity::{DeviceIds, Interfaces},
+    rotation::{RotationMethod, RotationReason},
+};
+
+/// Events buffered per subscriber; slower ones get a `lagged` event instead.
+const EVENT_BUFFER: usize = 1024;
+
+/// What happened.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    DeviceAdded {
+        iface: String,
+    },
+    DeviceRemoved {
+        iface: String,
+    },
+    /// The health checker took the device out of routing or put it back
+    HealthChanged {
+        healthy: bool,
+        exit_ip: Option<IpAddr>,
+        error: Option<String>,
+    },
+    /// The device stopped taking new connections ahead of a rotation
+    RotationStarted {
+        reason: RotationReason,
+        method: RotationMethod,
+    },
+    RotationFinished {
+        reason: RotationReason,
+        exit_ip: Option<IpAddr>,
+        attempts: u32,
+        error: Option<String>,
+    },
+    /// A modem reboot requested by `source` (`health` or `api`)
+    Reboot {
+        source: &'static str,
+        error: Option<String>,
+    },
+    /// An inbound SMS; the text itself only goes to the SMS webhooks
+    SmsReceived {
+        from: String,
+        index: u64,
+    },
+    /// New connections of `name` are refused until the `period` (`daily` or `monthly`) rolls over
+    QuotaExhausted {
+        scope: &'static str,
+        name: String,
+        period: &'static str,
+    },
+    /// The subscriber fell behind and `missed` events were dropped
+    Lagged {
+        missed: u64,
+    },
+}
+
+impl EventKind {
+    /// The `type` tag, as used in filters and as the SSE event name.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::DeviceAdded { .. } => "device_added",
+            EventKind::DeviceRemoved { .. } => "device_removed",
+            EventKind::HealthChanged { .. } => "health_changed",
+            EventKind::RotationStarted { .. } => "rotation_started",
+            EventKind::RotationFinished { .. } => "rotation_finished",
+            EventKind::Reboot { .. } => "reboot",
+            EventKind::SmsReceived { .. } => "sms_received",
+            EventKind::QuotaExhausted { .. } => "quota_exhausted",
+            EventKind::Lagged { .. } => "lagged",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Event {
+    /// Increases by one per published event; a gap means events were missed
+    pub id: u64,
+    #[serde(with = "time::serde::rfc3339")]
+    pub at: OffsetDateTime,
+    pub device: Option<String>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// Fan-out of device and proxy events to API subscribers. Publishing without
+/// subscribers is a no-op.
+pub struct EventBus {
+    tx: broadcast::Sender<Event>,
+    next_id: AtomicU64,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus {
+            tx: broadcast::channel(EVENT_BUFFER).0,
+            next_id: AtomicU64::new(1),
+        }
+    }
+}
+
+impl EventBus {
+    pub fn publish(&self, device: Option<&str>, kind: EventKind) {
+        let event = Event {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            at: OffsetDateTime::now_utc(),
+            device: device.map(str::to_string),
+            kind,
+        };
+        // only fails when nobody is subscribed
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+}
+
+/// Keep `interfaces` current and publish `device_added`/`device_removed` whenever the
+/// set of modem interfaces changes. Interfaces whose id could not be derived yet (e.g.
+/// a modem still booting with the `imei` strategy) are retried on every tick.
+pub fn spawn_device_watch_loop(
+    ids: Arc<DeviceIds>,
+    interfaces: Arc<Interfaces>,
+    events: Arc<EventBus>,
+    every: Duration,
+    logger: Logger,
+) {
+    tokio::spawn(async move {
+        let mut known = interfaces.get();
+        let mut ticker = interval(every);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let current = match ids.interfaces().await {
+                Ok(current) => current,
+                Err(e) => {
+                    warn!(logger, "list interfaces"; "error" => %e);
+                    continue;
+                }
+            };
+
+            for (id, iface) in known.iter() {
+                if current.get(id) != Some(iface) {
+                    info!(logger, "device removed"; "device" => id, "iface" => iface);
+                    events.publish(
+                        Some(id),
+                        EventKind::DeviceRemoved {
+                            iface: iface.clone(),
+                        },
+                    );
+                }
+            }
+            for (id, iface) in &current {
+                if known.get(id) != Some(iface) {
+                    info!(logger, "device added"; "device" => id, "iface" => iface);
+                    events.publish(
+                        Some(id),
+                        EventKind::DeviceAdded {
+                            iface: iface.clone(),
+                        },
+                    );
+                }
+            }
+            if current != *known {
+                interfaces.set(current);
+                known = interfaces.get();
+            }
+        }
+    });
+}
@@ -0,0 +1,617 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::IpAddr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use derive_builder::Builder;
+use serde::Serialize;
+use slog::{error, info, warn, Logger};
+use time::OffsetDateTime;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::lookup_host,
+    time::{interval, timeout, Instant, MissedTickBehavior},
+};
+use url::Url;
+
+use crate::{
+    events::{EventBus, EventKind},
+    identity::{spawn_per_device, Interfaces},
+    ip_history::IpHistory,
+    modem::Modems,
+    rotation::RotationRegistry,
+    tcp::{tcp_connect_with_fingerprint, OsFingerprint},
+};
+
+/// Upper bound for an echo endpoint's response, headers included.
+const MAX_PROBE_RESPONSE: u64 = 4 * 1024;
+
+/// What the probes through a device found.
+#[derive(Clone, Debug, Serialize)]
+pub struct DeviceHealth {
+    pub healthy: bool,
+    /// Public address the last successful probe came from
+    pub exit_ip: Option<IpAddr>,
+    pub latency_ms: Option<u64>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_check: Option<OffsetDateTime>,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    /// Reboots issued by the health checker since startup
+    pub reboots: u32,
+}
+
+impl Default for DeviceHealth {
+    fn default() -> Self {
+        DeviceHealth {
+            healthy: true,
+            exit_ip: None,
+            latency_ms: None,
+            last_check: None,
+            consecutive_failures: 0,
+            last_error: None,
+            reboots: 0,
+        }
+    }
+}
+
+/// Health of every probed device, shared with the proxy and the API.
+/// Devices that are not probed count as healthy.
+#[derive(Default)]
+pub struct HealthRegistry {
+    devices: RwLock<HashMap<String, DeviceHealth>>,
+}
+
+impl HealthRegistry {
+    pub fn is_healthy(&self, device: &str) -> bool {
+        self.devices
+            .read()
+            .unwrap()
+            .get(device)
+            .is_none_or(|h| h.healthy)
+    }
+
+    pub fn get(&self, device: &str) -> Option<DeviceHealth> {
+        self.devices.read().unwrap().get(device).cloned()
+    }
+
+    fn update<R>(&self, device: &str, f: impl FnOnce(&mut DeviceHealth) -> R) -> R {
+        let mut devices = self.devices.write().unwrap();
+        f(devices.entry(device.to_string()).or_default())
+    }
+}
+
+/// An `http://` endpoint that answers with the caller's IP address as plain text,
+/// e.g. `http://api.ipify.org/`.
+#[derive(Clone, Debug)]
+pub struct ProbeTarget {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl std::str::FromStr for ProbeTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s).map_err(|e| format!("invalid probe URL: {}", e))?;
+        if url.scheme() != "http" {
+            return Err(format!("probe URL must be http://, got `{}`", url.scheme()));
+        }
+        let host = url
+            .host_str()
+            .filter(|h| !h.is_empty())
+            .ok_or("probe URL needs a host")?
+            .trim_matches(['[', ']'])
+            .to_string();
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+
+        Ok(ProbeTarget {
+            host,
+            port: url.port().unwrap_or(80),
+            path,
+        })
+    }
+}
+
+/// Probes every device through its own interface and reboots modems that stop
+/// passing traffic. Unhealthy devices are refused by the proxy until they recover.
+/// Devices being rotated are left alone, since their link drops on purpose.
+#[derive(Builder)]
+#[builder(pattern = "owned")]
+pub struct HealthChecker {
+    /// Device id to interface name, looked up before every probe; devices that
+    /// appear later are probed too
+    interfaces: Arc<Interfaces>,
+    /// The modems to reboot; devices without one are only marked unhealthy
+    #[builder(default)]
+    modems: Arc<Modems>,
+    target: ProbeTarget,
+    registry: Arc<HealthRegistry>,
+    /// Devices draining or rotating here are neither probed nor rebooted
+    #[builder(default)]
+    rotation: Arc<RotationRegistry>,
+    /// Where every exit IP a probe sees is recorded
+    #[builder(default)]
+    history: Arc<IpHistory>,
+    /// Receives health changes and reboot results
+    #[builder(default)]
+    events: Arc<EventBus>,
+    logger: Logger,
+    fingerprint: OsFingerprint,
+    #[builder(default = "Duration::from_secs(30)")]
+    interval: Duration,
+    /// Max time for one probe, DNS included
+    #[builder(default = "Duration::from_secs(10)")]
+    timeout: Duration,
+    /// Consecutive failed probes before a device is taken out of routing
+    #[builder(default = "3")]
+    failure_threshold: u32,
+    /// Wait after the first reboot; doubles on every reboot that does not help
+    #[builder(default = "Duration::from_secs(60)")]
+    reboot_backoff: Duration,
+    #[builder(default = "Duration::from_secs(3600)")]
+    max_reboot_backoff: Duration,
+}
+
+impl HealthChecker {
+    /// Start one probe loop per device, for as long as the device is connected.
+    pub fn spawn(self) {
+        let checker = Arc::new(self);
+        spawn_per_device(
+            checker.interfaces.clone(),
+            checker.interval,
+            move |device| {
+                let checker = checker.clone();
+                let device = device.to_string();
+                Some(async move { checker.supervise(device).await })
+            },
+        );
+    }
+
+    async fn supervise(&self, device: String) {
+        let logger = self.logger.new(slog::o!("device" => device.clone()));
+        let mut ticker = interval(self.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut backoff = self.reboot_backoff;
+        let mut next_reboot = Instant::now();
+
+        loop {
+            ticker.tick().await;
+            let Some(ifname) = self.interfaces.get().get(&device).cloned() else {
+                continue;
+            };
+            // a probe would fail while the modem reconnects, and count against the device
+            if self.rotation.is_rotating(&device) {
+                continue;
+            }
+            let started = Instant::now();
+            let probed = probe_exit_ip(&self.target, &ifname, self.fingerprint, self.timeout).await;
+            let latency_ms = started.elapsed().as_millis() as u64;
+
+            match probed {
+                Ok(ip) => {
+                    self.history.record(&device, ip);
+                    let recovered = self.registry.update(&device, |h| {
+                        let recovered = !h.healthy;
+                        h.healthy = true;
+                        h.exit_ip = Some(ip);
+                        h.latency_ms = Some(latency_ms);
+                        h.last_check = Some(OffsetDateTime::now_utc());
+                        h.consecutive_failures = 0;
+                        h.last_error = None;
+                        recovered
+                    });
+                    if recovered {
+                        info!(logger, "device recovered, back in routing"; "exit_ip" => %ip);
+                        self.events.publish(
+                            Some(&device),
+                            EventKind::HealthChanged {
+                                healthy: true,
+                                exit_ip: Some(ip),
+                                error: None,
+                            },
+                        );
+                    }
+                    backoff = self.reboot_backoff;
+                }
+                Err(e) => {
+                    let threshold = self.failure_threshold;
+                    let (failures, went_down) = self.registry.update(&device, |h| {
+                        h.latency_ms = None;
+                        h.last_check = Some(OffsetDateTime::now_utc());
+                        h.consecutive_failures += 1;
+                        h.last_error = Some(e.to_string());
+                        let went_down = h.healthy && h.consecutive_failures >= threshold;
+                        if went_down {
+                            h.healthy = false;
+                        }
+                        (h.consecutive_failures, went_down)
+                    });
+                    warn!(logger, "health probe failed";
+                        "iface" => &ifname,
+                        "error" => %e,
+                        "failures" => failures,
+                    );
+
+                    if went_down {
+                        error!(logger, "device unhealthy, removed from routing");
+                        self.events.publish(
+                            Some(&device),
+                            EventKind::HealthChanged {
+                                healthy: false,
+                                exit_ip: None,
+                                error: Some(e.to_string()),
+                            },
+                        );
+                    }
+                    if failures >= threshold
+                        && Instant::now() >= next_reboot
+                        && !self.rotation.is_rotating(&device)
+                    {
+                        self.reboot(&device, &logger).await;
+                        next_reboot = Instant::now() + backoff;
+                        backoff = (backoff * 2).min(self.max_reboot_backoff);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn reboot(&self, device: &str, logger: &Logger) {
+        let Some(modem) = self.modems.get(device) else {
+            warn!(logger, "no modem to reboot");
+            return;
+        };
+
+        info!(logger, "rebooting modem");
+        let rebooted = modem
+            .lock()
+            .await
+            .reboot()
+            .await
+            .map_err(|e| e.to_string());
+        match &rebooted {
+            Ok(()) => self.registry.update(device, |h| h.reboots += 1),
+            Err(e) => error!(logger, "reboot modem"; "error" => e),
+        }
+        self.events.publish(
+            Some(device),
+            EventKind::Reboot {
+                source: "health",
+                error: rebooted.err(),
+            },
+        );
+    }
+}
+
+/// The public address `ifname` exits through, asked from the echo endpoint at `target`.
+/// `limit` bounds the whole probe, DNS included.
+pub async fn probe_exit_ip(
+    target: &ProbeTarget,
+    ifname: &str,
+    fingerprint: OsFingerprint,
+    limit: Duration,
+) -> io::Result<IpAddr> {
+    match timeout(limit, probe(target, ifname, fingerprint)).await {
+        Ok(res) => res,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "probe timed out")),
+    }
+}
+
+/// One plain HTTP request to the echo endpoint through `ifname`.
+async fn probe(target: &ProbeTarget, ifname: &str, fingerprint: OsFingerprint) -> io::Result<IpAddr> {
+    let addr = lookup_host((target.host.as_str(), target.port))
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "probe host not found"))?;
+    let mut stream = tcp_connect_with_fingerprint(addr, ifname, fingerprint).await?;
+
+    // HTTP/1.0, so the body is neither chunked nor kept alive
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: proxymodem\r\nAccept: text/plain\r\n\r\n",
+        target.path, target.host
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    stream
+        .take(MAX_PROBE_RESPONSE)
+        .read_to_end(&mut response)
+        .await?;
+    parse_echo_response(&response)
+}
+
+fn parse_echo_response(response: &[u8]) -> io::Result<IpAddr> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let response = String::from_utf8_lossy(response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| invalid("truncated probe response".to_string()))?;
+    let status = head.split(' ').nth(1).unwrap_or_default();
+    if status != "200" {
+        return Err(invalid(format!("probe endpoint answered {}", status)));
+    }
+    body.trim()
+        .parse()
+        .map_err(|_| invalid(format!("probe endpoint sent no IP address: {:?}", body.trim())))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        error::Error,
+        sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    };
+
+    use async_trait::async_trait;
+    use tokio::{net::TcpListener, sync::Mutex};
+
+    use super::*;
+    use crate::{
+        live::Live,
+        modem::{Modem, SharedModem, Sms, Telemetry},
+    };
+
+    const EXIT_IP: &str = "203.0.113.7";
+
+    #[test]
+    fn parses_echo_response() {
+        let ip = parse_echo_response(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\n203.0.113.7\n",
+        )
+        .unwrap();
+        assert_eq!(ip, EXIT_IP.parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn rejects_bad_echo_responses() {
+        for response in [
+            &b"HTTP/1.1 503 Service Unavailable\r\n\r\n203.0.113.7"[..],
+            b"HTTP/1.1 200 OK\r\n\r\n<html>hello</html>",
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n",
+            b"",
+        ] {
+            let err = parse_echo_response(response).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    /// Counts reboots; the health checker calls nothing else.
+    struct FakeModem {
+        reboots: Arc<AtomicU32>,
+    }
+
+    #[async_trait]
+    impl Modem for FakeModem {
+        async fn reboot(&mut self) -> Result<(), Box<dyn Error>> {
+            self.reboots.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+        async fn reconnect(&mut self) -> Result<(), Box<dyn Error>> {
+            Err(anyhow::anyhow!("not supported").into())
+        }
+        async fn imei(&mut self) -> Result<String, Box<dyn Error>> {
+            Err(anyhow::anyhow!("not supported").into())
+        }
+        async fn telemetry(&mut self) -> Result<Telemetry, Box<dyn Error>> {
+            Err(anyhow::anyhow!("not supported").into())
+        }
+        async fn unread_sms(&mut self) -> Result<Vec<Sms>, Box<dyn Error>> {
+            Err(anyhow::anyhow!("not supported").into())
+        }
+        async fn mark_sms_read(&mut self, _: u64) -> Result<(), Box<dyn Error>> {
+            Err(anyhow::anyhow!("not supported").into())
+        }
+        async fn delete_sms(&mut self, _: u64) -> Result<(), Box<dyn Error>> {
+            Err(anyhow::anyhow!("not supported").into())
+        }
+        async fn list_sms(&mut self, _: u32) -> Result<Vec<Sms>, Box<dyn Error>> {
+            Err(anyhow::anyhow!("not supported").into())
+        }
+        async fn send_sms(&mut self, _: &str, _: &str) -> Result<(), Box<dyn Error>> {
+            Err(anyhow::anyhow!("not supported").into())
+        }
+        async fn ussd(&mut self, _: &str) -> Result<String, Box<dyn Error>> {
+            Err(anyhow::anyhow!("not supported").into())
+        }
+    }
+
+    /// Every device's modem is a [`FakeModem`] adding to `reboots`.
+    fn fake_modems(interfaces: Arc<Interfaces>, reboots: Arc<AtomicU32>) -> Arc<Modems> {
+        Arc::new(Modems::new(interfaces, move |_| {
+            let modem: SharedModem = Arc::new(Mutex::new(FakeModem {
+                reboots: reboots.clone(),
+            }));
+            Some(("http://192.168.8.1/api".to_string(), modem))
+        }))
+    }
+
+    /// An echo endpoint on loopback that answers with `EXIT_IP` while `up` is set
+    /// and with a 503 otherwise.
+    async fn echo_stub(up: Arc<AtomicBool>) -> ProbeTarget {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request).await;
+                let response = if up.load(Ordering::SeqCst) {
+                    format!("HTTP/1.0 200 OK\r\n\r\n{}\n", EXIT_IP)
+                } else {
+                    "HTTP/1.0 503 Service Unavailable\r\n\r\n".to_string()
+                };
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        format!("http://127.0.0.1:{}/", port).parse().unwrap()
+    }
+
+    async fn eventually(what: &str, check: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if check() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out waiting for {}", what);
+    }
+
+    #[tokio::test]
+    async fn takes_failing_device_out_of_routing_and_back() {
+        let up = Arc::new(AtomicBool::new(true));
+        let target = echo_stub(up.clone()).await;
+        let registry = Arc::new(HealthRegistry::default());
+        let history = Arc::new(IpHistory::default());
+        let reboots = Arc::new(AtomicU32::new(0));
+        let interfaces = Arc::new(Live::new(HashMap::from([(
+            "dev".to_string(),
+            "lo".to_string(),
+        )])));
+
+        HealthCheckerBuilder::default()
+            .interfaces(interfaces.clone())
+            .modems(fake_modems(interfaces, reboots.clone()))
+            .target(target)
+            .registry(registry.clone())
+            .history(history.clone())
+            .logger(Logger::root(slog::Discard, slog::o!()))
+            .fingerprint(OsFingerprint::Linux)
+            .interval(Duration::from_millis(100))
+            .timeout(Duration::from_secs(2))
+            .failure_threshold(3)
+            // far longer than the test, so only the first reboot may happen
+            .reboot_backoff(Duration::from_secs(600))
+            .build()
+            .unwrap()
+            .spawn();
+
+        let exit_ip: IpAddr = EXIT_IP.parse().unwrap();
+        eventually("first probe", || {
+            registry.get("dev").is_some_and(|h| h.exit_ip.is_some())
+        })
+        .await;
+        let health = registry.get("dev").unwrap();
+        assert!(health.healthy);
+        assert_eq!(health.exit_ip, Some(exit_ip));
+        assert!(health.latency_ms.is_some());
+        assert_eq!(history.latest("dev").map(|s| s.ip), Some(exit_ip));
+
+        up.store(false, Ordering::SeqCst);
+        eventually("first failure", || {
+            registry
+                .get("dev")
+                .is_some_and(|h| h.consecutive_failures > 0)
+        })
+        .await;
+        let health = registry.get("dev").unwrap();
+        assert!(health.consecutive_failures < 3);
+        assert!(
+            health.healthy,
+            "below the threshold the device stays in routing"
+        );
+        assert!(health.last_error.is_some());
+
+        eventually("device down", || !registry.is_healthy("dev")).await;
+        assert!(registry.get("dev").unwrap().consecutive_failures >= 3);
+        eventually("reboot", || reboots.load(Ordering::SeqCst) == 1).await;
+
+        // keep failing well past the threshold: the backoff holds further reboots
+        eventually("more failures", || {
+            registry
+                .get("dev")
+                .is_some_and(|h| h.consecutive_failures >= 6)
+        })
+        .await;
+        assert_eq!(reboots.load(Ordering::SeqCst), 1);
+        assert_eq!(registry.get("dev").unwrap().reboots, 1);
+
+        up.store(true, Ordering::SeqCst);
+        eventually("recovery", || registry.is_healthy("dev")).await;
+        let health = registry.get("dev").unwrap();
+        assert_eq!(health.consecutive_failures, 0);
+        assert_eq!(health.exit_ip, Some(exit_ip));
+        assert!(health.last_error.is_none());
+    }
+
+    #[tokio::test]
+    async fn probes_devices_that_appear_later() {
+        let target = echo_stub(Arc::new(AtomicBool::new(true))).await;
+        let registry = Arc::new(HealthRegistry::default());
+        let interfaces = Arc::new(Interfaces::default());
+
+        HealthCheckerBuilder::default()
+            .interfaces(interfaces.clone())
+            .target(target)
+            .registry(registry.clone())
+            .logger(Logger::root(slog::Discard, slog::o!()))
+            .fingerprint(OsFingerprint::Linux)
+            .interval(Duration::from_millis(50))
+            .timeout(Duration::from_secs(2))
+            .build()
+            .unwrap()
+            .spawn();
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(registry.get("dev").is_none());
+
+        // plugged in at runtime, on an interface that was unknown at startup
+        interfaces.set(HashMap::from([("dev".to_string(), "lo".to_string())]));
+        eventually("probe of the new device", || {
+            registry.get("dev").is_some_and(|h| h.exit_ip.is_some())
+        })
+        .await;
+
+        // unplugged: the probe loop stops
+        interfaces.set(HashMap::new());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let checked = registry.get("dev").unwrap().last_check;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(registry.get("dev").unwrap().last_check, checked);
+    }
+
+    #[tokio::test]
+    async fn leaves_rotating_devices_alone() {
+        let target = echo_stub(Arc::new(AtomicBool::new(false))).await;
+        let registry = Arc::new(HealthRegistry::default());
+        let rotation = Arc::new(RotationRegistry::default());
+        let reboots = Arc::new(AtomicU32::new(0));
+        let interfaces = Arc::new(Live::new(HashMap::from([(
+            "dev".to_string(),
+            "lo".to_string(),
+        )])));
+        rotation.update("dev", |r| r.draining = true);
+
+        HealthCheckerBuilder::default()
+            .interfaces(interfaces.clone())
+            .modems(fake_modems(interfaces, reboots.clone()))
+            .target(target)
+            .registry(registry.clone())
+            .rotation(rotation.clone())
+            .logger(Logger::root(slog::Discard, slog::o!()))
+            .fingerprint(OsFingerprint::Linux)
+            .interval(Duration::from_millis(50))
+            .timeout(Duration::from_secs(2))
+            .failure_threshold(1)
+            .build()
+            .unwrap()
+            .spawn();
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(registry.get("dev").is_none(), "no probe while rotating");
+        assert!(registry.is_healthy("dev"));
+        assert_eq!(reboots.load(Ordering::SeqCst), 0);
+
+        // rotation done: the failing device is probed and rebooted again
+        rotation.update("dev", |r| r.draining = false);
+        eventually("device down", || !registry.is_healthy("dev")).await;
+        eventually("reboot", || reboots.load(Ordering::SeqCst) == 1).await;
+    }
+}
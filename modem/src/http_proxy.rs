@@ -0,0 +1,217 @@
+use std::net::SocketAddr;
+
+use axum::http::StatusCode;
+use base64::{engine::general_purpose, Engine as _};
+use socks5_proto::Address;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::socks5::{Result, Socks5Error};
+
+/// Upper bound for the request line plus headers of a CONNECT request.
+const MAX_HEAD: u64 = 8 * 1024;
+
+/// A parsed `CONNECT host:port HTTP/1.1` request.
+pub struct ConnectRequest {
+    pub target: Address,
+    /// `Proxy-Authorization: Basic` username and password, if sent.
+    pub credentials: Option<(String, String)>,
+}
+
+/// Reads the request head, leaving anything the client sent after it in `reader`.
+pub async fn read_connect_request<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> Result<ConnectRequest> {
+    let mut head = reader.take(MAX_HEAD);
+    let mut lines = Vec::new();
+    loop {
+        let mut line = Vec::new();
+        let n = head
+            .read_until(b'\n', &mut line)
+            .await
+            .map_err(|e| Socks5Error::Handshake(e.into()))?;
+        if n == 0 || !line.ends_with(b"\n") {
+            return Err(Socks5Error::HttpRequest(
+                "request head truncated or too large".to_string(),
+            ));
+        }
+        let line = String::from_utf8(line)?;
+        let line = line.trim_end_matches(['\r', '\n']).to_string();
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+
+    let mut request_line = lines
+        .first()
+        .map(|l| l.split(' '))
+        .ok_or_else(|| Socks5Error::HttpRequest("empty request".to_string()))?;
+    let method = request_line.next().unwrap_or_default();
+    let target = request_line.next().unwrap_or_default();
+    if method != "CONNECT" {
+        return Err(Socks5Error::HttpMethod(method.to_string()));
+    }
+
+    let credentials = lines[1..]
+        .iter()
+        .filter_map(|l| l.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("proxy-authorization"))
+        .map(|(_, value)| parse_basic(value.trim()))
+        .transpose()?;
+
+    Ok(ConnectRequest {
+        target: parse_authority(target)?,
+        credentials,
+    })
+}
+
+fn parse_basic(value: &str) -> Result<(String, String)> {
+    let invalid = || Socks5Error::HttpRequest("malformed Proxy-Authorization".to_string());
+    let encoded = value.strip_prefix("Basic ").ok_or_else(invalid)?;
+    let decoded = general_purpose::STANDARD
+        .decode(encoded.trim())
+        .map_err(|_| invalid())?;
+    let decoded = String::from_utf8(decoded)?;
+    let (username, password) = decoded.split_once(':').ok_or_else(invalid)?;
+    Ok((username.to_string(), password.to_string()))
+}
+
+/// `host:port` or `[v6]:port` into a SOCKS5 address.
+fn parse_authority(authority: &str) -> Result<Address> {
+    if let Ok(addr) = authority.parse::<SocketAddr>() {
+        return Ok(Address::SocketAddress(addr));
+    }
+    match authority.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && !host.contains(':') => {
+            let port = port.parse().map_err(|_| {
+                Socks5Error::HttpRequest(format!("invalid port in `{}`", authority))
+            })?;
+            Ok(Address::DomainAddress(host.as_bytes().to_vec(), port))
+        }
+        _ => Err(Socks5Error::HttpRequest(format!(
+            "invalid CONNECT target `{}`",
+            authority
+        ))),
+    }
+}
+
+/// Writes a body-less response; anything but 200 also closes the connection.
+pub async fn write_status<W: AsyncWrite + Unpin>(w: &mut W, status: StatusCode) -> Result<()> {
+    let response = if status == StatusCode::OK {
+        "HTTP/1.1 200 Connection established\r\n\r\n".to_string()
+    } else {
+        let mut extra = String::new();
+        if status == StatusCode::PROXY_AUTHENTICATION_REQUIRED {
+            extra.push_str("Proxy-Authenticate: Basic realm=\"proxymodem\"\r\n");
+        } else if status == StatusCode::METHOD_NOT_ALLOWED {
+            extra.push_str("Allow: CONNECT\r\n");
+        }
+        format!(
+            "HTTP/1.1 {} {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or_default(),
+            extra
+        )
+    };
+    w.write_all(response.as_bytes())
+        .await
+        .map_err(Socks5Error::ResponseWrite)?;
+    w.flush().await.map_err(Socks5Error::ResponseWrite)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::BufReader;
+
+    use super::*;
+
+    async fn read(head: &[u8]) -> Result<ConnectRequest> {
+        read_connect_request(&mut BufReader::new(head)).await
+    }
+
+    #[tokio::test]
+    async fn reads_connect_request_and_leaves_the_rest() {
+        // alice:secret
+        let mut reader = BufReader::new(
+            &b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\
+               proxy-authorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n\x16\x03\x01"[..],
+        );
+        let req = read_connect_request(&mut reader).await.unwrap();
+        assert_eq!(
+            req.target,
+            Address::DomainAddress(b"example.com".to_vec(), 443)
+        );
+        assert_eq!(
+            req.credentials,
+            Some(("alice".to_string(), "secret".to_string()))
+        );
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"\x16\x03\x01");
+    }
+
+    #[tokio::test]
+    async fn reads_ip_targets_without_credentials() {
+        let req = read(b"CONNECT [2001:db8::1]:443 HTTP/1.1\n\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            req.target,
+            Address::SocketAddress("[2001:db8::1]:443".parse().unwrap())
+        );
+        assert_eq!(req.credentials, None);
+
+        let req = read(b"CONNECT 203.0.113.7:8443 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            req.target,
+            Address::SocketAddress("203.0.113.7:8443".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_head() {
+        for head in [
+            &b""[..],
+            b"CONNECT example.com:443 HTTP/1.1",
+            b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com\r\n",
+        ] {
+            let err = read(head).await.err().unwrap();
+            assert!(matches!(err, Socks5Error::HttpRequest(_)), "{}", err);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_head() {
+        let mut head = b"CONNECT example.com:443 HTTP/1.1\r\n".to_vec();
+        head.extend_from_slice(
+            format!("X-Pad: {}\r\n\r\n", "a".repeat(MAX_HEAD as usize)).as_bytes(),
+        );
+        let err = read(&head).await.err().unwrap();
+        assert!(matches!(err, Socks5Error::HttpRequest(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_requests() {
+        let err = read(b"GET http://example.com/ HTTP/1.1\r\n\r\n")
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Socks5Error::HttpMethod(m) if m == "GET"));
+
+        for head in [
+            &b"\r\n"[..],
+            b"CONNECT example.com HTTP/1.1\r\n\r\n",
+            b"CONNECT example.com:https HTTP/1.1\r\n\r\n",
+            b"CONNECT :443 HTTP/1.1\r\n\r\n",
+            b"CONNECT example.com:443 HTTP/1.1\r\nProxy-Authorization: Bearer x\r\n\r\n",
+            b"CONNECT example.com:443 HTTP/1.1\r\nProxy-Authorization: Basic !!\r\n\r\n",
+            // "alice" without a colon
+            b"CONNECT example.com:443 HTTP/1.1\r\nProxy-Authorization: Basic YWxpY2U=\r\n\r\n",
+        ] {
+            let err = read(head).await.err().unwrap();
+            assert!(matches!(err, Socks5Error::HttpRequest(_)), "{}", err);
+        }
+    }
+}
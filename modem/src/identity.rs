@@ -0,0 +1,218 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    future::Future,
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use derive_builder::Builder;
+use get_if_addrs::get_if_addrs;
+use serde::Deserialize;
+use slog::{error, warn, Logger};
+use tokio::{
+    task::JoinHandle,
+    time::{interval, MissedTickBehavior},
+};
+use uuid::Uuid;
+
+use crate::{device::LinkInfo, live::Live, modem::Modem, modem_huaweie337::HuaweiE337};
+
+/// Device id to interface name of every modem interface, as last seen by the device
+/// watch loop. The proxy, the API and the rotator all route through this one map.
+pub type Interfaces = Live<HashMap<String, String>>;
+
+/// Keeps one task per device in `interfaces` running: `start` is asked for a task
+/// whenever a device appears (`None` if there is nothing to run for it), and the task
+/// is aborted once the device is gone. Checked every `every`; a device that moves to
+/// another interface keeps its task, which looks its interface up on each use.
+pub fn spawn_per_device<F, T>(interfaces: Arc<Interfaces>, every: Duration, mut start: F)
+where
+    F: FnMut(&str) -> Option<T> + Send + 'static,
+    T: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut running: HashMap<String, JoinHandle<()>> = HashMap::new();
+        let mut ticker = interval(every);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let current = interfaces.get();
+            running.retain(|device, task| {
+                let keep = current.contains_key(device) && !task.is_finished();
+                if !keep {
+                    task.abort();
+                }
+                keep
+            });
+            for device in current.keys() {
+                if running.contains_key(device) {
+                    continue;
+                }
+                if let Some(task) = start(device) {
+                    running.insert(device.clone(), tokio::spawn(task));
+                }
+            }
+        }
+    });
+}
+
+/// What a device id is derived from. The id doubles as the proxy password, so it
+/// should survive reboots and USB re-enumeration.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IdStrategy {
+    /// Interface name, e.g. `enx0c5b8f279a64`; changes whenever udev names the stick differently
+    #[default]
+    Interface,
+    /// MAC address; some HiLink firmwares give every stick the same one
+    Mac,
+    /// USB port path, e.g. `1-1.2`; stays with the port when a stick is swapped
+    UsbPath,
+    /// IMEI asked from the modem's web API; follows the stick to any port
+    Imei,
+}
+
+impl std::str::FromStr for IdStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interface" => Ok(IdStrategy::Interface),
+            "mac" => Ok(IdStrategy::Mac),
+            "usb-path" => Ok(IdStrategy::UsbPath),
+            "imei" => Ok(IdStrategy::Imei),
+            _ => Err(format!(
+                "unknown device id strategy `{}`, expected interface, mac, usb-path or imei",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AliasFile {
+    aliases: HashMap<String, String>,
+}
+
+/// Old device ids mapped to current ones, so proxy passwords keep working after
+/// the id strategy changes.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceAliases(HashMap<String, String>);
+
+impl DeviceAliases {
+    /// Load aliases from a JSON file of the form `{"aliases": {"<old id>": "<current id>"}}`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data = std::fs::read(path)
+            .with_context(|| format!("read device aliases file {}", path.display()))?;
+        let file: AliasFile = serde_json::from_slice(&data)
+            .with_context(|| format!("parse device aliases file {}", path.display()))?;
+        Ok(DeviceAliases(file.aliases))
+    }
+
+    /// The current id for `id`, which is returned as is when it is no alias.
+    pub fn resolve<'a>(&'a self, id: &'a str) -> &'a str {
+        self.0.get(id).map(String::as_str).unwrap_or(id)
+    }
+}
+
+/// Assigns every modem interface its device id.
+#[derive(Builder)]
+#[builder(pattern = "owned")]
+pub struct DeviceIds {
+    #[builder(default)]
+    strategy: IdStrategy,
+    #[builder(default)]
+    aliases: DeviceAliases,
+    /// Address of the modems' web API, asked for the IMEI
+    #[builder(default = "\"192.168.8.1\".to_string()")]
+    modem_host: String,
+    #[builder(default = "30")]
+    timeout_secs: u64,
+    logger: Logger,
+    /// IMEIs by interface name and MAC, so each stick is asked once
+    #[builder(setter(skip))]
+    imeis: Mutex<HashMap<(String, Option<String>), String>>,
+}
+
+impl DeviceIds {
+    /// Device id to interface name for every IP-up modem interface. Interfaces whose
+    /// id cannot be derived (no USB path, modem not answering) are left out.
+    pub async fn interfaces(&self) -> io::Result<HashMap<String, String>> {
+        let mut names: Vec<String> = get_if_addrs()?
+            .into_iter()
+            .map(|iface| iface.name)
+            .filter(|name| name.starts_with("enx"))
+            .collect();
+        names.sort();
+        names.dedup();
+
+        let mut ifaces = HashMap::new();
+        for ifname in names {
+            let Some(id) = self.id(&ifname).await else {
+                continue;
+            };
+            match ifaces.entry(id) {
+                Entry::Vacant(slot) => {
+                    slot.insert(ifname);
+                }
+                Entry::Occupied(taken) => error!(self.logger, "device id already taken, interface left out";
+                    "device" => taken.key(),
+                    "iface" => &ifname,
+                    "taken_by" => taken.get(),
+                ),
+            }
+        }
+        Ok(ifaces)
+    }
+
+    /// The current id for `id`, resolving aliases.
+    pub fn resolve<'a>(&'a self, id: &'a str) -> &'a str {
+        self.aliases.resolve(id)
+    }
+
+    async fn id(&self, ifname: &str) -> Option<String> {
+        let key = match self.strategy {
+            IdStrategy::Interface => return Some(interface_id(ifname)),
+            IdStrategy::Mac => LinkInfo::read(ifname).mac.map(|mac| format!("mac:{}", mac)),
+            IdStrategy::UsbPath => LinkInfo::read(ifname)
+                .usb_path
+                .map(|path| format!("usb:{}", path)),
+            IdStrategy::Imei => self.imei(ifname).await.map(|imei| format!("imei:{}", imei)),
+        };
+        if key.is_none() {
+            warn!(self.logger, "no device id for interface, left out";
+                "iface" => ifname,
+                "strategy" => ?self.strategy,
+            );
+        }
+        key.map(|key| Uuid::new_v5(&Uuid::NAMESPACE_URL, key.as_bytes()).to_string())
+    }
+
+    async fn imei(&self, ifname: &str) -> Option<String> {
+        let cache_key = (ifname.to_string(), LinkInfo::read(ifname).mac);
+        if let Some(imei) = self.imeis.lock().unwrap().get(&cache_key) {
+            return Some(imei.clone());
+        }
+
+        let mut modem =
+            HuaweiE337::new(self.modem_host.clone(), self.timeout_secs).with_interface(ifname);
+        match modem.imei().await.map_err(|e| e.to_string()) {
+            Ok(imei) => {
+                self.imeis.lock().unwrap().insert(cache_key, imei.clone());
+                Some(imei)
+            }
+            Err(e) => {
+                warn!(self.logger, "ask modem for IMEI"; "iface" => ifname, "error" => e);
+                None
+            }
+        }
+    }
+}
+
+/// The id an interface had before id strategies existed: a v5 UUID of its name.
+pub fn interface_id(ifname: &str) -> String {
+    Uuid::new_v5(&Uuid::NAMESPACE_URL, ifname.as_bytes()).to_string()
+}
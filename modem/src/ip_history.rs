@@ -0,0 +1,148 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::{Deserialize, Serialize};
+use slog::{error, Logger};
+use time::OffsetDateTime;
+
+/// Sightings kept per device; the oldest are dropped first.
+const MAX_SIGHTINGS: usize = 1000;
+
+lazy_static! {
+    static ref DEVICE_IP_REPEATS: IntCounterVec = register_int_counter_vec!(
+        "device_ip_repeats_total",
+        "Exit IPs that came back after the device had moved to another one",
+        &["device"]
+    )
+    .unwrap();
+}
+
+/// A stretch of time during which a device exited through one public IP.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IpSighting {
+    pub ip: IpAddr,
+    #[serde(with = "time::serde::rfc3339")]
+    pub first_seen: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub last_seen: OffsetDateTime,
+}
+
+/// Exit IPs observed per device, latest first, optionally persisted to a JSON file.
+#[derive(Default)]
+pub struct IpHistory {
+    path: Option<PathBuf>,
+    devices: Mutex<HashMap<String, VecDeque<IpSighting>>>,
+}
+
+impl IpHistory {
+    /// Open the history at `path`, loading previously persisted sightings if the file exists.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("create IP history directory {}", dir.display()))?;
+        }
+        let devices = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .with_context(|| format!("parse IP history file {}", path.display()))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("read IP history file {}", path.display()))
+            }
+        };
+
+        Ok(IpHistory {
+            path: Some(path),
+            devices: Mutex::new(devices),
+        })
+    }
+
+    /// Note that `device` exits through `ip` right now. Consecutive observations of the
+    /// same IP extend one sighting; a different IP starts a new one.
+    pub fn record(&self, device: &str, ip: IpAddr) {
+        let now = OffsetDateTime::now_utc();
+        let mut devices = self.devices.lock().unwrap();
+        let sightings = devices.entry(device.to_string()).or_default();
+
+        match sightings.front_mut() {
+            Some(latest) if latest.ip == ip => latest.last_seen = now,
+            _ => {
+                if sightings.iter().any(|s| s.ip == ip) {
+                    DEVICE_IP_REPEATS.with_label_values(&[device]).inc();
+                }
+                sightings.push_front(IpSighting {
+                    ip,
+                    first_seen: now,
+                    last_seen: now,
+                });
+                sightings.truncate(MAX_SIGHTINGS);
+            }
+        }
+    }
+
+    /// Whether `device` exited through `ip` at any point between `since` and `until`.
+    pub fn seen_between(
+        &self,
+        device: &str,
+        ip: IpAddr,
+        since: OffsetDateTime,
+        until: OffsetDateTime,
+    ) -> bool {
+        self.devices.lock().unwrap().get(device).is_some_and(|s| {
+            s.iter()
+                .any(|s| s.ip == ip && s.first_seen < until && s.last_seen >= since)
+        })
+    }
+
+    /// The IP `device` was seen on last.
+    pub fn latest(&self, device: &str) -> Option<IpSighting> {
+        self.devices.lock().unwrap().get(device)?.front().cloned()
+    }
+
+    /// Sightings of `device`, latest first.
+    pub fn device(&self, device: &str) -> Vec<IpSighting> {
+        self.devices
+            .lock()
+            .unwrap()
+            .get(device)
+            .map(|s| s.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Write the history to the backing file (no-op for an in-memory history).
+    pub fn flush(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let data = serde_json::to_vec_pretty(&*self.devices.lock().unwrap())?;
+        // write to a temp file and rename so a crash never leaves a truncated file
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, data)
+            .with_context(|| format!("write IP history file {}", tmp.display()))?;
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("rename IP history file {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// Periodically persist the IP history.
+pub fn spawn_ip_history_flush_loop(history: Arc<IpHistory>, interval: Duration, logger: Logger) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(e) = history.flush() {
+                error!(logger, "flush IP history"; "error" => %e);
+            }
+        }
+    });
+}
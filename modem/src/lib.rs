@@ -3,7 +3,27 @@ pub mod device;
 pub mod api;
 pub mod socks5;
 pub mod metrics;
+mod openmetrics;
 pub mod modem;
 pub mod modem_huaweie337;
 pub mod tcp;
-mod username;
\ No newline at end of file
+pub mod acl;
+pub mod auth;
+pub mod usage;
+mod username;
+pub mod users;
+mod http_proxy;
+pub mod tls;
+mod proxy_protocol;
+mod socks4;
+pub mod upstream;
+pub mod shutdown;
+pub mod telemetry;
+pub mod health;
+pub mod rotation;
+pub mod ip_history;
+pub mod identity;
+pub mod events;
+pub mod sms;
+pub mod live;
+pub mod config;
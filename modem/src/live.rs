@@ -0,0 +1,20 @@
+use std::sync::{Arc, RwLock};
+
+/// A value that can be replaced at runtime (e.g. on a config reload). Readers keep
+/// the version they got until they ask again, so an open tunnel is never affected.
+#[derive(Debug, Default)]
+pub struct Live<T>(RwLock<Arc<T>>);
+
+impl<T> Live<T> {
+    pub fn new(value: T) -> Self {
+        Live(RwLock::new(Arc::new(value)))
+    }
+
+    pub fn get(&self) -> Arc<T> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn set(&self, value: T) {
+        *self.0.write().unwrap() = Arc::new(value);
+    }
+}
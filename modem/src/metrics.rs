@@ -1,22 +1,52 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
 
+use anyhow::{Context, Result};
 use axum::body::Body;
 use axum::{
     Router,
-    extract::Request,
-    http::{HeaderValue, StatusCode, header},
+    extract::{Query, Request},
+    http::{HeaderMap, HeaderValue, StatusCode, header::ACCEPT},
     middleware::{self, Next},
     response::{IntoResponse, Response},
     routing::get,
 };
-use base64::{Engine as _, engine::general_purpose};
 use prometheus::{Encoder, TextEncoder};
-use slog::{Logger, debug};
+use serde::Deserialize;
+use slog::{Logger, debug, error};
 use tokio::sync::oneshot;
+use tokio_rustls::TlsAcceptor;
+
+use crate::auth::{HttpAuth, Role, require_role};
+use crate::openmetrics::{self, OPENMETRICS_FORMAT};
+use crate::tls::HttpListener;
+
+#[derive(Deserialize)]
+struct MetricsQuery {
+    /// `text` or `openmetrics`; overrides the `Accept` header
+    format: Option<String>,
+}
+
+/// Serves the Prometheus text format, or OpenMetrics (with exemplars) when the
+/// scraper asks for it.
+async fn metrics_handler(Query(query): Query<MetricsQuery>, headers: HeaderMap) -> Response {
+    let openmetrics = match query.format.as_deref() {
+        Some(format) => format == "openmetrics",
+        None => headers
+            .get(ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.contains("application/openmetrics-text")),
+    };
 
-async fn metrics_handler() -> impl IntoResponse {
     let metric_families = prometheus::gather();
+    if openmetrics {
+        return Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", HeaderValue::from_static(OPENMETRICS_FORMAT))
+            .body(openmetrics::encode(&metric_families).into())
+            .unwrap();
+    }
+
     let encoder = TextEncoder::new();
     let mut buffer = Vec::new();
 
@@ -37,99 +67,74 @@ async fn metrics_handler() -> impl IntoResponse {
         .unwrap()
 }
 
-// Basic auth middleware
-async fn basic_auth(
-    req: Request,
-    next: Next,
-    credentials: Arc<(String, String)>,
-) -> Result<Response, StatusCode> {
-    // Get authorization header
-    let auth_header = req
-        .headers()
-        .get(header::AUTHORIZATION)
-        .and_then(|header| header.to_str().ok());
-
-    // Check if authorization header is present and valid
-    match auth_header {
-        Some(auth) if auth.starts_with("Basic ") => {
-            // Extract credentials from header
-            let encoded = auth.trim_start_matches("Basic ");
-            let decoded = general_purpose::STANDARD
-                .decode(encoded)
-                .map_err(|_| StatusCode::UNAUTHORIZED)?;
-
-            let decoded_str = String::from_utf8(decoded).map_err(|_| StatusCode::UNAUTHORIZED)?;
-
-            // Check credentials
-            if decoded_str == format!("{}:{}", credentials.0, credentials.1) {
-                // Authentication successful, proceed with request
-                let response = next.run(req).await;
-                Ok(response)
-            } else {
-                // Invalid credentials
-                Err(StatusCode::UNAUTHORIZED)
-            }
-        }
-        _ => {
-            // No or invalid authorization header
-            Err(StatusCode::UNAUTHORIZED)
-        }
-    }
-}
-
 async fn handler_404() -> impl IntoResponse {
     (StatusCode::NOT_FOUND, "Not found")
 }
 
-/// Start a metrics server with basic authentication
+/// Liveness probe; needs no credentials.
+async fn healthz_handler() -> impl IntoResponse {
+    (StatusCode::OK, "ok")
+}
+
+/// Start a metrics server protected by `auth` (Basic credentials and/or bearer tokens),
+/// over HTTPS when `tls` is set
 ///
+/// The listener is bound before returning, so a taken port is reported to the caller.
 /// Returns a shutdown signal sender that can be used to stop the server
 pub async fn start_metrics_server(
     addr: SocketAddr,
-    username: String,
-    password: String,
+    auth: HttpAuth,
+    tls: Option<TlsAcceptor>,
     logger: Logger,
-) -> oneshot::Sender<()> {
+) -> Result<oneshot::Sender<()>> {
     let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
 
-    tokio::spawn(async move {
-        let mut app = Router::new()
-            .route("/metrics", get(metrics_handler))
-            .fallback(handler_404);
-
-        // Only add auth middleware if both username and password are provided
-        if !username.is_empty() && !password.is_empty() {
-            // Store credentials in Arc for sharing across async tasks
-            let credentials = Arc::new((username, password));
-
-            let auth_middleware = move |req: Request, next: Next| {
-                let creds = credentials.clone();
-                async move { basic_auth(req, next, creds).await }
-            };
-
-            app = app.layer(middleware::from_fn(auth_middleware));
-            debug!(logger, "Metrics server started with authentication";);
-        } else {
-            debug!(logger, "Metrics server started without authentication");
-        }
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    let listener = HttpListener::bind(addr, tls, logger.clone())
+        .await
+        .with_context(|| format!("bind metrics server to {}", addr))?;
+
+    let mut app = Router::new().route("/metrics", get(metrics_handler));
+
+    // Only add auth middleware if credentials or tokens are configured
+    if auth.is_enabled() {
+        // Store credentials in Arc for sharing across async tasks
+        let auth = Arc::new(auth);
+        let auth_logger = logger.clone();
+
+        let auth_middleware = move |req: Request, next: Next| {
+            let auth = auth.clone();
+            let logger = auth_logger.clone();
+            async move { require_role(req, next, auth, Role::Read, logger).await }
+        };
+
+        app = app.route_layer(middleware::from_fn(auth_middleware));
+        debug!(logger, "Metrics server started with authentication";);
+    } else {
+        debug!(logger, "Metrics server started without authentication");
+    }
 
-        debug!(
-            logger,
-            "Metrics server listening on http://{}/metrics", addr
-        );
+    // added after the auth layer so probes need no credentials
+    let app = app
+        .route("/healthz", get(healthz_handler))
+        .fallback(handler_404);
 
-        let listener = tokio::net::TcpListener::bind(addr)
-            .await
-            .unwrap_or_else(|e| panic!("could not bind to {}: {}", addr, e));
+    debug!(
+        logger,
+        "Metrics server listening on {}://{}/metrics", scheme, addr
+    );
 
+    tokio::spawn(async move {
         // Use Axum's serve with graceful shutdown
-        axum::serve(listener, app)
+        let served = axum::serve(listener, app)
             .with_graceful_shutdown(async {
                 shutdown_rx.await.ok();
             })
-            .await
-            .unwrap_or_else(|e| panic!("could not server app {}", e));
+            .await;
+        if let Err(e) = served {
+            error!(logger, "metrics server failed"; "error" => %e);
+        }
     });
 
-    shutdown_tx
+    Ok(shutdown_tx)
 }
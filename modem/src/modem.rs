@@ -1,7 +1,130 @@
 use async_trait::async_trait;
-use std::error::Error;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::{Arc, RwLock},
+};
+use tokio::sync::Mutex;
+
+use crate::identity::Interfaces;
 
 #[async_trait]
 pub trait Modem: Send + Sync {
     async fn reboot(&mut self) -> Result<(), Box<dyn Error>>;
-}
\ No newline at end of file
+
+    /// Drop and re-establish the data connection; the carrier usually hands out a new IP.
+    async fn reconnect(&mut self) -> Result<(), Box<dyn Error>>;
+
+    /// The stick's IMEI; unlike the interface name it never changes.
+    async fn imei(&mut self) -> Result<String, Box<dyn Error>>;
+
+    /// Current radio, connection and traffic readings.
+    async fn telemetry(&mut self) -> Result<Telemetry, Box<dyn Error>>;
+
+    /// Unread messages in the inbox, oldest first.
+    async fn unread_sms(&mut self) -> Result<Vec<Sms>, Box<dyn Error>>;
+
+    async fn mark_sms_read(&mut self, index: u64) -> Result<(), Box<dyn Error>>;
+
+    async fn delete_sms(&mut self, index: u64) -> Result<(), Box<dyn Error>>;
+
+    /// Up to `count` inbox messages, read or not, newest first.
+    async fn list_sms(&mut self, count: u32) -> Result<Vec<Sms>, Box<dyn Error>>;
+
+    async fn send_sms(&mut self, phone: &str, content: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Run a USSD code such as `*100#` and return the network's reply.
+    async fn ussd(&mut self, code: &str) -> Result<String, Box<dyn Error>>;
+}
+
+/// An inbound text message as stored on the SIM or modem.
+#[derive(Clone, Debug, Serialize)]
+pub struct Sms {
+    /// Storage slot, used to mark the message read or delete it
+    pub index: u64,
+    pub phone: String,
+    pub content: String,
+    /// Modem local time, e.g. `2025-05-15 10:00:00`
+    pub date: String,
+    /// Not yet marked read on the modem
+    pub unread: bool,
+}
+
+/// A snapshot of what the modem reports about its SIM, radio link and traffic.
+/// Readings the modem leaves empty (e.g. RSRP outside LTE) are `None`.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Telemetry {
+    pub imei: String,
+    pub connected: bool,
+    /// `LTE`, `3G`, `2G`, `none` or `unknown`
+    pub network_type: String,
+    pub operator: String,
+    pub cell_id: String,
+    pub rsrp_dbm: Option<f64>,
+    pub rsrq_db: Option<f64>,
+    pub sinr_db: Option<f64>,
+    pub rssi_dbm: Option<f64>,
+    /// Totals since the modem's statistics were last cleared
+    pub upload_bytes: u64,
+    pub download_bytes: u64,
+    /// Seconds since the current data session was established
+    pub connected_seconds: u64,
+}
+
+/// A modem handle shared by everything that talks to the stick, so they keep one
+/// web UI session.
+pub type SharedModem = Arc<Mutex<dyn Modem + Send + Sync>>;
+
+/// Opens a device's modem: its web API URL and handle, or `None` if it has no modem.
+type Open = dyn Fn(&str) -> Option<(String, SharedModem)> + Send + Sync;
+
+/// The modems of the devices currently in the interface map. A handle is opened the
+/// first time a device is asked for, so devices plugged in at runtime get one too,
+/// and stays with the device id whatever interface it moves to.
+pub struct Modems {
+    interfaces: Arc<Interfaces>,
+    open: Box<Open>,
+    handles: RwLock<HashMap<String, (String, SharedModem)>>,
+}
+
+impl Modems {
+    pub fn new(
+        interfaces: Arc<Interfaces>,
+        open: impl Fn(&str) -> Option<(String, SharedModem)> + Send + Sync + 'static,
+    ) -> Self {
+        Modems {
+            interfaces,
+            open: Box::new(open),
+            handles: RwLock::default(),
+        }
+    }
+
+    /// The modem of `device`, `None` if it has none or is not connected right now.
+    pub fn get(&self, device: &str) -> Option<SharedModem> {
+        self.entry(device).map(|(_, modem)| modem)
+    }
+
+    /// Web API URL of `device`'s modem, e.g. `http://192.168.8.1/api`.
+    pub fn api_url(&self, device: &str) -> Option<String> {
+        self.entry(device).map(|(url, _)| url)
+    }
+
+    fn entry(&self, device: &str) -> Option<(String, SharedModem)> {
+        if !self.interfaces.get().contains_key(device) {
+            return None;
+        }
+        if let Some(entry) = self.handles.read().unwrap().get(device) {
+            return Some(entry.clone());
+        }
+        let opened = (self.open)(device)?;
+        let mut handles = self.handles.write().unwrap();
+        Some(handles.entry(device.to_string()).or_insert(opened).clone())
+    }
+}
+
+impl Default for Modems {
+    fn default() -> Self {
+        Modems::new(Arc::default(), |_| None)
+    }
+}
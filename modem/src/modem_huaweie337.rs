@@ -3,20 +3,39 @@ use async_trait::async_trait;
 use base64::{decode as b64decode, encode as b64encode};
 use quick_xml::{events::Event, Reader};
 use reqwest::header::{COOKIE};
-use std::{error::Error, time::Duration};
+use std::{collections::HashMap, error::Error, sync::Arc, time::Duration};
+use time::OffsetDateTime;
 
 // We'll use openssl instead of the problematic rsa crate
-use crate::modem::Modem;
+use crate::{
+    identity::Interfaces,
+    modem::{Modem, Sms, Telemetry},
+};
 use openssl::{
     bn::BigNum,
     rsa::{Padding, Rsa},
 };
 
+/// How long `ussd` waits for the network's reply.
+const USSD_POLL_TRIES: u32 = 15;
+const USSD_POLL_DELAY: Duration = Duration::from_secs(2);
+/// Error codes for an expired session (125002) or a stale verification token (125003)
+const SESSION_ERRORS: &[&str] = &["125002", "125003"];
+
 pub struct HuaweiE337 {
     host: String,
     session_token: Option<String>,
     verification_token: Option<String>,
     timeout_secs: u64,
+    /// Reach the web UI through this interface; every stick answers on the same address
+    interface: Option<Binding>,
+}
+
+/// Which interface the web UI is reached through.
+enum Binding {
+    Fixed(String),
+    /// Whatever interface the device id maps to at the time of the request
+    Live(Arc<Interfaces>, String),
 }
 
 impl HuaweiE337 {
@@ -27,10 +46,45 @@ impl HuaweiE337 {
             session_token: None,
             verification_token: None,
             timeout_secs,
+            interface: None,
+        }
+    }
+
+    /// Talk to the stick attached to `ifname` instead of following the routing table
+    pub fn with_interface(mut self, ifname: impl Into<String>) -> Self {
+        self.interface = Some(Binding::Fixed(ifname.into()));
+        self
+    }
+
+    /// Talk to the stick through whatever interface `device` maps to in `interfaces`,
+    /// so the handle keeps working after the stick is re-enumerated
+    pub fn with_interfaces(
+        mut self,
+        interfaces: Arc<Interfaces>,
+        device: impl Into<String>,
+    ) -> Self {
+        self.interface = Some(Binding::Live(interfaces, device.into()));
+        self
+    }
+
+    fn client(&self) -> Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder();
+        match &self.interface {
+            Some(Binding::Fixed(ifname)) => builder = builder.interface(ifname),
+            Some(Binding::Live(interfaces, device)) => {
+                let ifname = interfaces
+                    .get()
+                    .get(device)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("device {} has no interface", device))?;
+                builder = builder.interface(&ifname);
+            }
+            None => {}
         }
+        Ok(builder.build()?)
     }
 
-    /// Initialize the session by refreshing tokens - must be called before reconnect
+    /// Initialize the session by refreshing tokens - checks the modem is reachable up front
     pub async fn init(&mut self) -> Result<()> {
         self.refresh_session_token().await
     }
@@ -39,7 +93,7 @@ impl HuaweiE337 {
     async fn refresh_session_token(&mut self) -> Result<()> {
         let url = format!("http://{}/api/webserver/SesTokInfo", self.host);
 
-        let client = reqwest::Client::new();
+        let client = self.client()?;
         let response = client
             .get(&url)
             .timeout(Duration::from_secs(self.timeout_secs))
@@ -64,11 +118,10 @@ impl HuaweiE337 {
         loop {
             match reader.read_event(&mut buf) {
                 Ok(Event::Start(ref e)) if e.name() == tag.as_bytes() => {
-                    txt.push(
-                        reader
-                            .read_text(tag.as_bytes(), &mut Vec::new())
-                            .expect("Cannot decode text value"),
-                    );
+                    let text = reader
+                        .read_text(tag.as_bytes(), &mut Vec::new())
+                        .map_err(|e| anyhow!("Cannot decode text value of {}: {:?}", tag, e))?;
+                    txt.push(text);
                     return Ok(txt[0].clone());
                 }
                 Ok(Event::Eof) => return Err(anyhow!("Expected tag {} not found", tag)),
@@ -85,12 +138,97 @@ impl HuaweiE337 {
         }
     }
 
+    /// Like `get_value_from_tag`, but empty or missing tags are `None`
+    async fn optional_tag(&self, xml: &str, tag: &str) -> Option<String> {
+        self.get_value_from_tag(xml, tag)
+            .await
+            .ok()
+            .filter(|v| !v.is_empty())
+    }
+
+    /// GET an authenticated API page, e.g. `device/signal`. Tokens are only fetched
+    /// when there are none yet or the modem reports the session as expired.
+    async fn get_page(&mut self, page: &str) -> Result<String> {
+        if self.session_token.is_none() || self.verification_token.is_none() {
+            self.refresh_session_token().await?;
+        }
+        let body = match self.request_page(page).await? {
+            Err(code) if SESSION_ERRORS.contains(&code.as_str()) => {
+                self.refresh_session_token().await?;
+                self.request_page(page).await?
+            }
+            body => body,
+        };
+        body.map_err(|code| anyhow!("{} failed with error {}", page, code))
+    }
+
+    /// One GET of `page`; the inner error is the modem's error code
+    async fn request_page(&mut self, page: &str) -> Result<std::result::Result<String, String>> {
+        let url = format!("http://{}/api/{}", self.host, page);
+        let resp = match (&self.session_token, &self.verification_token) {
+            (Some(token), Some(verif_token)) => {
+                self.client()?
+                    .get(&url)
+                    .header(COOKIE, format!("SessionId={}", token))
+                    .header("__RequestVerificationToken", verif_token)
+                    .timeout(Duration::from_secs(self.timeout_secs))
+                    .send()
+                    .await?
+            }
+            _ => return Err(anyhow!("Missing session or verification token")),
+        };
+
+        let body = resp.text().await?;
+        if body.contains("<error>") {
+            let code = self.optional_tag(&body, "code").await.unwrap_or_default();
+            return Ok(Err(code));
+        }
+        Ok(Ok(body))
+    }
+
+    /// POST an XML request to an authenticated API page, e.g. `dialup/mobile-dataswitch`
+    async fn post_page(&mut self, page: &str, xml: &str) -> Result<()> {
+        let body = self.post_query(page, xml).await?;
+        if !body.contains("<response>OK</response>") {
+            return Err(anyhow!("{} failed: {}", page, body));
+        }
+        Ok(())
+    }
+
+    /// POST an XML request to a page that answers with data, e.g. `sms/sms-list`
+    async fn post_query(&mut self, page: &str, xml: &str) -> Result<String> {
+        // verification tokens are single-use on most firmwares
+        self.refresh_session_token().await?;
+
+        let url = format!("http://{}/api/{}", self.host, page);
+        let resp = match (&self.session_token, &self.verification_token) {
+            (Some(token), Some(verif_token)) => {
+                self.client()?
+                    .post(&url)
+                    .header(COOKIE, format!("SessionId={}", token))
+                    .header("__RequestVerificationToken", verif_token)
+                    .body(xml.to_string())
+                    .timeout(Duration::from_secs(self.timeout_secs))
+                    .send()
+                    .await?
+            }
+            _ => return Err(anyhow!("Missing session or verification token")),
+        };
+
+        let body = resp.text().await?;
+        if body.contains("<error>") {
+            let code = self.optional_tag(&body, "code").await.unwrap_or_default();
+            return Err(anyhow!("{} failed with error {}", page, code));
+        }
+        Ok(body)
+    }
+
     /// Fetch public key and encrypt payload using OpenSSL
     async fn encrypt_with_public_key(&mut self, payload: &str) -> Result<String> {
         // 1) Fetch the modem's public key
         let url = format!("http://{}/api/webserver/publickey", self.host);
 
-        let client = reqwest::Client::new();
+        let client = self.client()?;
         let resp = match (&self.session_token, &self.verification_token) {
             (Some(token), Some(verif_token)) => {
                 client
@@ -143,51 +281,351 @@ impl HuaweiE337 {
     }
 }
 
+/// Signal readings come as `-95dBm`, `12dB` or `>=-51dBm`
+fn parse_level(value: &str) -> Option<f64> {
+    value
+        .trim_start_matches(['>', '<', '='])
+        .trim_end_matches(|c: char| c.is_ascii_alphabetic())
+        .parse()
+        .ok()
+}
+
+/// The `<Message>` entries of an `sms/sms-list` response, in the order listed.
+fn parse_sms_list(xml: &str) -> Result<Vec<Sms>> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+    let mut buf = Vec::new();
+    let mut messages = Vec::new();
+    let mut fields: Option<HashMap<String, String>> = None;
+    let mut tag = String::new();
+
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e)) if e.name() == b"Message" => fields = Some(HashMap::new()),
+            Ok(Event::Start(ref e)) => tag = String::from_utf8_lossy(e.name()).into_owned(),
+            Ok(Event::Text(ref e)) => {
+                if let Some(fields) = fields.as_mut() {
+                    let text = e.unescape_and_decode(&reader)?;
+                    fields.insert(tag.clone(), text);
+                }
+            }
+            Ok(Event::End(ref e)) if e.name() == b"Message" => {
+                let Some(fields) = fields.take() else {
+                    continue;
+                };
+                let field = |name: &str| fields.get(name).cloned().unwrap_or_default();
+                messages.push(Sms {
+                    index: field("Index")
+                        .parse()
+                        .map_err(|_| anyhow!("SMS without index"))?,
+                    phone: field("Phone"),
+                    content: field("Content"),
+                    date: field("Date"),
+                    unread: field("Smstat") == "0",
+                });
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => {
+                return Err(anyhow!(
+                    "Error at position {}: {:?}",
+                    reader.buffer_position(),
+                    e
+                ))
+            }
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    Ok(messages)
+}
+
+/// The `sms/sms-list` request for the first `count` inbox (`BoxType` 1) messages, newest first.
+fn sms_list_request(count: u32, unread_first: bool) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?><request><PageIndex>1</PageIndex><ReadCount>{}</ReadCount><BoxType>1</BoxType><SortType>0</SortType><Ascending>0</Ascending><UnreadPreferred>{}</UnreadPreferred></request>"#,
+        count.clamp(1, 50),
+        u8::from(unread_first)
+    )
+}
+
+/// `2025-05-15 10:00:00`, the date format of the SMS API.
+fn sms_date(at: OffsetDateTime) -> String {
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02}:{:02}",
+        at.year(),
+        u8::from(at.month()),
+        at.day(),
+        at.hour(),
+        at.minute(),
+        at.second()
+    )
+}
+
+/// Escape text for an XML request body.
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// `CurrentNetworkType` / `CurrentNetworkTypeEx` codes of the HiLink API
+fn network_type(code: &str) -> &'static str {
+    match code.parse::<u32>() {
+        Ok(0) => "none",
+        Ok(1..=3 | 21..=23) => "2G",
+        Ok(4..=18 | 41..=46 | 61..=65) => "3G",
+        Ok(19 | 101) => "LTE",
+        _ => "unknown",
+    }
+}
+
 #[async_trait]
 impl Modem for HuaweiE337 {
     /// Reconnect the modem - main functionality
     async fn reboot(&mut self) -> Result<(), Box<dyn Error>> {
-        // Ensure we have valid tokens
-        if self.session_token.is_none() || self.verification_token.is_none() {
-            return Err(Box::from(anyhow!(
-                "Session not initialized, call init() first"
-            )));
-        }
-
-        // Prepare reconnect XML payload
+        // post_page fetches fresh tokens; cached ones are single-use or expired by now
         let xml =
             r#"<?xml version="1.0" encoding="UTF-8"?><request><Control>1</Control></request>"#;
+        self.post_page("device/control", xml).await?;
 
-        // Send the reconnect request
-        let url = format!("http://{}/api/device/control", self.host);
-        let client = reqwest::Client::new();
+        Ok(())
+    }
 
-        let resp = match (&self.session_token, &self.verification_token) {
-            (Some(token), Some(verif_token)) => {
-                client
-                    .post(&url)
-                    .header(COOKIE, format!("SessionId={}", token))
-                    .header("__requestverificationtoken", verif_token)
-                    .body(xml)
-                    .timeout(Duration::from_secs(self.timeout_secs))
-                    .send()
-                    .await?
-            }
-            _ => return Err(Box::from(anyhow!("Missing session or verification token"))),
+    /// Switch mobile data off and on again; much faster than a reboot
+    async fn reconnect(&mut self) -> Result<(), Box<dyn Error>> {
+        let switch = |on: u8| {
+            format!(
+                r#"<?xml version="1.0" encoding="UTF-8"?><request><dataswitch>{}</dataswitch></request>"#,
+                on
+            )
         };
 
-        // Update verification token if present in response
-        if let Some(new_token) = resp.headers().get("__requestverificationtoken") {
-            self.verification_token = Some(new_token.to_str()?.to_owned());
-        }
+        self.post_page("dialup/mobile-dataswitch", &switch(0)).await?;
+        // give the stick time to tear the bearer down before dialing again
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        self.post_page("dialup/mobile-dataswitch", &switch(1)).await?;
 
-        let response_text = resp.text().await?;
+        Ok(())
+    }
 
-        // Check if response contains "OK"
-        if !response_text.contains("<response>OK</response>") {
-            return Err(Box::from(anyhow!("Reconnect failed: {}", response_text)));
+    async fn imei(&mut self) -> Result<String, Box<dyn Error>> {
+        let info = self.get_page("device/information").await?;
+        let imei = self
+            .optional_tag(&info, "Imei")
+            .await
+            .ok_or_else(|| anyhow!("modem reported no IMEI"))?;
+        Ok(imei)
+    }
+
+    async fn telemetry(&mut self) -> Result<Telemetry, Box<dyn Error>> {
+        let info = self.get_page("device/information").await?;
+        let signal = self.get_page("device/signal").await?;
+        let status = self.get_page("monitoring/status").await?;
+        let plmn = self.get_page("net/current-plmn").await?;
+        let traffic = self.get_page("monitoring/traffic-statistics").await?;
+
+        let number = |v: Option<String>| v.and_then(|v| v.parse().ok()).unwrap_or_default();
+        let network = match self.optional_tag(&status, "CurrentNetworkTypeEx").await {
+            Some(code) => Some(code),
+            None => self.optional_tag(&status, "CurrentNetworkType").await,
+        };
+        let connection = self.optional_tag(&status, "ConnectionStatus").await;
+        let rsrp = self.optional_tag(&signal, "rsrp").await;
+        let rsrq = self.optional_tag(&signal, "rsrq").await;
+        let sinr = self.optional_tag(&signal, "sinr").await;
+        let rssi = self.optional_tag(&signal, "rssi").await;
+        let imei = self.optional_tag(&info, "Imei").await;
+        let operator = self.optional_tag(&plmn, "FullName").await;
+        let cell_id = self.optional_tag(&signal, "cell_id").await;
+
+        Ok(Telemetry {
+            imei: imei.unwrap_or_default(),
+            connected: connection.as_deref() == Some("901"),
+            network_type: network_type(network.as_deref().unwrap_or_default()).to_string(),
+            operator: operator.unwrap_or_default(),
+            cell_id: cell_id.unwrap_or_default(),
+            rsrp_dbm: rsrp.as_deref().and_then(parse_level),
+            rsrq_db: rsrq.as_deref().and_then(parse_level),
+            sinr_db: sinr.as_deref().and_then(parse_level),
+            rssi_dbm: rssi.as_deref().and_then(parse_level),
+            upload_bytes: number(self.optional_tag(&traffic, "TotalUpload").await),
+            download_bytes: number(self.optional_tag(&traffic, "TotalDownload").await),
+            connected_seconds: number(self.optional_tag(&traffic, "CurrentConnectTime").await),
+        })
+    }
+
+    async fn unread_sms(&mut self) -> Result<Vec<Sms>, Box<dyn Error>> {
+        let notifications = self.get_page("monitoring/check-notifications").await?;
+        let unread: u32 = self
+            .optional_tag(&notifications, "UnreadMessage")
+            .await
+            .and_then(|n| n.parse().ok())
+            .unwrap_or_default();
+        if unread == 0 {
+            return Ok(Vec::new());
         }
 
+        // unread ones before read ones
+        let list = self
+            .post_query("sms/sms-list", &sms_list_request(unread, true))
+            .await?;
+        let mut messages = parse_sms_list(&list)?;
+        messages.retain(|m| m.unread);
+        // oldest first
+        messages.reverse();
+        Ok(messages)
+    }
+
+    async fn mark_sms_read(&mut self, index: u64) -> Result<(), Box<dyn Error>> {
+        let xml = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?><request><Index>{}</Index></request>"#,
+            index
+        );
+        self.post_page("sms/set-read", &xml).await?;
         Ok(())
     }
+
+    async fn delete_sms(&mut self, index: u64) -> Result<(), Box<dyn Error>> {
+        let xml = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?><request><Index>{}</Index></request>"#,
+            index
+        );
+        self.post_page("sms/delete-sms", &xml).await?;
+        Ok(())
+    }
+
+    async fn list_sms(&mut self, count: u32) -> Result<Vec<Sms>, Box<dyn Error>> {
+        let list = self
+            .post_query("sms/sms-list", &sms_list_request(count, false))
+            .await?;
+        Ok(parse_sms_list(&list)?)
+    }
+
+    async fn send_sms(&mut self, phone: &str, content: &str) -> Result<(), Box<dyn Error>> {
+        let xml = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?><request><Index>-1</Index><Phones><Phone>{}</Phone></Phones><Sca></Sca><Content>{}</Content><Length>{}</Length><Reserved>1</Reserved><Date>{}</Date></request>"#,
+            xml_escape(phone),
+            xml_escape(content),
+            content.chars().count(),
+            sms_date(OffsetDateTime::now_utc())
+        );
+        self.post_page("sms/send-sms", &xml).await?;
+        Ok(())
+    }
+
+    async fn ussd(&mut self, code: &str) -> Result<String, Box<dyn Error>> {
+        let xml = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?><request><content>{}</content><codeType>CodeType</codeType><timeout></timeout></request>"#,
+            xml_escape(code)
+        );
+        self.post_page("ussd/send", &xml).await?;
+
+        // the reply arrives asynchronously; `result` is 1 while the network is still answering
+        for _ in 0..USSD_POLL_TRIES {
+            tokio::time::sleep(USSD_POLL_DELAY).await;
+            // `get_page` renews the session itself when it expired
+            let status = self.get_page("ussd/status").await?;
+            if self.optional_tag(&status, "result").await.as_deref() == Some("1") {
+                continue;
+            }
+            let reply = self.get_page("ussd/get").await?;
+            return Ok(self.get_value_from_tag(&reply, "content").await?);
+        }
+        Err(format!(
+            "no USSD reply to {} after {:?}",
+            code,
+            USSD_POLL_DELAY * USSD_POLL_TRIES
+        )
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIST: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<response>
+<Count>2</Count>
+<Messages>
+<Message>
+<Smstat>0</Smstat>
+<Index>40001</Index>
+<Phone>+380501234567</Phone>
+<Content>Code: 1234 &amp; more</Content>
+<Date>2025-05-15 10:00:00</Date>
+<Sca></Sca>
+<SaveType>4</SaveType>
+<Priority>0</Priority>
+<SmsType>1</SmsType>
+</Message>
+<Message>
+<Smstat>1</Smstat>
+<Index>40000</Index>
+<Phone>Kyivstar</Phone>
+<Content>Balance 10 UAH</Content>
+<Date>2025-05-14 09:00:00</Date>
+</Message>
+</Messages>
+</response>"#;
+
+    #[test]
+    fn parses_sms_list() {
+        let messages = parse_sms_list(LIST).unwrap();
+        assert_eq!(messages.len(), 2);
+
+        assert_eq!(messages[0].index, 40001);
+        assert_eq!(messages[0].phone, "+380501234567");
+        assert_eq!(messages[0].content, "Code: 1234 & more");
+        assert_eq!(messages[0].date, "2025-05-15 10:00:00");
+        assert!(messages[0].unread);
+
+        assert_eq!(messages[1].index, 40000);
+        assert_eq!(messages[1].phone, "Kyivstar");
+        assert!(!messages[1].unread);
+    }
+
+    #[test]
+    fn parses_empty_sms_list() {
+        let xml = "<response><Count>0</Count><Messages></Messages></response>";
+        assert!(parse_sms_list(xml).unwrap().is_empty());
+    }
+
+    #[test]
+    fn drops_truncated_message() {
+        let cut = LIST.find("<Index>40000").unwrap();
+        let messages = parse_sms_list(&LIST[..cut]).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].index, 40001);
+    }
+
+    #[test]
+    fn rejects_message_without_index() {
+        let xml = "<response><Messages><Message><Phone>+380501234567</Phone>\
+                   <Content>hi</Content></Message></Messages></response>";
+        assert!(parse_sms_list(xml).is_err());
+
+        let xml = "<response><Messages><Message><Index>x</Index></Message></Messages></response>";
+        assert!(parse_sms_list(xml).is_err());
+    }
+
+    #[tokio::test]
+    async fn malformed_tag_value_is_an_error() {
+        let modem = HuaweiE337::new("192.168.8.1".to_string(), 1);
+        let xml = "<response><Imei>861234567890123</response>";
+        assert!(modem.get_value_from_tag(xml, "Imei").await.is_err());
+        assert_eq!(modem.optional_tag(xml, "Imei").await, None);
+
+        let xml = "<response><Imei>861234567890123";
+        assert!(modem.get_value_from_tag(xml, "Imei").await.is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_xml() {
+        let xml = "<response><Messages><Message><Index>1</Phone></Message></Messages></response>";
+        assert!(parse_sms_list(xml).is_err());
+    }
 }
@@ -0,0 +1,321 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use prometheus::{
+    proto::{LabelPair, MetricFamily, MetricType},
+    HistogramVec,
+};
+
+/// Content type of the OpenMetrics text exposition.
+pub const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Longest exemplar label set the spec allows, in UTF-8 characters.
+const MAX_EXEMPLAR_LABELS_LEN: usize = 128;
+
+struct Exemplar {
+    labels: Vec<(&'static str, String)>,
+    value: f64,
+    timestamp: f64,
+}
+
+/// Series identity: metric family name, `name=value` labels sorted by name (the
+/// order the registry reports them in) and bucket upper bound.
+type ExemplarKey = (String, Vec<String>, u64);
+
+lazy_static! {
+    /// The latest exemplar per histogram bucket.
+    static ref EXEMPLARS: Mutex<HashMap<ExemplarKey, Exemplar>> = Mutex::new(HashMap::new());
+}
+
+/// Observe `value` on `histogram` and keep it as the exemplar of the bucket it falls
+/// in. `name` must be the name the histogram was registered with and `buckets` its
+/// bucket bounds.
+pub(crate) fn observe_with_exemplar(
+    histogram: &HistogramVec,
+    name: &str,
+    buckets: &[f64],
+    labels: &[(&str, &str)],
+    value: f64,
+    exemplar: Vec<(&'static str, String)>,
+) {
+    histogram
+        .with(&labels.iter().copied().collect::<HashMap<_, _>>())
+        .observe(value);
+
+    let labels_len: usize = exemplar
+        .iter()
+        .map(|(k, v)| k.chars().count() + v.chars().count())
+        .sum();
+    if labels_len > MAX_EXEMPLAR_LABELS_LEN {
+        return;
+    }
+
+    let bound = buckets
+        .iter()
+        .copied()
+        .find(|b| value <= *b)
+        .unwrap_or(f64::INFINITY);
+    let key = (
+        name.to_string(),
+        series_labels(labels.iter().copied()),
+        bound.to_bits(),
+    );
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    EXEMPLARS.lock().unwrap().insert(
+        key,
+        Exemplar {
+            labels: exemplar,
+            value,
+            timestamp,
+        },
+    );
+}
+
+/// Encodes `families` in the OpenMetrics 1.0 text format, with the recorded
+/// exemplars attached to histogram buckets.
+pub fn encode(families: &[MetricFamily]) -> String {
+    let exemplars = EXEMPLARS.lock().unwrap();
+    let mut out = String::new();
+
+    for mf in families {
+        let name = mf.name();
+        let (family, kind) = match mf.get_field_type() {
+            // the family is named without the suffix, its samples with it
+            MetricType::COUNTER => (name.strip_suffix("_total").unwrap_or(name), "counter"),
+            MetricType::GAUGE => (name, "gauge"),
+            MetricType::HISTOGRAM => (name, "histogram"),
+            MetricType::SUMMARY => (name, "summary"),
+            MetricType::UNTYPED => (name, "unknown"),
+        };
+        let _ = writeln!(out, "# TYPE {} {}", family, kind);
+        if !mf.help().is_empty() {
+            let _ = writeln!(out, "# HELP {} {}", family, escape(mf.help()));
+        }
+
+        for m in mf.get_metric() {
+            let labels = m.get_label();
+            match mf.get_field_type() {
+                MetricType::COUNTER => {
+                    let sample = format!("{}_total", family);
+                    sample_line(&mut out, &sample, labels, None, m.get_counter().value());
+                }
+                MetricType::GAUGE => {
+                    sample_line(&mut out, name, labels, None, m.get_gauge().value());
+                }
+                MetricType::HISTOGRAM => {
+                    let h = m.get_histogram();
+                    let series = series_labels(labels.iter().map(|l| (l.name(), l.value())));
+                    let bucket = format!("{}_bucket", name);
+                    let mut buckets: Vec<(f64, u64)> = h
+                        .get_bucket()
+                        .iter()
+                        .map(|b| (b.upper_bound(), b.cumulative_count()))
+                        .collect();
+                    if buckets.last().map(|(le, _)| *le) != Some(f64::INFINITY) {
+                        buckets.push((f64::INFINITY, h.get_sample_count()));
+                    }
+                    for (le, count) in buckets {
+                        sample_line(
+                            &mut out,
+                            &bucket,
+                            labels,
+                            Some(("le", &float(le))),
+                            count as f64,
+                        );
+                        let key = (name.to_string(), series.clone(), le.to_bits());
+                        if let Some(e) = exemplars.get(&key) {
+                            exemplar_suffix(&mut out, e);
+                        }
+                    }
+                    sample_line(
+                        &mut out,
+                        &format!("{}_sum", name),
+                        labels,
+                        None,
+                        h.get_sample_sum(),
+                    );
+                    sample_line(
+                        &mut out,
+                        &format!("{}_count", name),
+                        labels,
+                        None,
+                        h.get_sample_count() as f64,
+                    );
+                }
+                MetricType::SUMMARY => {
+                    let s = m.get_summary();
+                    for q in s.get_quantile() {
+                        sample_line(
+                            &mut out,
+                            name,
+                            labels,
+                            Some(("quantile", &float(q.quantile()))),
+                            q.value(),
+                        );
+                    }
+                    sample_line(
+                        &mut out,
+                        &format!("{}_sum", name),
+                        labels,
+                        None,
+                        s.sample_sum(),
+                    );
+                    sample_line(
+                        &mut out,
+                        &format!("{}_count", name),
+                        labels,
+                        None,
+                        s.sample_count() as f64,
+                    );
+                }
+                MetricType::UNTYPED => {
+                    sample_line(&mut out, name, labels, None, m.untyped.value());
+                }
+            }
+        }
+    }
+
+    out.push_str("# EOF\n");
+    out
+}
+
+fn series_labels<'a>(labels: impl Iterator<Item = (&'a str, &'a str)>) -> Vec<String> {
+    let mut labels: Vec<String> = labels.map(|(k, v)| format!("{}={}", k, v)).collect();
+    labels.sort();
+    labels
+}
+
+/// Writes one `name{labels} value` line.
+fn sample_line(
+    out: &mut String,
+    name: &str,
+    labels: &[LabelPair],
+    extra: Option<(&str, &str)>,
+    value: f64,
+) {
+    out.push_str(name);
+    let pairs = labels
+        .iter()
+        .map(|l| (l.name(), l.value()))
+        .chain(extra)
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+        .collect::<Vec<_>>();
+    if !pairs.is_empty() {
+        let _ = write!(out, "{{{}}}", pairs.join(","));
+    }
+    let _ = write!(out, " {}", float(value));
+    out.push('\n');
+}
+
+/// Appends ` # {labels} value timestamp` to the line just written.
+fn exemplar_suffix(out: &mut String, e: &Exemplar) {
+    out.pop(); // the newline
+    let labels = e
+        .labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+        .collect::<Vec<_>>()
+        .join(",");
+    let _ = writeln!(
+        out,
+        " # {{{}}} {} {:.3}",
+        labels,
+        float(e.value),
+        e.timestamp
+    );
+}
+
+fn float(v: f64) -> String {
+    if v.is_infinite() {
+        if v > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else if v.is_nan() {
+        "NaN".to_string()
+    } else {
+        v.to_string()
+    }
+}
+
+fn escape(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::{HistogramOpts, IntCounterVec, IntGauge, Opts, Registry};
+
+    use super::*;
+
+    #[test]
+    fn encodes_the_openmetrics_text_format() {
+        let registry = Registry::new();
+        let requests = IntCounterVec::new(
+            Opts::new("om_test_requests_total", "Requests \"served\"\nper path"),
+            &["path"],
+        )
+        .unwrap();
+        registry.register(Box::new(requests.clone())).unwrap();
+        requests.with_label_values(&["/a\"b\\c\nd"]).inc_by(3);
+
+        let up = IntGauge::new("om_test_up", "Up").unwrap();
+        registry.register(Box::new(up.clone())).unwrap();
+        up.set(1);
+
+        let buckets = [0.1, 1.0];
+        let latency = HistogramVec::new(
+            HistogramOpts::new("om_test_latency_seconds", "Latency").buckets(buckets.to_vec()),
+            &["device"],
+        )
+        .unwrap();
+        registry.register(Box::new(latency.clone())).unwrap();
+        observe_with_exemplar(
+            &latency,
+            "om_test_latency_seconds",
+            &buckets,
+            &[("device", "dev")],
+            0.5,
+            vec![("user", "alice".to_string())],
+        );
+        // pin the wall-clock timestamp so the output is stable
+        for (key, e) in EXEMPLARS.lock().unwrap().iter_mut() {
+            if key.0 == "om_test_latency_seconds" {
+                e.timestamp = 1700000000.0;
+            }
+        }
+        // over the length limit: observed, but not kept as an exemplar
+        observe_with_exemplar(
+            &latency,
+            "om_test_latency_seconds",
+            &buckets,
+            &[("device", "dev")],
+            0.05,
+            vec![("user", "x".repeat(MAX_EXEMPLAR_LABELS_LEN))],
+        );
+
+        let expected = r#"# TYPE om_test_latency_seconds histogram
+# HELP om_test_latency_seconds Latency
+om_test_latency_seconds_bucket{device="dev",le="0.1"} 1
+om_test_latency_seconds_bucket{device="dev",le="1"} 2 # {user="alice"} 0.5 1700000000.000
+om_test_latency_seconds_bucket{device="dev",le="+Inf"} 2
+om_test_latency_seconds_sum{device="dev"} 0.55
+om_test_latency_seconds_count{device="dev"} 2
+# TYPE om_test_requests counter
+# HELP om_test_requests Requests \"served\"\nper path
+om_test_requests_total{path="/a\"b\\c\nd"} 3
+# TYPE om_test_up gauge
+# HELP om_test_up Up
+om_test_up 1
+# EOF
+"#;
+        assert_eq!(encode(&registry.gather()), expected);
+    }
+}
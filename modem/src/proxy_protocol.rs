@@ -0,0 +1,213 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::TcpStream,
+};
+
+/// Binary (v2) header signature.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest possible v1 header, CRLF included.
+const V1_MAX_LEN: usize = 107;
+
+/// Whether `stream` starts with a PROXY header, judged by its first byte without
+/// consuming it: `P` for v1, `\r` for the v2 signature. Neither starts a TLS, SOCKS or
+/// HTTP `CONNECT` request. A stream closed before sending anything has no header.
+pub async fn has_header(stream: &TcpStream) -> io::Result<bool> {
+    let mut first = [0u8; 1];
+    let n = stream.peek(&mut first).await?;
+    Ok(n == 1 && (first[0] == b'P' || first[0] == V2_SIGNATURE[0]))
+}
+
+/// Reads a PROXY protocol v1 or v2 header from the start of `stream`.
+///
+/// Returns the original client address, or `None` for `LOCAL`/`UNKNOWN` headers
+/// (health checks from the load balancer itself), where the TCP peer should be used.
+/// Only the header is consumed, so the stream can be handed on as-is.
+pub async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, start).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    start: [u8; 12],
+) -> io::Result<Option<SocketAddr>> {
+    // byte by byte, so nothing after the CRLF is consumed
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _dst, sport, _dport] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("bad PROXY v1 source"))?;
+            let port: u16 = sport.parse().map_err(|_| invalid("bad PROXY v1 port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY v1 header")),
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    let ver_cmd = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let len = stream.read_u16().await? as usize;
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+
+    if ver_cmd >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    match ver_cmd & 0x0f {
+        0x0 => return Ok(None), // LOCAL
+        0x1 => {}               // PROXY
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    }
+
+    // TCP or UDP over IPv4 / IPv6; anything else (UNSPEC, unix sockets) has no usable address
+    match family >> 4 {
+        0x1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        0x2 if body.len() >= 36 => {
+            let octets: [u8; 16] = body[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)))
+        }
+        0x1 | 0x2 => Err(invalid("truncated PROXY v2 address block")),
+        _ => Ok(None),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    use super::*;
+
+    fn v2(ver_cmd: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.extend_from_slice(&[ver_cmd, family]);
+        buf.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        buf.extend_from_slice(body);
+        buf
+    }
+
+    #[tokio::test]
+    async fn reads_v1_header_and_leaves_the_rest() {
+        let mut reader: &[u8] = b"PROXY TCP4 198.51.100.1 10.0.0.1 40000 1080\r\n\x05\x01\x00";
+        let client = read_header(&mut reader).await.unwrap();
+        assert_eq!(client, Some("198.51.100.1:40000".parse().unwrap()));
+        assert_eq!(reader, b"\x05\x01\x00");
+
+        let mut reader: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 40000 1080\r\n";
+        let client = read_header(&mut reader).await.unwrap();
+        assert_eq!(client, Some("[2001:db8::1]:40000".parse().unwrap()));
+
+        let mut reader: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut reader).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn reads_v2_header() {
+        let mut body = vec![198, 51, 100, 1, 10, 0, 0, 1];
+        body.extend_from_slice(&40000u16.to_be_bytes());
+        body.extend_from_slice(&1080u16.to_be_bytes());
+        let mut buf = v2(0x21, 0x11, &body);
+        buf.push(0x05);
+        let mut reader = buf.as_slice();
+        let client = read_header(&mut reader).await.unwrap();
+        assert_eq!(client, Some("198.51.100.1:40000".parse().unwrap()));
+        assert_eq!(reader, [0x05]);
+
+        // LOCAL: a health check from the balancer itself
+        let buf = v2(0x20, 0x00, &[]);
+        assert_eq!(read_header(&mut buf.as_slice()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_headers() {
+        let mut reader: &[u8] = b"PROXY TCP4 198.51.100.1";
+        let err = read_header(&mut reader).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let buf = v2(0x21, 0x11, &[198, 51, 100, 1]);
+        let err = read_header(&mut &buf[..buf.len() - 1]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // complete, but too short for an IPv4 address block
+        let err = read_header(&mut buf.as_slice()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_and_malformed_headers() {
+        let long = format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX_LEN));
+        let err = read_header(&mut long.as_bytes()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        for header in [
+            &b"PROXY TCP4 not-an-ip 10.0.0.1 40000 1080\r\n"[..],
+            b"PROXY TCP4 198.51.100.1 10.0.0.1 99999 1080\r\n",
+            b"PROXY TCP4 198.51.100.1\r\n",
+            b"\x05\x01\x00\x03\x0bexample.com",
+        ] {
+            let err = read_header(&mut &header[..]).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        let buf = v2(0x11, 0x11, &[0; 12]);
+        assert!(read_header(&mut buf.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn detects_header_without_consuming_it() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        for (first, expected) in [
+            (&b"PROXY UNKNOWN\r\n"[..], true),
+            (&V2_SIGNATURE[..], true),
+            (b"\x05\x01\x00", false),
+            (b"\x16\x03\x01", false),
+            (b"CONNECT example.com:443 HTTP/1.1\r\n", false),
+            (b"", false),
+        ] {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            client.write_all(first).await.unwrap();
+            client.shutdown().await.unwrap();
+
+            assert_eq!(has_header(&server).await.unwrap(), expected, "{:?}", first);
+            let mut rest = Vec::new();
+            server.read_to_end(&mut rest).await.unwrap();
+            assert_eq!(rest, first);
+        }
+    }
+}
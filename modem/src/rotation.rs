@@ -0,0 +1,790 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use derive_builder::Builder;
+use lazy_static::lazy_static;
+use openssl::rand::rand_bytes;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::{Deserialize, Serialize};
+use slog::{debug, error, info, warn, Logger};
+use time::OffsetDateTime;
+use tokio::{
+    sync::Mutex,
+    time::{interval, sleep, Instant, MissedTickBehavior},
+};
+use uuid::Uuid;
+
+use crate::{
+    events::{EventBus, EventKind},
+    health::{probe_exit_ip, ProbeTarget},
+    identity::{spawn_per_device, Interfaces},
+    ip_history::IpHistory,
+    live::Live,
+    modem::{Modem, Modems, SharedModem},
+    tcp::OsFingerprint,
+    usage::UsageStore,
+};
+
+/// Rotations kept per device for the API.
+const HISTORY_LEN: usize = 50;
+
+/// Longest `unique_ip_minutes` accepted: one year.
+const MAX_UNIQUE_IP_MINUTES: u64 = 525_600;
+
+/// How often a draining device is checked for open tunnels.
+const DRAIN_POLL: Duration = Duration::from_millis(500);
+
+/// Probes for the new exit IP after a rotation, while the link comes back up.
+const EXIT_PROBE_TRIES: u32 = 12;
+const EXIT_PROBE_DELAY: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    static ref DEVICE_ROTATIONS: IntCounterVec = register_int_counter_vec!(
+        "device_rotations_total",
+        "IP rotations by trigger and outcome",
+        &["device", "reason", "result"]
+    )
+    .unwrap();
+}
+
+/// How a device gets a new IP.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RotationMethod {
+    /// Switch mobile data off and on; a few seconds without a link
+    #[default]
+    Reconnect,
+    /// Restart the modem; slower, but also clears a wedged stick
+    Reboot,
+}
+
+/// When and how a device rotates its IP. Every trigger that is set counts,
+/// whichever is reached first.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RotationPolicy {
+    #[serde(default)]
+    pub every_minutes: Option<u64>,
+    #[serde(default)]
+    pub after_connections: Option<u64>,
+    /// Bytes sent plus received through the device
+    #[serde(default)]
+    pub after_bytes: Option<u64>,
+    /// Up to this many seconds of random delay once a trigger fires, so devices
+    /// sharing a policy do not drop at once
+    #[serde(default)]
+    pub jitter_secs: u64,
+    #[serde(default)]
+    pub method: RotationMethod,
+    /// Seconds open tunnels get to finish before the device rotates anyway; it
+    /// takes no new connections meanwhile
+    #[serde(default = "default_drain_secs")]
+    pub drain_secs: u64,
+    /// Rotate again while the new exit IP was already used within this many minutes;
+    /// needs an exit IP probe
+    #[serde(default)]
+    pub unique_ip_minutes: Option<u64>,
+    /// Rotations per trigger before settling for a recently used IP
+    #[serde(default = "default_unique_ip_attempts")]
+    pub unique_ip_attempts: u32,
+}
+
+fn default_drain_secs() -> u64 {
+    300
+}
+
+fn default_unique_ip_attempts() -> u32 {
+    3
+}
+
+impl RotationPolicy {
+    fn validate(&self, device: &str) -> Result<()> {
+        let triggers = [self.every_minutes, self.after_connections, self.after_bytes];
+        if triggers.iter().all(Option::is_none) {
+            return Err(anyhow::anyhow!(
+                "rotation policy for `{}` needs every_minutes, after_connections or after_bytes",
+                device
+            ));
+        }
+        if triggers.contains(&Some(0)) {
+            return Err(anyhow::anyhow!(
+                "rotation policy for `{}`: triggers must be greater than 0",
+                device
+            ));
+        }
+        if self.unique_ip_minutes == Some(0) || self.unique_ip_attempts == 0 {
+            return Err(anyhow::anyhow!(
+                "rotation policy for `{}`: unique_ip_minutes and unique_ip_attempts must be greater than 0",
+                device
+            ));
+        }
+        if self.unique_ip_minutes > Some(MAX_UNIQUE_IP_MINUTES) {
+            return Err(anyhow::anyhow!(
+                "rotation policy for `{}`: unique_ip_minutes must be at most {} (one year)",
+                device,
+                MAX_UNIQUE_IP_MINUTES
+            ));
+        }
+        Ok(())
+    }
+
+    /// What a manual rotation of a device without a policy uses.
+    fn manual() -> Self {
+        RotationPolicy {
+            every_minutes: None,
+            after_connections: None,
+            after_bytes: None,
+            jitter_secs: 0,
+            method: RotationMethod::default(),
+            drain_secs: default_drain_secs(),
+            unique_ip_minutes: None,
+            unique_ip_attempts: default_unique_ip_attempts(),
+        }
+    }
+
+    /// The first trigger reached, if any.
+    fn due(&self, elapsed: Duration, connections: u64, bytes: u64) -> Option<RotationReason> {
+        let reached = |limit: Option<u64>, value: u64| limit.is_some_and(|l| value >= l);
+        if reached(self.every_minutes, elapsed.as_secs() / 60) {
+            Some(RotationReason::Schedule)
+        } else if reached(self.after_connections, connections) {
+            Some(RotationReason::Connections)
+        } else if reached(self.after_bytes, bytes) {
+            Some(RotationReason::Bytes)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Pool {
+    devices: Vec<String>,
+    #[serde(flatten)]
+    policy: RotationPolicy,
+}
+
+/// Policies as written in the rotation file or the `[rotation]` config section.
+#[derive(Default, Deserialize)]
+pub struct RotationConfig {
+    #[serde(default)]
+    pools: HashMap<String, Pool>,
+    #[serde(default)]
+    devices: HashMap<String, RotationPolicy>,
+}
+
+/// Rotation policies keyed by device id. Devices without one never rotate on their own.
+#[derive(Default)]
+pub struct RotationPolicies(HashMap<String, RotationPolicy>);
+
+impl RotationPolicies {
+    /// Load policies from a JSON file of the form
+    /// `{"pools": {"<name>": {"devices": ["<id>", ...], ...policy}}, "devices": {"<id>": policy}}`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data = std::fs::read(path)
+            .with_context(|| format!("read rotation file {}", path.display()))?;
+        let config: RotationConfig = serde_json::from_slice(&data)
+            .with_context(|| format!("parse rotation file {}", path.display()))?;
+        RotationPolicies::new(config)
+    }
+
+    /// Expand pools into per-device policies. A device's own entry overrides its pool.
+    /// Devices are named by id; anything else, e.g. an interface name, is rejected.
+    pub fn new(config: RotationConfig) -> Result<Self> {
+        let mut policies = HashMap::new();
+        for (name, pool) in config.pools {
+            for device in pool.devices {
+                if Uuid::parse_str(&device).is_err() {
+                    return Err(anyhow::anyhow!(
+                        "rotation pool `{}`: `{}` is not a device id",
+                        name,
+                        device
+                    ));
+                }
+                if policies
+                    .insert(device.clone(), pool.policy.clone())
+                    .is_some()
+                {
+                    return Err(anyhow::anyhow!(
+                        "device `{}` is in rotation pool `{}` and another one",
+                        device,
+                        name
+                    ));
+                }
+            }
+        }
+        policies.extend(config.devices);
+        for (device, policy) in &policies {
+            if Uuid::parse_str(device).is_err() {
+                return Err(anyhow::anyhow!(
+                    "rotation policy for `{}`: not a device id",
+                    device
+                ));
+            }
+            policy.validate(device)?;
+        }
+        Ok(RotationPolicies(policies))
+    }
+
+    pub fn get(&self, device: &str) -> Option<&RotationPolicy> {
+        self.0.get(device)
+    }
+
+    /// Devices with a policy.
+    pub fn devices(&self) -> impl Iterator<Item = &str> {
+        self.0.keys().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// What set off a rotation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RotationReason {
+    Schedule,
+    Connections,
+    Bytes,
+    /// Requested through the API
+    Manual,
+}
+
+impl RotationReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RotationReason::Schedule => "schedule",
+            RotationReason::Connections => "connections",
+            RotationReason::Bytes => "bytes",
+            RotationReason::Manual => "manual",
+        }
+    }
+}
+
+/// One rotation attempt.
+#[derive(Clone, Debug, Serialize)]
+pub struct RotationRecord {
+    #[serde(with = "time::serde::rfc3339")]
+    pub at: OffsetDateTime,
+    pub reason: RotationReason,
+    pub method: RotationMethod,
+    /// Tunnels still open when the drain period ran out
+    pub cut_tunnels: u64,
+    /// Rotations it took to get an IP not used within `unique_ip_minutes`
+    pub attempts: u32,
+    /// Public address after the rotation, if probed
+    pub exit_ip: Option<IpAddr>,
+    /// The exit IP was used within `unique_ip_minutes` and the attempts ran out
+    pub repeated_ip: bool,
+    pub error: Option<String>,
+}
+
+/// Rotation state of one device.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DeviceRotation {
+    /// `None` when the device has no rotation policy
+    pub policy: Option<RotationPolicy>,
+    pub paused: bool,
+    /// Waiting for open tunnels before rotating; new connections are refused
+    pub draining: bool,
+    /// A manual rotation waits for the next check
+    pub requested: bool,
+    pub active_tunnels: u64,
+    /// Connections since the last rotation
+    pub connections: u64,
+    /// Bytes since the last rotation, as of the last check
+    pub bytes: u64,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_rotation: Option<OffsetDateTime>,
+    /// Successful rotations since startup
+    pub rotations: u32,
+    /// Latest attempts first
+    pub history: VecDeque<RotationRecord>,
+    /// A rotator loop drives the device, so it can be rotated on request
+    #[serde(skip)]
+    rotatable: bool,
+}
+
+/// Connection counts and rotation state of every device, shared with the proxy and the API.
+#[derive(Default)]
+pub struct RotationRegistry {
+    devices: RwLock<HashMap<String, DeviceRotation>>,
+}
+
+impl RotationRegistry {
+    /// Count a new connection through `device`, open until the lease is dropped.
+    /// `None` while the device drains ahead of a rotation.
+    pub fn lease(self: &Arc<Self>, device: &str) -> Option<Lease> {
+        self.update(device, |r| {
+            if r.draining {
+                return None;
+            }
+            r.active_tunnels += 1;
+            r.connections += 1;
+            Some(Lease {
+                registry: self.clone(),
+                device: device.to_string(),
+            })
+        })
+    }
+
+    pub fn get(&self, device: &str) -> Option<DeviceRotation> {
+        self.devices.read().unwrap().get(device).cloned()
+    }
+
+    /// Whether `device` is draining or rotating, so its link is expected to drop.
+    pub fn is_rotating(&self, device: &str) -> bool {
+        self.devices
+            .read()
+            .unwrap()
+            .get(device)
+            .is_some_and(|r| r.draining)
+    }
+
+    /// Pause or resume scheduled rotations of `device`. `None` if it has no policy.
+    pub fn set_paused(&self, device: &str, paused: bool) -> Option<DeviceRotation> {
+        let mut devices = self.devices.write().unwrap();
+        let rotation = devices.get_mut(device).filter(|r| r.policy.is_some())?;
+        rotation.paused = paused;
+        Some(rotation.clone())
+    }
+
+    /// Rotate `device` at the next check, even while paused and without a policy.
+    /// `None` if no modem can rotate it.
+    pub fn request(&self, device: &str) -> Option<DeviceRotation> {
+        let mut devices = self.devices.write().unwrap();
+        let rotation = devices.get_mut(device).filter(|r| r.rotatable)?;
+        rotation.requested = true;
+        Some(rotation.clone())
+    }
+
+    pub(crate) fn update<R>(&self, device: &str, f: impl FnOnce(&mut DeviceRotation) -> R) -> R {
+        let mut devices = self.devices.write().unwrap();
+        f(devices.entry(device.to_string()).or_default())
+    }
+}
+
+/// An open connection through a device; a rotation waits for leases to be dropped.
+pub struct Lease {
+    registry: Arc<RotationRegistry>,
+    device: String,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.registry.update(&self.device, |r| {
+            r.active_tunnels = r.active_tunnels.saturating_sub(1)
+        });
+    }
+}
+
+/// Rotates every device with a policy through its modem, draining it first.
+#[derive(Builder)]
+#[builder(pattern = "owned")]
+pub struct Rotator {
+    /// Looked up on every check, so reloaded policies apply without a restart
+    policies: Arc<Live<RotationPolicies>>,
+    /// Policies for devices without a modem are skipped
+    modems: Arc<Modems>,
+    registry: Arc<RotationRegistry>,
+    /// Source of the per-device byte counts
+    #[builder(default)]
+    usage: Arc<UsageStore>,
+    logger: Logger,
+    /// How often the triggers are evaluated
+    #[builder(default = "Duration::from_secs(5)")]
+    check_interval: Duration,
+    /// Echo endpoint asked for the new exit IP after each rotation; without it
+    /// rotations are not recorded in the IP history and `unique_ip_minutes` is ignored
+    #[builder(default)]
+    probe: Option<ProbeTarget>,
+    /// Device id to interface name, for the exit IP probe; devices that appear later
+    /// are scheduled too
+    #[builder(default)]
+    interfaces: Arc<Interfaces>,
+    #[builder(default = "OsFingerprint::Windows")]
+    fingerprint: OsFingerprint,
+    /// Max time for one exit IP probe, DNS included
+    #[builder(default = "Duration::from_secs(10)")]
+    probe_timeout: Duration,
+    /// Exit IPs seen per device
+    #[builder(default)]
+    history: Arc<IpHistory>,
+    /// Receives rotation starts and results
+    #[builder(default)]
+    events: Arc<EventBus>,
+}
+
+impl Rotator {
+    /// Start one scheduling loop per connected modem; it idles while the device has
+    /// no policy.
+    pub fn spawn(self) {
+        let rotator = Arc::new(self);
+        for device in rotator.policies.get().devices() {
+            if rotator.modems.get(device).is_none() {
+                warn!(rotator.logger, "rotation policy for unknown device"; "device" => device);
+            }
+        }
+        spawn_per_device(
+            rotator.interfaces.clone(),
+            rotator.check_interval,
+            move |device| {
+                let modem = rotator.modems.get(device)?;
+                let rotator = rotator.clone();
+                let device = device.to_string();
+                Some(async move { rotator.schedule(device, modem).await })
+            },
+        );
+    }
+
+    async fn schedule(&self, device: String, modem: SharedModem) {
+        let logger = self.logger.new(slog::o!("device" => device.clone()));
+
+        let mut ticker = interval(self.check_interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut since = Instant::now();
+        let mut bytes_at = self.device_bytes(&device);
+        let mut pending: Option<(RotationReason, Instant)> = None;
+        let mut current: Option<RotationPolicy> = None;
+        let mut first = true;
+        self.registry.update(&device, |r| r.rotatable = true);
+
+        loop {
+            ticker.tick().await;
+            let policy = self.policies.get().get(&device).cloned();
+            if first || policy != current {
+                if let Some(policy) = &policy {
+                    if policy.unique_ip_minutes.is_some() && self.probe.is_none() {
+                        warn!(logger, "unique_ip_minutes needs an exit IP probe, ignored");
+                    }
+                }
+                if !first {
+                    info!(logger, "rotation policy changed"; "active" => policy.is_some());
+                }
+                // triggers keep counting from the last rotation; only a due one is re-evaluated
+                pending = None;
+                self.registry.update(&device, |r| r.policy = policy.clone());
+                current = policy;
+                first = false;
+            }
+
+            if self.registry.update(&device, |r| std::mem::take(&mut r.requested)) {
+                let policy = current.clone().unwrap_or_else(RotationPolicy::manual);
+                pending = None;
+                self.rotate(&device, &policy, RotationReason::Manual, &modem, &logger)
+                    .await;
+                since = Instant::now();
+                bytes_at = self.device_bytes(&device);
+                continue;
+            }
+            let Some(policy) = &current else {
+                continue;
+            };
+
+            let bytes = self.device_bytes(&device).saturating_sub(bytes_at);
+            let (paused, connections) = self.registry.update(&device, |r| {
+                r.bytes = bytes;
+                (r.paused, r.connections)
+            });
+            if paused {
+                pending = None;
+                continue;
+            }
+
+            if pending.is_none() {
+                pending = policy
+                    .due(since.elapsed(), connections, bytes)
+                    .map(|reason| {
+                        let delay = jitter(policy.jitter_secs);
+                        debug!(logger, "rotation due";
+                            "reason" => reason.as_str(),
+                            "delay_secs" => delay.as_secs(),
+                        );
+                        (reason, Instant::now() + delay)
+                    });
+            }
+            let Some((reason, at)) = pending else {
+                continue;
+            };
+            if Instant::now() < at {
+                continue;
+            }
+
+            pending = None;
+            self.rotate(&device, policy, reason, &modem, &logger).await;
+            // failed attempts restart the count too, so a broken modem is not hammered
+            since = Instant::now();
+            bytes_at = self.device_bytes(&device);
+        }
+    }
+
+    async fn rotate(
+        &self,
+        device: &str,
+        policy: &RotationPolicy,
+        reason: RotationReason,
+        modem: &Mutex<dyn Modem + Send + Sync>,
+        logger: &Logger,
+    ) {
+        let open = self.registry.update(device, |r| {
+            r.draining = true;
+            r.active_tunnels
+        });
+        info!(logger, "draining device for rotation";
+            "reason" => reason.as_str(),
+            "tunnels" => open,
+        );
+        self.events.publish(
+            Some(device),
+            EventKind::RotationStarted {
+                reason,
+                method: policy.method,
+            },
+        );
+
+        let deadline = Instant::now() + Duration::from_secs(policy.drain_secs);
+        let cut_tunnels = loop {
+            let open = self.registry.update(device, |r| r.active_tunnels);
+            if open == 0 || Instant::now() >= deadline {
+                break open;
+            }
+            sleep(DRAIN_POLL).await;
+        };
+
+        let mut attempts = 0;
+        let (rotated, exit_ip, repeated_ip) = loop {
+            attempts += 1;
+            let started = OffsetDateTime::now_utc();
+            let mut locked = modem.lock().await;
+            let rotated = match policy.method {
+                RotationMethod::Reconnect => locked.reconnect().await.map_err(|e| e.to_string()),
+                RotationMethod::Reboot => locked.reboot().await.map_err(|e| e.to_string()),
+            };
+            drop(locked);
+            if rotated.is_err() {
+                break (rotated, None, false);
+            }
+
+            let Some(ip) = self.exit_ip(device, logger).await else {
+                break (rotated, None, false);
+            };
+            // the health checker may have recorded the new IP already, so only
+            // sightings from before this attempt count
+            let repeated = policy.unique_ip_minutes.is_some_and(|minutes| {
+                let since = minutes
+                    .checked_mul(60)
+                    .and_then(|secs| i64::try_from(secs).ok())
+                    .and_then(|secs| started.checked_sub(time::Duration::seconds(secs)))
+                    .unwrap_or(OffsetDateTime::UNIX_EPOCH);
+                self.history.seen_between(device, ip, since, started)
+            });
+            self.history.record(device, ip);
+            if !repeated {
+                break (rotated, Some(ip), false);
+            }
+            if attempts >= policy.unique_ip_attempts {
+                warn!(logger, "no unused exit IP, keeping a recent one";
+                    "exit_ip" => %ip,
+                    "attempts" => attempts,
+                );
+                break (rotated, Some(ip), true);
+            }
+            info!(logger, "exit IP used recently, rotating again";
+                "exit_ip" => %ip,
+                "attempts" => attempts,
+            );
+        };
+
+        let at = OffsetDateTime::now_utc();
+        let result = if rotated.is_ok() { "ok" } else { "error" };
+        DEVICE_ROTATIONS
+            .with_label_values(&[device, reason.as_str(), result])
+            .inc();
+        match &rotated {
+            Ok(()) => info!(logger, "device rotated";
+                "reason" => reason.as_str(),
+                "cut_tunnels" => cut_tunnels,
+                "exit_ip" => exit_ip.map(|ip| ip.to_string()),
+                "attempts" => attempts,
+            ),
+            Err(e) => error!(logger, "rotate device"; "reason" => reason.as_str(), "error" => e),
+        }
+        self.events.publish(
+            Some(device),
+            EventKind::RotationFinished {
+                reason,
+                exit_ip,
+                attempts,
+                error: rotated.as_ref().err().cloned(),
+            },
+        );
+
+        self.registry.update(device, |r| {
+            r.draining = false;
+            r.connections = 0;
+            r.bytes = 0;
+            if rotated.is_ok() {
+                r.rotations += 1;
+                r.last_rotation = Some(at);
+            }
+            r.history.push_front(RotationRecord {
+                at,
+                reason,
+                method: policy.method,
+                cut_tunnels,
+                attempts,
+                exit_ip,
+                repeated_ip,
+                error: rotated.err(),
+            });
+            r.history.truncate(HISTORY_LEN);
+        });
+    }
+
+    /// The exit IP of `device` once its link is back, `None` without a probe or if it never answers.
+    async fn exit_ip(&self, device: &str, logger: &Logger) -> Option<IpAddr> {
+        let target = self.probe.as_ref()?;
+        for _ in 0..EXIT_PROBE_TRIES {
+            sleep(EXIT_PROBE_DELAY).await;
+            // looked up each try: a rebooted stick is back once the watch loop saw it
+            let Some(ifname) = self.interfaces.get().get(device).cloned() else {
+                continue;
+            };
+            match probe_exit_ip(target, &ifname, self.fingerprint, self.probe_timeout).await {
+                Ok(ip) => return Some(ip),
+                Err(e) => debug!(logger, "exit IP probe after rotation"; "error" => %e),
+            }
+        }
+        warn!(logger, "no exit IP after rotation");
+        None
+    }
+
+    fn device_bytes(&self, device: &str) -> u64 {
+        self.usage
+            .device(device)
+            .map(|u| u.total_sent + u.total_recv)
+            .unwrap_or_default()
+    }
+}
+
+/// A random delay of up to `secs` seconds.
+fn jitter(secs: u64) -> Duration {
+    let mut buf = [0u8; 8];
+    rand_bytes(&mut buf).expect("no randomness available");
+    Duration::from_secs(u64::from_le_bytes(buf) % secs.saturating_add(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEV_A: &str = "6ba7b810-9dad-51d1-80b4-00c04fd430c8";
+    const DEV_B: &str = "9e1a3c2f-4b5d-5e6f-8a7b-0c1d2e3f4a5b";
+
+    fn policies(json: &str) -> Result<RotationPolicies> {
+        RotationPolicies::new(serde_json::from_str(json).unwrap())
+    }
+
+    fn policy(json: &str) -> RotationPolicy {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn due_at_the_first_trigger_reached() {
+        let policy =
+            policy(r#"{"every_minutes": 30, "after_connections": 100, "after_bytes": 1000}"#);
+        let min = |m: u64| Duration::from_secs(m * 60);
+
+        assert_eq!(policy.due(min(29), 99, 999), None);
+        assert_eq!(policy.due(min(30), 0, 0), Some(RotationReason::Schedule));
+        assert_eq!(
+            policy.due(min(29), 100, 0),
+            Some(RotationReason::Connections)
+        );
+        assert_eq!(policy.due(min(29), 0, 1000), Some(RotationReason::Bytes));
+        // the schedule is reported first when several fire at once
+        assert_eq!(
+            policy.due(min(31), 200, 2000),
+            Some(RotationReason::Schedule)
+        );
+
+        let bytes_only = RotationPolicy {
+            after_bytes: Some(10),
+            ..RotationPolicy::manual()
+        };
+        assert_eq!(bytes_only.due(min(1_000_000), u64::MAX, 9), None);
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        assert_eq!(jitter(0), Duration::ZERO);
+        let delays: Vec<Duration> = (0..1000).map(|_| jitter(3)).collect();
+        assert!(delays.iter().all(|d| *d <= Duration::from_secs(3)));
+        // every delay from 0 to 3 seconds comes up
+        for secs in 0..=3 {
+            assert!(delays.contains(&Duration::from_secs(secs)), "{}", secs);
+        }
+        // the widest window does not overflow
+        jitter(u64::MAX);
+    }
+
+    #[test]
+    fn expands_pools_and_device_overrides() {
+        let policies = policies(&format!(
+            r#"{{
+                "pools": {{"residential": {{"devices": ["{}", "{}"], "every_minutes": 30}}}},
+                "devices": {{"{}": {{"after_connections": 5}}}}
+            }}"#,
+            DEV_A, DEV_B, DEV_B
+        ))
+        .unwrap();
+        assert_eq!(policies.len(), 2);
+        assert_eq!(policies.get(DEV_A).unwrap().every_minutes, Some(30));
+        let own = policies.get(DEV_B).unwrap();
+        assert_eq!((own.every_minutes, own.after_connections), (None, Some(5)));
+    }
+
+    #[test]
+    fn rejects_pools_naming_unknown_devices() {
+        let err = policies(&format!(
+            r#"{{"pools": {{"residential": {{"devices": ["{}", "enx0c5b8f279a64"], "every_minutes": 30}}}}}}"#,
+            DEV_A
+        ))
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "rotation pool `residential`: `enx0c5b8f279a64` is not a device id"
+        );
+
+        let err = policies(r#"{"devices": {"modem-1": {"every_minutes": 30}}}"#)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "rotation policy for `modem-1`: not a device id"
+        );
+
+        let err = policies(&format!(
+            r#"{{"pools": {{
+                "a": {{"devices": ["{}"], "every_minutes": 30}},
+                "b": {{"devices": ["{}"], "every_minutes": 60}}
+            }}}}"#,
+            DEV_A, DEV_A
+        ))
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("and another one"), "{}", err);
+    }
+}
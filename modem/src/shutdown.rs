@@ -0,0 +1,28 @@
+use anyhow::{Context, Result};
+use slog::{info, warn, Logger};
+use tokio::signal::unix::{signal, SignalKind};
+pub use tokio_util::sync::CancellationToken;
+
+/// Cancel `token` on the first SIGTERM or SIGINT. A second signal exits immediately,
+/// for when draining takes longer than the operator is willing to wait.
+pub fn cancel_on_signal(token: CancellationToken, logger: Logger) -> Result<()> {
+    let mut term = signal(SignalKind::terminate()).context("install SIGTERM handler")?;
+    let mut int = signal(SignalKind::interrupt()).context("install SIGINT handler")?;
+
+    tokio::spawn(async move {
+        let name = tokio::select! {
+            _ = term.recv() => "SIGTERM",
+            _ = int.recv() => "SIGINT",
+        };
+        info!(logger, "shutdown requested"; "signal" => name);
+        token.cancel();
+
+        let name = tokio::select! {
+            _ = term.recv() => "SIGTERM",
+            _ = int.recv() => "SIGINT",
+        };
+        warn!(logger, "second signal, exiting without draining"; "signal" => name);
+        std::process::exit(1);
+    });
+    Ok(())
+}
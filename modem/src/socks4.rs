@@ -0,0 +1,188 @@
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use socks5_proto::{Address, Command};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::socks5::{Result, Socks5Error};
+
+/// First byte of a SOCKS4/4a request.
+pub const VERSION: u8 = 0x04;
+
+/// Longest USERID or hostname we accept, terminator included.
+const MAX_FIELD: u64 = 256;
+
+const REPLY_GRANTED: u8 = 90;
+const REPLY_REJECTED: u8 = 91;
+
+/// A SOCKS4 or SOCKS4a request.
+pub struct Socks4Request {
+    pub command: Command,
+    pub target: Address,
+    /// `user[-fingerprint-X]:password`, or just the username for IP-authenticated users.
+    pub userid: String,
+}
+
+impl Socks4Request {
+    /// Username and password from the USERID field, if it carries a password.
+    pub fn credentials(&self) -> Option<(String, String)> {
+        self.userid
+            .split_once(':')
+            .map(|(user, password)| (user.to_string(), password.to_string()))
+    }
+}
+
+pub async fn read_request<R: AsyncBufRead + Unpin>(r: &mut R) -> Result<Socks4Request> {
+    let invalid = |msg: &str| Socks5Error::Socks4Request(msg.to_string());
+    let io = |e: std::io::Error| Socks5Error::Handshake(e.into());
+
+    if r.read_u8().await.map_err(io)? != VERSION {
+        return Err(invalid("not a SOCKS4 request"));
+    }
+    let command = match r.read_u8().await.map_err(io)? {
+        0x01 => Command::Connect,
+        0x02 => Command::Bind,
+        _ => return Err(invalid("unknown command")),
+    };
+    let port = r.read_u16().await.map_err(io)?;
+    let ip = Ipv4Addr::from(r.read_u32().await.map_err(io)?);
+    let userid = String::from_utf8(read_nul_terminated(r).await?)?;
+
+    // SOCKS4a: an address of 0.0.0.x (x != 0) means a hostname follows the USERID
+    let octets = ip.octets();
+    let target = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+        let host = read_nul_terminated(r).await?;
+        if host.is_empty() {
+            return Err(invalid("empty SOCKS4a hostname"));
+        }
+        Address::DomainAddress(host, port)
+    } else {
+        Address::SocketAddress(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+    };
+
+    Ok(Socks4Request {
+        command,
+        target,
+        userid,
+    })
+}
+
+async fn read_nul_terminated<R: AsyncBufRead + Unpin>(r: &mut R) -> Result<Vec<u8>> {
+    let mut field = Vec::new();
+    r.take(MAX_FIELD)
+        .read_until(0, &mut field)
+        .await
+        .map_err(|e| Socks5Error::Handshake(e.into()))?;
+    if field.pop() != Some(0) {
+        return Err(Socks5Error::Socks4Request(
+            "field too long or not terminated".to_string(),
+        ));
+    }
+    Ok(field)
+}
+
+/// SOCKS4 only knows "granted" and "rejected or failed".
+pub async fn write_reply<W: AsyncWrite + Unpin>(w: &mut W, granted: bool) -> Result<()> {
+    let code = if granted {
+        REPLY_GRANTED
+    } else {
+        REPLY_REJECTED
+    };
+    // DSTPORT and DSTIP are ignored by clients for CONNECT
+    w.write_all(&[0, code, 0, 0, 0, 0, 0, 0])
+        .await
+        .map_err(Socks5Error::ResponseWrite)?;
+    w.flush().await.map_err(Socks5Error::ResponseWrite)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(ip: [u8; 4], port: u16, userid: &[u8], host: Option<&[u8]>) -> Vec<u8> {
+        let mut buf = vec![VERSION, 0x01];
+        buf.extend_from_slice(&port.to_be_bytes());
+        buf.extend_from_slice(&ip);
+        buf.extend_from_slice(userid);
+        buf.push(0);
+        if let Some(host) = host {
+            buf.extend_from_slice(host);
+            buf.push(0);
+        }
+        buf
+    }
+
+    #[tokio::test]
+    async fn reads_socks4_request() {
+        let buf = request([203, 0, 113, 7], 443, b"alice:secret", None);
+        let req = read_request(&mut buf.as_slice()).await.unwrap();
+
+        assert!(matches!(req.command, Command::Connect));
+        assert_eq!(
+            req.target,
+            Address::SocketAddress("203.0.113.7:443".parse().unwrap())
+        );
+        assert_eq!(
+            req.credentials(),
+            Some(("alice".to_string(), "secret".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_socks4a_hostname_and_leaves_the_rest() {
+        let mut buf = request([0, 0, 0, 1], 80, b"alice", Some(b"example.com"));
+        buf.extend_from_slice(b"GET /");
+        let mut reader = buf.as_slice();
+        let req = read_request(&mut reader).await.unwrap();
+
+        assert_eq!(
+            req.target,
+            Address::DomainAddress(b"example.com".to_vec(), 80)
+        );
+        assert_eq!(req.userid, "alice");
+        assert_eq!(req.credentials(), None);
+        assert_eq!(reader, b"GET /");
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_request() {
+        let buf = request([203, 0, 113, 7], 443, b"alice", None);
+        for len in [1, 5, buf.len() - 1] {
+            let err = read_request(&mut &buf[..len]).await.err().unwrap();
+            assert!(
+                matches!(
+                    err,
+                    Socks5Error::Handshake(_) | Socks5Error::Socks4Request(_)
+                ),
+                "{} bytes: {}",
+                len,
+                err
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_fields() {
+        let long = vec![b'a'; MAX_FIELD as usize];
+        let buf = request([203, 0, 113, 7], 443, &long, None);
+        let err = read_request(&mut buf.as_slice()).await.err().unwrap();
+        assert!(matches!(err, Socks5Error::Socks4Request(_)), "{}", err);
+
+        let buf = request([0, 0, 0, 1], 443, b"alice", Some(&long));
+        let err = read_request(&mut buf.as_slice()).await.err().unwrap();
+        assert!(matches!(err, Socks5Error::Socks4Request(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn rejects_other_versions_and_commands() {
+        let mut buf = request([203, 0, 113, 7], 443, b"alice", None);
+        buf[0] = 0x05;
+        assert!(read_request(&mut buf.as_slice()).await.is_err());
+
+        let mut buf = request([203, 0, 113, 7], 443, b"alice", None);
+        buf[1] = 0x03;
+        assert!(read_request(&mut buf.as_slice()).await.is_err());
+
+        let buf = request([0, 0, 0, 1], 443, b"alice", Some(b""));
+        assert!(read_request(&mut buf.as_slice()).await.is_err());
+    }
+}
@@ -1,7 +1,26 @@
+use crate::acl::{AclPolicy, Denied, SourceAllowlist};
+use crate::health::HealthRegistry;
+use crate::http_proxy::{read_connect_request, write_status};
+use crate::identity::{DeviceAliases, Interfaces};
+use crate::live::Live;
+use crate::openmetrics::observe_with_exemplar;
+use crate::proxy_protocol;
+use crate::rotation::{Lease, RotationRegistry};
+use crate::socks4::{self, Socks4Request};
 use crate::tcp::{tcp_connect_with_fingerprint, OsFingerprint};
+use crate::tls::MaybeTlsStream;
+use crate::upstream::{UpstreamError, Upstreams};
+use crate::usage::{Meter, Metered, QuotaExceeded, UsageStore};
 use crate::username::parse_username;
+use crate::users::UserStore;
+use axum::http::StatusCode;
 use derive_builder::Builder;
-use slog::{error, Logger};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, Histogram,
+    HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+};
+use slog::{debug, error, info, warn, Logger};
 use socks5_proto::{
     handshake::{
         password::{Request as PasswordRequest, Response as PasswordResponse},
@@ -11,17 +30,94 @@ use socks5_proto::{
 };
 use std::{
     collections::HashMap,
+    future::Future,
     io,
     net::{AddrParseError, SocketAddr},
+    pin::Pin,
     result,
     string::FromUtf8Error,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
 };
-use std::sync::Arc;
 use thiserror::Error;
 use tokio::{
-    io::copy_bidirectional,
-    net::{TcpListener, TcpStream},
+    io::{copy_bidirectional, AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader, ReadBuf},
+    net::{lookup_host, TcpListener, TcpStream},
+    time::{sleep, timeout, timeout_at},
 };
+use tokio_rustls::TlsAcceptor;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+/// First byte of a SOCKS5 method selection; anything but SOCKS4/5 is treated as HTTP.
+const SOCKS5_VERSION: u8 = 0x05;
+
+/// A client connection after optional TLS termination. The buffer lets us peek
+/// at the first byte to tell SOCKS5, SOCKS4 and HTTP CONNECT apart.
+type Client = BufReader<MaybeTlsStream>;
+
+/// When a connection's handshake has to be done by (`None` = no limit).
+type Deadline = Option<tokio::time::Instant>;
+
+/// Labels of the per-tunnel metrics. Users and devices come from our own config,
+/// so their number is bounded; targets are never used as labels.
+const TUNNEL_LABELS: &[&str] = &["device", "user", "fingerprint"];
+
+const CONNECT_SECONDS: &str = "proxy_connect_duration_seconds";
+const CONNECT_BUCKETS: &[f64] = &[0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Numbers outbound connections for exemplars and logs.
+static CONNECTION_IDS: AtomicU64 = AtomicU64::new(1);
+
+lazy_static! {
+    static ref PROXY_CONNECTIONS: IntCounterVec = register_int_counter_vec!(
+        "proxy_connections_accepted_total",
+        "Authorized proxy connections, counted when the outbound connect starts",
+        TUNNEL_LABELS
+    )
+    .unwrap();
+    static ref PROXY_AUTH_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "proxy_auth_failures_total",
+        "Rejected proxy credentials, by client protocol",
+        &["protocol"]
+    )
+    .unwrap();
+    static ref PROXY_ACTIVE_TUNNELS: IntGaugeVec = register_int_gauge_vec!(
+        "proxy_active_tunnels",
+        "Tunnels currently open",
+        TUNNEL_LABELS
+    )
+    .unwrap();
+    static ref PROXY_BYTES: IntCounterVec = register_int_counter_vec!(
+        "proxy_bytes_total",
+        "Bytes relayed; direction `in` is client to target, `out` is target to client",
+        &["device", "user", "fingerprint", "direction"]
+    )
+    .unwrap();
+    static ref PROXY_CONNECT_SECONDS: HistogramVec = register_histogram_vec!(
+        CONNECT_SECONDS,
+        "Time to open the outbound connection, DNS and upstream handshake included",
+        TUNNEL_LABELS,
+        CONNECT_BUCKETS.to_vec()
+    )
+    .unwrap();
+    static ref PROXY_TUNNEL_SECONDS: HistogramVec = register_histogram_vec!(
+        "proxy_tunnel_duration_seconds",
+        "How long tunnels stayed open",
+        TUNNEL_LABELS,
+        vec![1.0, 5.0, 15.0, 30.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 14400.0]
+    )
+    .unwrap();
+    static ref PROXY_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "proxy_errors_total",
+        "Failed proxy connections, by error kind",
+        &["error"]
+    )
+    .unwrap();
+}
 
 #[derive(Debug, Error)]
 pub enum Socks5Error {
@@ -55,74 +151,681 @@ pub enum Socks5Error {
     #[error("tcp connect via interface failed: {0}")]
     Connect(#[source] io::Error),
 
+    #[error("resolve `{0}` failed: {1}")]
+    Resolve(String, #[source] io::Error),
+
+    #[error("destination denied: {0}")]
+    DestinationDenied(Denied),
+
+    #[error("handshake timed out")]
+    HandshakeTimeout,
+
+    #[error("connect to {0} timed out")]
+    ConnectTimeout(String),
+
+    #[error("tunnel idle for {0:?}")]
+    IdleTimeout(Duration),
+
+    #[error("tunnel failed: {0}")]
+    Tunnel(#[source] io::Error),
+
     #[error("response write failed: {0}")]
     ResponseWrite(#[source] io::Error),
 
     #[error("command not supported: {0:?}")]
     UnsupportedCommand(Command),
 
+    #[error("unknown protocol, first byte {0:#04x}")]
+    UnknownProtocol(u8),
+
     #[error("command not allowed: {0:?}")]
     CommandNotAllowed(Command),
 
+    #[error("quota exhausted for {0}")]
+    QuotaExceeded(QuotaExceeded),
+
     #[error("utf8 decoding failed: {0}")]
     Utf8(#[from] FromUtf8Error),
+
+    #[error("TLS handshake failed: {0}")]
+    Tls(#[source] io::Error),
+
+    #[error("invalid HTTP proxy request: {0}")]
+    HttpRequest(String),
+
+    #[error("invalid SOCKS4 request: {0}")]
+    Socks4Request(String),
+
+    #[error("HTTP method not supported: {0}")]
+    HttpMethod(String),
+
+    #[error("proxy authentication required")]
+    ProxyAuthRequired,
+
+    #[error("invalid PROXY protocol header: {0}")]
+    ProxyProtocol(#[source] io::Error),
+
+    #[error(transparent)]
+    Upstream(#[from] UpstreamError),
+
+    #[error("device `{0}` is unhealthy")]
+    DeviceUnhealthy(String),
+
+    #[error("device `{0}` is draining for an IP rotation")]
+    DeviceRotating(String),
 }
 
 #[derive(Builder, Clone)]
-#[builder(pattern = "owned")]
+#[builder(pattern = "owned", derive(Clone))]
 pub struct Socks5 {
     fingerprint: OsFingerprint,
+    /// Per-device defaults overriding `fingerprint`; a `-fingerprint-` username suffix still wins.
+    #[builder(default)]
+    fingerprints: HashMap<String, OsFingerprint>,
     listen_addr: SocketAddr,
-    iface_map: HashMap<String, String>,
+    /// Read once per connection, so a tunnel keeps the interface it was opened on.
+    iface_map: Arc<Interfaces>,
+    /// Old device ids still accepted as passwords, mapped to the current ones.
+    #[builder(default)]
+    aliases: DeviceAliases,
     logger: Logger,
+    #[builder(default)]
+    usage: Arc<UsageStore>,
+    /// Swapped on config reloads; each connection checks against the current policy.
+    #[builder(default)]
+    acl: Arc<Live<AclPolicy>>,
+    /// Global client allowlist, checked right after accept.
+    #[builder(default)]
+    allowed_sources: SourceAllowlist,
+    /// Load balancers whose connections may start with a PROXY protocol header
+    /// carrying the real client address (`None` = never parse the header).
+    #[builder(default)]
+    proxy_protocol: Option<SourceAllowlist>,
+    #[builder(default)]
+    users: Arc<UserStore>,
+    /// Per-device upstream proxies the outbound connection is chained through.
+    #[builder(default)]
+    upstreams: Arc<Upstreams>,
+    /// Devices the health checker took out of routing are refused.
+    #[builder(default)]
+    health: Arc<HealthRegistry>,
+    /// Connections per device; devices draining for a rotation are refused.
+    #[builder(default)]
+    rotation: Arc<RotationRegistry>,
+    /// Max time from accept until the request is read, PROXY header, TLS and auth
+    /// included; one deadline for the whole handshake (`None` = unlimited).
+    #[builder(default = "Some(Duration::from_secs(10))")]
+    handshake_timeout: Option<Duration>,
+    /// Max time to establish the outbound TCP connection (`None` = kernel default).
+    #[builder(default = "Some(Duration::from_secs(10))")]
+    connect_timeout: Option<Duration>,
+    /// Close a tunnel after no data moved for this long (`None` = never).
+    #[builder(default = "Some(Duration::from_secs(300))")]
+    idle_timeout: Option<Duration>,
+    /// Terminate TLS before the SOCKS5/HTTP handshake (`None` = cleartext).
+    #[builder(default)]
+    tls: Option<TlsAcceptor>,
+    /// Also serve HTTP `CONNECT`; otherwise anything but SOCKS4/5 is dropped.
+    #[builder(default)]
+    http_connect: bool,
+    /// Stop accepting once cancelled; `run` returns when the tunnels have drained.
+    #[builder(default)]
+    shutdown: CancellationToken,
+    /// How long open tunnels may keep running after shutdown (`None` = until they finish).
+    #[builder(default = "Some(Duration::from_secs(30))")]
+    drain_timeout: Option<Duration>,
 }
 
 pub type Result<T> = result::Result<T, Socks5Error>;
 
+impl Socks5Error {
+    /// Short, stable name of the variant, used as the `error` metric label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Socks5Error::Listen(_) => "listen",
+            Socks5Error::Accept(_) => "accept",
+            Socks5Error::Handshake(_) => "handshake",
+            Socks5Error::UnsupportedMethod => "unsupported_method",
+            Socks5Error::PasswordRequest(_) => "password_request",
+            Socks5Error::AuthenticationFailed(_) => "authentication_failed",
+            Socks5Error::PasswordResponseWrite(_) => "password_response_write",
+            Socks5Error::RequestRead(_) => "request_read",
+            Socks5Error::InvalidAddress(_) => "invalid_address",
+            Socks5Error::Connect(_) => "connect",
+            Socks5Error::Resolve(..) => "resolve",
+            Socks5Error::DestinationDenied(_) => "destination_denied",
+            Socks5Error::HandshakeTimeout => "handshake_timeout",
+            Socks5Error::ConnectTimeout(_) => "connect_timeout",
+            Socks5Error::IdleTimeout(_) => "idle_timeout",
+            Socks5Error::Tunnel(_) => "tunnel",
+            Socks5Error::ResponseWrite(_) => "response_write",
+            Socks5Error::UnsupportedCommand(_) => "unsupported_command",
+            Socks5Error::UnknownProtocol(_) => "unknown_protocol",
+            Socks5Error::CommandNotAllowed(_) => "command_not_allowed",
+            Socks5Error::QuotaExceeded(_) => "quota_exceeded",
+            Socks5Error::Utf8(_) => "utf8",
+            Socks5Error::Tls(_) => "tls",
+            Socks5Error::HttpRequest(_) => "http_request",
+            Socks5Error::Socks4Request(_) => "socks4_request",
+            Socks5Error::HttpMethod(_) => "http_method",
+            Socks5Error::ProxyAuthRequired => "proxy_auth_required",
+            Socks5Error::ProxyProtocol(_) => "proxy_protocol",
+            Socks5Error::Upstream(_) => "upstream",
+            Socks5Error::DeviceUnhealthy(_) => "device_unhealthy",
+            Socks5Error::DeviceRotating(_) => "device_rotating",
+        }
+    }
+
+    /// The SOCKS5 reply code a client should see for this failure.
+    ///
+    /// Clients rely on this to tell a dead target (refused, host unreachable)
+    /// apart from a dead modem link (network unreachable, general failure).
+    pub fn reply(&self) -> Reply {
+        match self {
+            Socks5Error::Connect(e) => connect_error_reply(e),
+            Socks5Error::ConnectTimeout(_) => Reply::TtlExpired,
+            Socks5Error::InvalidAddress(_) => Reply::AddressTypeNotSupported,
+            Socks5Error::Resolve(..) => Reply::HostUnreachable,
+            Socks5Error::QuotaExceeded(_)
+            | Socks5Error::DestinationDenied(_)
+            | Socks5Error::CommandNotAllowed(_) => Reply::ConnectionNotAllowed,
+            Socks5Error::UnsupportedCommand(_) => Reply::CommandNotSupported,
+            Socks5Error::Upstream(e) => e.reply(),
+            Socks5Error::DeviceUnhealthy(_) | Socks5Error::DeviceRotating(_) => {
+                Reply::NetworkUnreachable
+            }
+            _ => Reply::GeneralFailure,
+        }
+    }
+
+    /// The HTTP status an HTTP CONNECT client should see for this failure.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Socks5Error::AuthenticationFailed(_) | Socks5Error::ProxyAuthRequired => {
+                StatusCode::PROXY_AUTHENTICATION_REQUIRED
+            }
+            Socks5Error::QuotaExceeded(_) | Socks5Error::DestinationDenied(_) => {
+                StatusCode::FORBIDDEN
+            }
+            Socks5Error::HttpMethod(_) => StatusCode::METHOD_NOT_ALLOWED,
+            Socks5Error::HttpRequest(_) | Socks5Error::InvalidAddress(_) => StatusCode::BAD_REQUEST,
+            Socks5Error::ConnectTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Socks5Error::Connect(e) if connect_error_reply(e) == Reply::TtlExpired => {
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            Socks5Error::Connect(_) | Socks5Error::Resolve(..) => StatusCode::BAD_GATEWAY,
+            Socks5Error::Upstream(e) => match e.reply() {
+                Reply::ConnectionNotAllowed => StatusCode::FORBIDDEN,
+                Reply::TtlExpired => StatusCode::GATEWAY_TIMEOUT,
+                _ => StatusCode::BAD_GATEWAY,
+            },
+            Socks5Error::DeviceUnhealthy(_) | Socks5Error::DeviceRotating(_) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Maps an outbound connect error to a SOCKS5 reply.
+fn connect_error_reply(err: &io::Error) -> Reply {
+    match err.raw_os_error() {
+        Some(libc::ECONNREFUSED) => return Reply::ConnectionRefused,
+        Some(libc::ENETUNREACH) | Some(libc::ENETDOWN) => return Reply::NetworkUnreachable,
+        Some(libc::EHOSTUNREACH) | Some(libc::EHOSTDOWN) => return Reply::HostUnreachable,
+        Some(libc::ETIMEDOUT) => return Reply::TtlExpired,
+        // SO_BINDTODEVICE without CAP_NET_RAW, or the interface vanished
+        Some(libc::EPERM) | Some(libc::EACCES) | Some(libc::ENODEV) => {
+            return Reply::GeneralFailure;
+        }
+        _ => {}
+    }
+
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
+        io::ErrorKind::NetworkUnreachable | io::ErrorKind::NetworkDown => Reply::NetworkUnreachable,
+        io::ErrorKind::HostUnreachable => Reply::HostUnreachable,
+        io::ErrorKind::TimedOut => Reply::TtlExpired,
+        _ => Reply::GeneralFailure,
+    }
+}
+
 impl Socks5 {
-    /// Consume the builder and start serving forever.
-    pub async fn run(self) -> Result<Socks5Error> {
+    /// Consume the builder and serve until `shutdown` is cancelled, then stop
+    /// accepting and drain open tunnels.
+    pub async fn run(self) -> Result<()> {
         let listener = TcpListener::bind(self.listen_addr)
             .await
             .map_err(Socks5Error::Listen)?;
 
-        let logger = self.logger.clone();
-        let iface_map = self.iface_map.clone();
         let server = Arc::new(self);
+        let connections = TaskTracker::new();
+        let force_close = CancellationToken::new();
 
         loop {
-            let (stream, peer) = listener.accept().await.map_err(Socks5Error::Accept)?;
-            let server = Arc::clone(&server);               // cheap clone of the Arc
-            let iface_map = server.iface_map.clone();
-            let logger    = server.logger.clone();
-            
-            tokio::spawn(async move {
-                if let Err(err) = server.handle_client(stream, iface_map).await {
-                    error!(logger, "client {} error: {}", peer, err);
+            let (stream, peer) = tokio::select! {
+                res = listener.accept() => res.map_err(Socks5Error::Accept)?,
+                _ = server.shutdown.cancelled() => break,
+            };
+            let server = Arc::clone(&server); // cheap clone of the Arc
+            let iface_map = server.iface_map.get();
+            let force_close = force_close.clone();
+
+            connections.spawn(async move {
+                tokio::select! {
+                    _ = server.serve(stream, peer, iface_map) => {}
+                    _ = force_close.cancelled() => {}
                 }
             });
         }
+
+        drop(listener);
+        connections.close();
+        info!(server.logger, "SOCKS5 listener closed, draining";
+            "addr" => %server.listen_addr,
+            "connections" => connections.len(),
+        );
+
+        if let Some(limit) = server.drain_timeout {
+            if timeout(limit, connections.wait()).await.is_err() {
+                warn!(server.logger, "drain deadline passed, closing tunnels";
+                    "addr" => %server.listen_addr,
+                    "connections" => connections.len(),
+                );
+                force_close.cancel();
+            }
+        }
+        connections.wait().await;
+        Ok(())
     }
 
-    /// Per‐connection handler: does the SOCKS5 handshake, auth, CONNECT, proxying.
+    /// Admission checks and error logging around [`Socks5::handle_client`].
+    async fn serve(
+        &self,
+        mut stream: TcpStream,
+        peer: SocketAddr,
+        iface_map: Arc<HashMap<String, String>>,
+    ) {
+        let deadline = self
+            .handshake_timeout
+            .map(|limit| tokio::time::Instant::now() + limit);
+        let peer = match self.client_addr(&mut stream, peer, deadline).await {
+            Ok(client) => client,
+            Err(err) => {
+                PROXY_ERRORS.with_label_values(&[err.kind()]).inc();
+                warn!(self.logger, "client rejected"; "proxy" => %peer, "error" => %err);
+                return;
+            }
+        };
+
+        if !self.allowed_sources.allows(peer.ip()) {
+            warn!(self.logger, "client rejected by source allowlist"; "peer" => %peer);
+            return;
+        }
+
+        if let Err(err) = self.handle_client(stream, peer, iface_map, deadline).await {
+            PROXY_ERRORS.with_label_values(&[err.kind()]).inc();
+            error!(self.logger, "client {} error: {}", peer, err);
+        }
+    }
+
+    /// The real client address: from the PROXY protocol header when `peer` is a
+    /// trusted load balancer that sent one, otherwise `peer` itself.
+    async fn client_addr(
+        &self,
+        stream: &mut TcpStream,
+        peer: SocketAddr,
+        deadline: Deadline,
+    ) -> Result<SocketAddr> {
+        match &self.proxy_protocol {
+            Some(trusted) if trusted.allows(peer.ip()) => {
+                let header = until(
+                    deadline,
+                    async {
+                        if !proxy_protocol::has_header(stream)
+                            .await
+                            .map_err(Socks5Error::ProxyProtocol)?
+                        {
+                            return Ok(None);
+                        }
+                        proxy_protocol::read_header(stream)
+                            .await
+                            .map_err(Socks5Error::ProxyProtocol)
+                    },
+                    Socks5Error::HandshakeTimeout,
+                )
+                .await?;
+                Ok(header.unwrap_or(peer))
+            }
+            _ => Ok(peer),
+        }
+    }
+
+    /// Per‐connection handler: TLS, then SOCKS5, SOCKS4 or HTTP CONNECT, then proxying.
     async fn handle_client(
         &self,
-        mut client: TcpStream,
-        iface_map: HashMap<String, String>,
+        stream: TcpStream,
+        peer: SocketAddr,
+        iface_map: Arc<HashMap<String, String>>,
+        deadline: Deadline,
     ) -> Result<()> {
+        // the TLS handshake and the protocol sniff count toward the handshake timeout
+        let (client, first) = until(
+            deadline,
+            async {
+                let stream = MaybeTlsStream::accept(stream, self.tls.as_ref())
+                    .await
+                    .map_err(Socks5Error::Tls)?;
+                let mut client = BufReader::new(stream);
+                let first = client
+                    .fill_buf()
+                    .await
+                    .map_err(|e| Socks5Error::Handshake(e.into()))?
+                    .first()
+                    .copied();
+                Ok((client, first))
+            },
+            Socks5Error::HandshakeTimeout,
+        )
+        .await?;
+
+        let (protocol, res) = match first {
+            Some(SOCKS5_VERSION) => (
+                "socks5",
+                self.handle_socks5(client, peer, iface_map, deadline).await,
+            ),
+            Some(socks4::VERSION) => (
+                "socks4",
+                self.handle_socks4(client, peer, iface_map, deadline).await,
+            ),
+            Some(_) if self.http_connect => (
+                "http",
+                self.handle_http(client, peer, iface_map, deadline).await,
+            ),
+            Some(byte) => return Err(Socks5Error::UnknownProtocol(byte)),
+            None => return Ok(()), // closed before sending anything
+        };
+        if let Err(Socks5Error::AuthenticationFailed(_) | Socks5Error::ProxyAuthRequired) = &res {
+            PROXY_AUTH_FAILURES.with_label_values(&[protocol]).inc();
+        }
+        res
+    }
+
+    async fn handle_socks5(
+        &self,
+        mut client: Client,
+        peer: SocketAddr,
+        iface_map: Arc<HashMap<String, String>>,
+        deadline: Deadline,
+    ) -> Result<()> {
+        // 1-6) handshake, auth and request, bounded by the handshake timeout
+        let (username, password, fingerprint, req) = until(
+            deadline,
+            self.negotiate(&mut client, peer, &iface_map),
+            Socks5Error::HandshakeTimeout,
+        )
+        .await?;
+
+        // 7) dispatch
+        match req.command {
+            Command::Connect => {
+                if let Err(e) = self.usage.check_quota(&username, &password) {
+                    Response::new(Reply::ConnectionNotAllowed, req.address)
+                        .write_to(&mut client)
+                        .await
+                        .map_err(Socks5Error::ResponseWrite)?;
+                    return Err(Socks5Error::QuotaExceeded(e));
+                }
+
+                let (_sent, _recv) = self
+                    .server_socks5_connect(
+                        &password,
+                        &username,
+                        req.address,
+                        fingerprint,
+                        client,
+                        &iface_map,
+                    )
+                    .await?;
+                Ok(())
+            }
+            cmd @ Command::Associate => {
+                Response::new(Reply::ConnectionNotAllowed, req.address)
+                    .write_to(&mut client)
+                    .await
+                    .map_err(Socks5Error::ResponseWrite)?;
+                Err(Socks5Error::CommandNotAllowed(cmd))
+            }
+            cmd => {
+                Response::new(Reply::CommandNotSupported, req.address)
+                    .write_to(&mut client)
+                    .await
+                    .map_err(Socks5Error::ResponseWrite)?;
+                Err(Socks5Error::UnsupportedCommand(cmd))
+            }
+        }
+    }
+
+    /// HTTP CONNECT proxying, for clients configured with an `http(s)://` proxy URL.
+    /// Credentials are the same as for SOCKS5, sent as `Proxy-Authorization: Basic`.
+    async fn handle_http(
+        &self,
+        mut client: Client,
+        peer: SocketAddr,
+        iface_map: Arc<HashMap<String, String>>,
+        deadline: Deadline,
+    ) -> Result<()> {
+        let negotiated = until(
+            deadline,
+            async {
+                let req = read_connect_request(&mut client).await?;
+                let (username, device, fingerprint) =
+                    self.authorize(req.credentials, peer, &iface_map)?;
+                Ok((username, device, fingerprint, req.target))
+            },
+            Socks5Error::HandshakeTimeout,
+        )
+        .await
+        .and_then(|(username, device, fingerprint, target)| {
+            self.usage
+                .check_quota(&username, &device)
+                .map_err(Socks5Error::QuotaExceeded)?;
+            Ok((username, device, fingerprint, target))
+        });
+        let (username, device, fingerprint, target) = match negotiated {
+            Ok(n) => n,
+            Err(e) => {
+                write_status(&mut client, e.status()).await?;
+                return Err(e);
+            }
+        };
+
+        let (outbound, lease) = match self
+            .connect_outbound(&device, &username, &target, fingerprint, &iface_map)
+            .await
+        {
+            Ok(conn) => conn,
+            Err(e) => {
+                write_status(&mut client, e.status()).await?;
+                return Err(e);
+            }
+        };
+        write_status(&mut client, StatusCode::OK).await?;
+
+        let meter = self.usage.meter(&username, &device);
+        let labels = [device.as_str(), username.as_str(), fingerprint.as_str()];
+        self.tunnel(client, outbound, lease, meter, labels)
+            .await
+            .map(|_| ())
+    }
+
+    /// SOCKS4/4a CONNECT, for legacy clients. The password travels in the USERID
+    /// field as `user:password`.
+    async fn handle_socks4(
+        &self,
+        mut client: Client,
+        peer: SocketAddr,
+        iface_map: Arc<HashMap<String, String>>,
+        deadline: Deadline,
+    ) -> Result<()> {
+        let negotiated = until(
+            deadline,
+            async {
+                let req = socks4::read_request(&mut client).await?;
+                self.authorize_socks4(req, peer, &iface_map)
+            },
+            Socks5Error::HandshakeTimeout,
+        )
+        .await
+        .and_then(|(username, device, fingerprint, target)| {
+            self.usage
+                .check_quota(&username, &device)
+                .map_err(Socks5Error::QuotaExceeded)?;
+            Ok((username, device, fingerprint, target))
+        });
+        let (username, device, fingerprint, target) = match negotiated {
+            Ok(n) => n,
+            Err(e) => {
+                socks4::write_reply(&mut client, false).await?;
+                return Err(e);
+            }
+        };
+
+        let (outbound, lease) = match self
+            .connect_outbound(&device, &username, &target, fingerprint, &iface_map)
+            .await
+        {
+            Ok(conn) => conn,
+            Err(e) => {
+                socks4::write_reply(&mut client, false).await?;
+                return Err(e);
+            }
+        };
+        socks4::write_reply(&mut client, true).await?;
+
+        let meter = self.usage.meter(&username, &device);
+        let labels = [device.as_str(), username.as_str(), fingerprint.as_str()];
+        self.tunnel(client, outbound, lease, meter, labels)
+            .await
+            .map(|_| ())
+    }
+
+    fn authorize_socks4(
+        &self,
+        req: Socks4Request,
+        peer: SocketAddr,
+        iface_map: &HashMap<String, String>,
+    ) -> Result<(String, String, OsFingerprint, Address)> {
+        if req.command != Command::Connect {
+            return Err(Socks5Error::UnsupportedCommand(req.command));
+        }
+        let (username, device, fingerprint) = self.authorize(req.credentials(), peer, iface_map)?;
+        Ok((username, device, fingerprint, req.target))
+    }
+
+    /// Checks credentials sent in-band (HTTP CONNECT, SOCKS4), falling back to IP
+    /// authentication when none are sent.
+    ///
+    /// Returns the bare username, the device id and the fingerprint.
+    fn authorize(
+        &self,
+        credentials: Option<(String, String)>,
+        peer: SocketAddr,
+        iface_map: &HashMap<String, String>,
+    ) -> Result<(String, String, OsFingerprint)> {
+        let Some((username, password)) = credentials else {
+            let (user, device) = self
+                .users
+                .ip_user(peer.ip())
+                .and_then(|u| {
+                    let device = self.known_device(u.device.as_deref()?, iface_map)?;
+                    Some((u.name, device))
+                })
+                .ok_or(Socks5Error::ProxyAuthRequired)?;
+            let fingerprint = self.default_fingerprint(&device);
+            return Ok((user, device, fingerprint));
+        };
+
+        let (device, secret) = self.split_password(&password, iface_map);
+        let fallback = device.as_deref().map_or(self.fingerprint, |d| self.default_fingerprint(d));
+        let (username, fingerprint) = parse_username(username.as_str(), fallback)
+            .map_err(|_| Socks5Error::AuthenticationFailed(username.clone()))?;
+        let device = device.filter(|_| self.users.authorize(&username, secret, peer.ip()));
+        let Some(device) = device else {
+            return Err(Socks5Error::AuthenticationFailed(username));
+        };
+        Ok((username, device, fingerprint))
+    }
+
+    /// The fingerprint for connections through `device` that do not ask for one.
+    fn default_fingerprint(&self, device: &str) -> OsFingerprint {
+        self.fingerprints
+            .get(device)
+            .copied()
+            .unwrap_or(self.fingerprint)
+    }
+
+    /// The current id of `device`, which may be an alias; `None` if no interface serves it.
+    fn known_device(&self, device: &str, iface_map: &HashMap<String, String>) -> Option<String> {
+        let device = self.aliases.resolve(device);
+        iface_map.contains_key(device).then(|| device.to_string())
+    }
+
+    /// The device and user secret in a proxy password, `<device id>` or
+    /// `<device id>:<secret>`. Device ids may contain colons themselves, so the
+    /// whole password is tried as a device first.
+    fn split_password<'a>(
+        &self,
+        password: &'a str,
+        iface_map: &HashMap<String, String>,
+    ) -> (Option<String>, Option<&'a str>) {
+        if let Some(device) = self.known_device(password, iface_map) {
+            return (Some(device), None);
+        }
+        match password.rsplit_once(':') {
+            Some((device, secret)) => (self.known_device(device, iface_map), Some(secret)),
+            None => (None, None),
+        }
+    }
+
+    /// Reads the method selection, credentials and request from a fresh client.
+    ///
+    /// Returns the bare username, the device id (aliases resolved), the fingerprint and the request.
+    async fn negotiate(
+        &self,
+        client: &mut Client,
+        peer: SocketAddr,
+        iface_map: &HashMap<String, String>,
+    ) -> Result<(String, String, OsFingerprint, Request)> {
         // 1) handshake
-        let hs_req = HandshakeRequest::read_from(&mut client)
+        let hs_req = HandshakeRequest::read_from(client)
             .await
             .map_err(Socks5Error::Handshake)?;
 
-        // 2) check USER/PASS support
-        if !hs_req
-            .methods
-            .iter()
-            .any(|&m| m == HandshakeMethod::PASSWORD)
+        // 2) IP-authenticated users may skip USER/PASS; credentials win when offered
+        if !hs_req.methods.contains(&HandshakeMethod::PASSWORD)
+            && hs_req.methods.contains(&HandshakeMethod::NONE)
         {
+            if let Some((user, device)) = self.users.ip_user(peer.ip()).and_then(|u| {
+                let device = self.known_device(u.device.as_deref()?, iface_map)?;
+                Some((u.name, device))
+            }) {
+                HandshakeResponse::new(HandshakeMethod::NONE)
+                    .write_to(client)
+                    .await
+                    .map_err(Socks5Error::ResponseWrite)?;
+                let req = Request::read_from(client)
+                    .await
+                    .map_err(Socks5Error::RequestRead)?;
+                let fingerprint = self.default_fingerprint(&device);
+                return Ok((user, device, fingerprint, req));
+            }
+        }
+
+        // 2b) check USER/PASS support
+        if !hs_req.methods.contains(&HandshakeMethod::PASSWORD) {
             HandshakeResponse::new(HandshakeMethod::UNACCEPTABLE)
-                .write_to(&mut client)
+                .write_to(client)
                 .await
                 .map_err(Socks5Error::ResponseWrite)?;
             return Err(Socks5Error::UnsupportedMethod);
@@ -130,85 +833,511 @@ impl Socks5 {
 
         // 3) ack USER/PASS
         HandshakeResponse::new(HandshakeMethod::PASSWORD)
-            .write_to(&mut client)
+            .write_to(client)
             .await
             .map_err(Socks5Error::ResponseWrite)?;
 
         // 4) read credentials
-        let pwd_req = PasswordRequest::read_from(&mut client)
+        let pwd_req = PasswordRequest::read_from(client)
             .await
             .map_err(Socks5Error::PasswordRequest)?;
         let username = String::from_utf8(pwd_req.username)?;
         let password = String::from_utf8(pwd_req.password)?;
 
         // 5) validate
-        let (username, fingerprint) = parse_username(username.as_str(), self.fingerprint)
+        let (device, secret) = self.split_password(&password, iface_map);
+        let fallback = device.as_deref().map_or(self.fingerprint, |d| self.default_fingerprint(d));
+        let (username, fingerprint) = parse_username(username.as_str(), fallback)
             .map_err(|_| Socks5Error::AuthenticationFailed(username.clone()))?;
 
-        let auth_ok = username == "modem" && iface_map.contains_key(&password);
+        let device = device.filter(|_| self.users.authorize(&username, secret, peer.ip()));
 
-        PasswordResponse::new(auth_ok)
-            .write_to(&mut client)
+        PasswordResponse::new(device.is_some())
+            .write_to(client)
             .await
             .map_err(Socks5Error::PasswordResponseWrite)?;
-        if !auth_ok {
+        let Some(device) = device else {
             return Err(Socks5Error::AuthenticationFailed(username));
-        }
+        };
 
         // 6) read SOCKS5 request
-        let req = Request::read_from(&mut client)
+        let req = Request::read_from(client)
             .await
             .map_err(Socks5Error::RequestRead)?;
 
-        // 7) lookup interface name
-        let ifname = iface_map.get(&password).unwrap(); // safe—just checked
+        Ok((username, device, fingerprint, req))
+    }
 
-        // 8) dispatch
-        match req.command {
-            Command::Connect => {
-                let (_sent, _recv) =
-                    Self::server_socks5_connect(ifname, req.address, fingerprint, client).await?;
-                Ok(())
-            }
-            cmd @ Command::Associate => {
-                Response::new(Reply::ConnectionNotAllowed, req.address)
-                    .write_to(&mut client)
-                    .await
-                    .map_err(Socks5Error::ResponseWrite)?;
-                Err(Socks5Error::CommandNotAllowed(cmd))
-            }
-            cmd => {
-                Response::new(Reply::CommandNotSupported, req.address)
+    async fn server_socks5_connect(
+        &self,
+        device: &str,
+        username: &str,
+        requested_addr: Address,
+        fingerprint: OsFingerprint,
+        mut client: Client,
+        iface_map: &HashMap<String, String>,
+    ) -> Result<(u64, u64)> {
+        let meter = self.usage.meter(username, device);
+        // always tell the client why the connect failed before closing
+        let (outbound, lease) = match self
+            .connect_outbound(device, username, &requested_addr, fingerprint, iface_map)
+            .await
+        {
+            Ok(conn) => conn,
+            Err(e) => {
+                Response::new(e.reply(), requested_addr)
                     .write_to(&mut client)
                     .await
                     .map_err(Socks5Error::ResponseWrite)?;
-                Err(Socks5Error::UnsupportedCommand(cmd))
+                return Err(e);
             }
+        };
+
+        Response::new(Reply::Succeeded, requested_addr)
+            .write_to(&mut client)
+            .await
+            .map_err(Socks5Error::ResponseWrite)?;
+
+        let labels = [device, username, fingerprint.as_str()];
+        self.tunnel(client, outbound, lease, meter, labels).await
+    }
+
+    /// Resolves `requested_addr` against the ACL and connects to it over the device's
+    /// interface, chaining through the device's upstream proxy if it has one. The
+    /// lease counts the connection against the device until the tunnel closes.
+    /// `iface_map` is the connection's snapshot, so the interface dialed is the one the
+    /// device was authorized, health-checked and leased on.
+    async fn connect_outbound(
+        &self,
+        device: &str,
+        username: &str,
+        requested_addr: &Address,
+        fingerprint: OsFingerprint,
+        iface_map: &HashMap<String, String>,
+    ) -> Result<(TcpStream, Lease)> {
+        let ifname = iface_map.get(device).cloned().ok_or_else(|| {
+            Socks5Error::Connect(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no interface for device `{}`", device),
+            ))
+        })?;
+        if !self.health.is_healthy(device) {
+            return Err(Socks5Error::DeviceUnhealthy(device.to_string()));
         }
+        let lease = self
+            .rotation
+            .lease(device)
+            .ok_or_else(|| Socks5Error::DeviceRotating(device.to_string()))?;
+        let labels = [device, username, fingerprint.as_str()];
+        PROXY_CONNECTIONS.with_label_values(&labels).inc();
+        let started = Instant::now();
+
+        let stream = self
+            .dial(&ifname, device, username, requested_addr, fingerprint)
+            .await?;
+        // the exemplar carries a connection id rather than the destination; the id
+        // leads to the debug log line that has the target
+        let conn = CONNECTION_IDS.fetch_add(1, Ordering::Relaxed);
+        debug!(self.logger, "connected";
+            "conn" => conn, "device" => device, "user" => username, "target" => %requested_addr);
+        observe_with_exemplar(
+            &PROXY_CONNECT_SECONDS,
+            CONNECT_SECONDS,
+            CONNECT_BUCKETS,
+            &[
+                ("device", device),
+                ("user", username),
+                ("fingerprint", fingerprint.as_str()),
+            ],
+            started.elapsed().as_secs_f64(),
+            vec![("conn", conn.to_string())],
+        );
+        Ok((stream, lease))
     }
 
-    async fn server_socks5_connect(
+    /// [`Socks5::connect_outbound`] without the metrics.
+    async fn dial(
+        &self,
         ifname: &str,
-        requested_addr: Address,
+        device: &str,
+        username: &str,
+        requested_addr: &Address,
         fingerprint: OsFingerprint,
-        mut client: TcpStream,
+    ) -> Result<TcpStream> {
+        let Some(upstream) = self.upstreams.get(device) else {
+            let sock_addr = self.resolve_allowed(username, requested_addr).await?;
+            return with_timeout(
+                self.connect_timeout,
+                async {
+                    tcp_connect_with_fingerprint(sock_addr, ifname, fingerprint)
+                        .await
+                        .map_err(Socks5Error::Connect)
+                },
+                Socks5Error::ConnectTimeout(sock_addr.to_string()),
+            )
+            .await;
+        };
+
+        // socks5h upstreams resolve names themselves; only host and port rules apply then
+        let target = match requested_addr {
+            Address::DomainAddress(..) if upstream.resolves_remotely() => {
+                self.check_host_allowed(username, requested_addr)?;
+                requested_addr.clone()
+            }
+            _ => Address::SocketAddress(self.resolve_allowed(username, requested_addr).await?),
+        };
+
+        // client → proxymodem → modem → upstream → target
+        with_timeout(
+            self.connect_timeout,
+            async {
+                let upstream_addr = upstream.resolve().await?;
+                let mut stream = tcp_connect_with_fingerprint(upstream_addr, ifname, fingerprint)
+                    .await
+                    .map_err(|e| UpstreamError::Connect(upstream.to_string(), e))?;
+                upstream.handshake(&mut stream, &target).await?;
+                Ok(stream)
+            },
+            Socks5Error::ConnectTimeout(target.to_string()),
+        )
+        .await
+    }
+
+    /// Copies bytes both ways until either side closes or the tunnel goes idle.
+    async fn tunnel(
+        &self,
+        client: Client,
+        mut outbound: TcpStream,
+        _lease: Lease,
+        meter: Meter,
+        labels: [&str; 3],
     ) -> Result<(u64, u64)> {
-        let sock_addr: SocketAddr = requested_addr
-            .to_string()
-            .parse()
-            .map_err(Socks5Error::InvalidAddress)?;
+        let _open = OpenTunnel::new(&labels);
 
-        let mut outbound = tcp_connect_with_fingerprint(sock_addr, ifname, fingerprint)
-            .await
-            .map_err(Socks5Error::Connect)?;
+        // bytes are accounted as they flow, so open tunnels count toward quotas
+        let client = Counted::new(Metered::new(client, meter), &labels);
+        let mut client = Idle::new(client);
+        let Some(idle_timeout) = self.idle_timeout else {
+            return copy_bidirectional(&mut client, &mut outbound)
+                .await
+                .map_err(tunnel_error);
+        };
 
-        Response::new(Reply::Succeeded, requested_addr)
-            .write_to(&mut client)
-            .await
-            .map_err(Socks5Error::ResponseWrite)?;
+        let last_activity = client.last_activity.clone();
+        let started = client.started;
+        let copy = copy_bidirectional(&mut client, &mut outbound);
+        tokio::pin!(copy);
+        loop {
+            let idle_for = started
+                .elapsed()
+                .saturating_sub(Duration::from_millis(last_activity.load(Ordering::Relaxed)));
+            if idle_for >= idle_timeout {
+                return Err(Socks5Error::IdleTimeout(idle_timeout));
+            }
+
+            tokio::select! {
+                res = &mut copy => return res.map_err(tunnel_error),
+                _ = sleep(idle_timeout - idle_for) => {}
+            }
+        }
+    }
+
+    /// Checks a domain left for the upstream to resolve against the ACL's host and port rules.
+    fn check_host_allowed(&self, username: &str, requested_addr: &Address) -> Result<()> {
+        let Address::DomainAddress(domain, port) = requested_addr else {
+            return Ok(());
+        };
+        let host = String::from_utf8(domain.clone())?;
+        self.acl
+            .get()
+            .check_host(username, &host, *port)
+            .map_err(|d| {
+                warn!(self.logger, "destination denied";
+                    "user" => username,
+                    "target" => &d.target,
+                    "reason" => &d.reason,
+                );
+                Socks5Error::DestinationDenied(d)
+            })
+    }
+
+    /// Resolves the requested address and returns the first destination the ACL allows.
+    /// Names go through the host's resolver (`/etc/resolv.conf`, the default route), not
+    /// the device's interface, so the ACL checks what the host's DNS answers.
+    async fn resolve_allowed(
+        &self,
+        username: &str,
+        requested_addr: &Address,
+    ) -> Result<SocketAddr> {
+        let (host, addrs) = match requested_addr {
+            Address::SocketAddress(addr) => (None, vec![*addr]),
+            Address::DomainAddress(domain, port) => {
+                let host = String::from_utf8(domain.clone())?;
+                let lookup = async {
+                    lookup_host((host.as_str(), *port))
+                        .await
+                        .map(|addrs| addrs.collect::<Vec<_>>())
+                        .map_err(|e| Socks5Error::Resolve(host.clone(), e))
+                };
+                let addrs = with_timeout(
+                    self.connect_timeout,
+                    lookup,
+                    Socks5Error::Resolve(host.clone(), io::ErrorKind::TimedOut.into()),
+                )
+                .await?;
+                (Some(host), addrs)
+            }
+        };
+
+        let acl = self.acl.get();
+        let mut denied = None;
+        for addr in addrs {
+            match acl.check(username, host.as_deref(), addr.ip(), addr.port()) {
+                Ok(()) => return Ok(addr),
+                Err(d) => denied = Some(d),
+            }
+        }
 
-        copy_bidirectional(&mut client, &mut outbound)
+        match denied {
+            Some(d) => {
+                warn!(self.logger, "destination denied";
+                    "user" => username,
+                    "target" => &d.target,
+                    "reason" => &d.reason,
+                );
+                Err(Socks5Error::DestinationDenied(d))
+            }
+            None => Err(Socks5Error::Resolve(
+                host.unwrap_or_default(),
+                io::ErrorKind::NotFound.into(),
+            )),
+        }
+    }
+}
+
+/// A failed copy, or the quota [`Metered`] cut the tunnel for.
+fn tunnel_error(e: io::Error) -> Socks5Error {
+    match e.get_ref().and_then(|e| e.downcast_ref::<QuotaExceeded>()) {
+        Some(exceeded) => Socks5Error::QuotaExceeded(exceeded.clone()),
+        None => Socks5Error::Tunnel(e),
+    }
+}
+
+/// Runs `fut`, failing with `err` if it has not completed by `deadline`.
+async fn until<T>(
+    deadline: Deadline,
+    fut: impl Future<Output = Result<T>>,
+    err: Socks5Error,
+) -> Result<T> {
+    match deadline {
+        Some(deadline) => timeout_at(deadline, fut).await.map_err(|_| err)?,
+        None => fut.await,
+    }
+}
+
+/// Runs `fut`, failing with `err` if it does not complete within `limit`.
+async fn with_timeout<T>(
+    limit: Option<Duration>,
+    fut: impl Future<Output = Result<T>>,
+    err: Socks5Error,
+) -> Result<T> {
+    match limit {
+        Some(limit) => timeout(limit, fut).await.map_err(|_| err)?,
+        None => fut.await,
+    }
+}
+
+/// Records when data last moved in either direction through the wrapped stream.
+struct Idle<S> {
+    inner: S,
+    started: Instant,
+    last_activity: Arc<AtomicU64>, // millis since `started`
+}
+
+impl<S> Idle<S> {
+    fn new(inner: S) -> Self {
+        Idle {
+            inner,
+            started: Instant::now(),
+            last_activity: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn touch(&self) {
+        self.last_activity
+            .store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Idle<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if buf.filled().len() > before {
+            self.touch();
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Idle<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            if n > 0 {
+                self.touch();
+            }
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Keeps `proxy_active_tunnels` up to date and records the tunnel duration on drop,
+/// so tunnels closed by the drain deadline are counted too.
+struct OpenTunnel {
+    active: IntGauge,
+    duration: Histogram,
+    started: Instant,
+}
+
+impl OpenTunnel {
+    fn new(labels: &[&str; 3]) -> Self {
+        let active = PROXY_ACTIVE_TUNNELS.with_label_values(labels);
+        active.inc();
+        OpenTunnel {
+            active,
+            duration: PROXY_TUNNEL_SECONDS.with_label_values(labels),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Drop for OpenTunnel {
+    fn drop(&mut self) {
+        self.active.dec();
+        self.duration.observe(self.started.elapsed().as_secs_f64());
+    }
+}
+
+/// Adds the bytes moving through the client side of a tunnel to `proxy_bytes_total`.
+struct Counted<S> {
+    inner: S,
+    bytes_in: IntCounter,
+    bytes_out: IntCounter,
+}
+
+impl<S> Counted<S> {
+    fn new(inner: S, labels: &[&str; 3]) -> Self {
+        let [device, user, fingerprint] = *labels;
+        Counted {
+            inner,
+            bytes_in: PROXY_BYTES.with_label_values(&[device, user, fingerprint, "in"]),
+            bytes_out: PROXY_BYTES.with_label_values(&[device, user, fingerprint, "out"]),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.bytes_in.inc_by((buf.filled().len() - before) as u64);
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.bytes_out.inc_by(n as u64);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::AclConfig;
+
+    fn proxy(acl: &str) -> Socks5 {
+        let acl: AclConfig = serde_json::from_str(acl).unwrap();
+        Socks5Builder::default()
+            .fingerprint(OsFingerprint::Linux)
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .iface_map(Arc::default())
+            .logger(Logger::root(slog::Discard, slog::o!()))
+            .acl(Arc::new(Live::new(AclPolicy::new(acl))))
+            .build()
+            .unwrap()
+    }
+
+    fn domain(host: &str, port: u16) -> Address {
+        Address::DomainAddress(host.as_bytes().to_vec(), port)
+    }
+
+    #[tokio::test]
+    async fn checks_resolved_addresses() {
+        // a name that passes the host rules is still refused for what it resolves to
+        let strict = proxy(
+            r#"{"default_deny": false, "rules": [
+                {"action": "deny", "cidrs": ["127.0.0.0/8", "::1/128"], "reason": "loopback"}
+            ]}"#,
+        );
+        assert!(strict.acl.get().check_host("u", "localhost", 80).is_ok());
+        match strict.resolve_allowed("u", &domain("localhost", 80)).await {
+            Err(Socks5Error::DestinationDenied(d)) => {
+                assert_eq!(d.reason, "loopback");
+                assert!(d.target.starts_with("localhost:80 ("), "{}", d.target);
+            }
+            other => panic!("expected a denial, got {:?}", other),
+        }
+
+        let open = proxy(r#"{"default_deny": false}"#);
+        let addr = open
+            .resolve_allowed("u", &domain("localhost", 80))
             .await
-            .map_err(Socks5Error::Connect)
+            .unwrap();
+        assert!(addr.ip().is_loopback());
+        assert_eq!(addr.port(), 80);
+
+        let literal = Address::SocketAddress("192.168.8.1:80".parse().unwrap());
+        assert!(matches!(
+            proxy("{}").resolve_allowed("u", &literal).await,
+            Err(Socks5Error::DestinationDenied(_))
+        ));
     }
 }
@@ -6,18 +6,34 @@ use std::{
     os::fd::AsRawFd,
 };
 use libc::{c_void, setsockopt, SOL_SOCKET, SO_BINDTODEVICE, SO_RCVBUF, SO_SNDBUF};
+use serde::Deserialize;
 use tokio::net::{TcpSocket, TcpStream};
 
 /// Which OS “fingerprint” to pretend to be
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum OsFingerprint {
     Windows,
     Linux,
     Android,
     MacOS,
+    #[allow(clippy::upper_case_acronyms)]
     IOS,
 }
 
+impl OsFingerprint {
+    /// Lowercase name, as accepted in the `-fingerprint-` username suffix.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OsFingerprint::Windows => "windows",
+            OsFingerprint::Linux => "linux",
+            OsFingerprint::Android => "android",
+            OsFingerprint::MacOS => "macos",
+            OsFingerprint::IOS => "ios",
+        }
+    }
+}
+
 pub async fn tcp_connect_with_fingerprint(
     remote_addr: SocketAddr,
     ifname: &str,
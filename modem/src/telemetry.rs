@@ -0,0 +1,290 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use lazy_static::lazy_static;
+use prometheus::{register_gauge_vec, GaugeVec};
+use slog::{debug, warn, Logger};
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::{
+    identity::{spawn_per_device, Interfaces},
+    modem::{Modems, SharedModem, Telemetry},
+};
+
+const LABELS: &[&str] = &["cluster", "device", "imei"];
+
+lazy_static! {
+    static ref MODEM_UP: GaugeVec = register_gauge_vec!(
+        "modem_up",
+        "1 if the modem's management API answered the last poll",
+        LABELS
+    )
+    .unwrap();
+    static ref MODEM_CONNECTED: GaugeVec = register_gauge_vec!(
+        "modem_connected",
+        "1 if the modem has a data connection",
+        LABELS
+    )
+    .unwrap();
+    static ref MODEM_INFO: GaugeVec = register_gauge_vec!(
+        "modem_info",
+        "Always 1; network type, operator and serving cell as labels",
+        &[
+            "cluster",
+            "device",
+            "imei",
+            "network_type",
+            "operator",
+            "cell_id"
+        ]
+    )
+    .unwrap();
+    static ref MODEM_RSRP: GaugeVec = register_gauge_vec!(
+        "modem_signal_rsrp_dbm",
+        "LTE reference signal received power",
+        LABELS
+    )
+    .unwrap();
+    static ref MODEM_RSRQ: GaugeVec = register_gauge_vec!(
+        "modem_signal_rsrq_db",
+        "LTE reference signal received quality",
+        LABELS
+    )
+    .unwrap();
+    static ref MODEM_SINR: GaugeVec = register_gauge_vec!(
+        "modem_signal_sinr_db",
+        "Signal to interference plus noise ratio",
+        LABELS
+    )
+    .unwrap();
+    static ref MODEM_RSSI: GaugeVec =
+        register_gauge_vec!("modem_signal_rssi_dbm", "Received signal strength", LABELS).unwrap();
+    static ref MODEM_UPLOAD: GaugeVec = register_gauge_vec!(
+        "modem_upload_bytes",
+        "Bytes uploaded as counted by the modem",
+        LABELS
+    )
+    .unwrap();
+    static ref MODEM_DOWNLOAD: GaugeVec = register_gauge_vec!(
+        "modem_download_bytes",
+        "Bytes downloaded as counted by the modem",
+        LABELS
+    )
+    .unwrap();
+    static ref MODEM_CONNECTED_SECONDS: GaugeVec = register_gauge_vec!(
+        "modem_connected_seconds",
+        "Age of the current data session",
+        LABELS
+    )
+    .unwrap();
+}
+
+/// Latest readings of every modem that answered its last poll, shared with the API.
+#[derive(Default)]
+pub struct TelemetryRegistry {
+    devices: RwLock<HashMap<String, Telemetry>>,
+}
+
+impl TelemetryRegistry {
+    pub fn get(&self, device: &str) -> Option<Telemetry> {
+        self.devices.read().unwrap().get(device).cloned()
+    }
+
+    fn set(&self, device: &str, telemetry: Option<Telemetry>) {
+        let mut devices = self.devices.write().unwrap();
+        match telemetry {
+            Some(t) => devices.insert(device.to_string(), t),
+            None => devices.remove(device),
+        };
+    }
+}
+
+/// Poll the management API of every connected device's modem through the handle the
+/// rest of the proxy uses (so they share one session), export the readings as gauges
+/// and keep them in `registry`. A device's series are dropped once it disconnects.
+pub fn spawn_modem_metrics_loop(
+    cluster: String,
+    interfaces: Arc<Interfaces>,
+    modems: Arc<Modems>,
+    every: Duration,
+    registry: Arc<TelemetryRegistry>,
+    logger: Logger,
+) {
+    spawn_per_device(interfaces, every, move |device| {
+        let modem = modems.get(device)?;
+        let exported = Exported {
+            cluster: cluster.clone(),
+            device: device.to_string(),
+            registry: registry.clone(),
+            last: None,
+        };
+        let logger = logger.new(slog::o!("device" => device.to_string()));
+        Some(poll(exported, modem, every, logger))
+    });
+}
+
+/// What one device's loop has exported; dropping it (the device disconnected and its
+/// loop was stopped) takes the series and the registry entry away.
+struct Exported {
+    cluster: String,
+    device: String,
+    registry: Arc<TelemetryRegistry>,
+    last: Option<Telemetry>,
+}
+
+impl Drop for Exported {
+    fn drop(&mut self) {
+        if let Some(prev) = &self.last {
+            clear(&self.cluster, &self.device, prev);
+        }
+        let imei = self
+            .last
+            .as_ref()
+            .map(|t| t.imei.as_str())
+            .unwrap_or_default();
+        let _ = MODEM_UP.remove_label_values(&[self.cluster.as_str(), &self.device, imei]);
+        self.registry.set(&self.device, None);
+    }
+}
+
+async fn poll(mut exported: Exported, modem: SharedModem, every: Duration, logger: Logger) {
+    let mut ticker = interval(every);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
+        let polled = modem
+            .lock()
+            .await
+            .telemetry()
+            .await
+            .map_err(|e| e.to_string());
+        let Exported {
+            cluster,
+            device,
+            registry,
+            last,
+        } = &mut exported;
+        match polled {
+            Ok(t) => {
+                if let Some(prev) = last.as_ref() {
+                    forget(cluster, device, prev, &t);
+                }
+                export(cluster, device, &t);
+                debug!(logger, "modem polled";
+                    "network" => &t.network_type,
+                    "rsrp" => ?t.rsrp_dbm,
+                    "connected" => t.connected,
+                );
+                registry.set(device, Some(t.clone()));
+                *last = Some(t);
+            }
+            Err(e) => {
+                let imei = last.as_ref().map(|t| t.imei.as_str()).unwrap_or_default();
+                MODEM_UP
+                    .with_label_values(&[cluster.as_str(), device, imei])
+                    .set(0.0);
+                if let Some(prev) = last.as_ref() {
+                    clear(cluster, device, prev);
+                }
+                registry.set(device, None);
+                warn!(logger, "poll modem"; "error" => e);
+            }
+        }
+    }
+}
+
+fn export(cluster: &str, device: &str, t: &Telemetry) {
+    let labels = [cluster, device, t.imei.as_str()];
+    let flag = |b: bool| if b { 1.0 } else { 0.0 };
+
+    MODEM_UP.with_label_values(&labels).set(1.0);
+    MODEM_CONNECTED
+        .with_label_values(&labels)
+        .set(flag(t.connected));
+    MODEM_INFO
+        .with_label_values(&info_labels(cluster, device, t))
+        .set(1.0);
+    for (gauge, value) in [
+        (&*MODEM_RSRP, t.rsrp_dbm),
+        (&*MODEM_RSRQ, t.rsrq_db),
+        (&*MODEM_SINR, t.sinr_db),
+        (&*MODEM_RSSI, t.rssi_dbm),
+    ] {
+        match value {
+            Some(v) => gauge.with_label_values(&labels).set(v),
+            // not reported on this network type; leave a gap instead of a stale value
+            None => {
+                let _ = gauge.remove_label_values(&labels);
+            }
+        }
+    }
+    MODEM_UPLOAD
+        .with_label_values(&labels)
+        .set(t.upload_bytes as f64);
+    MODEM_DOWNLOAD
+        .with_label_values(&labels)
+        .set(t.download_bytes as f64);
+    MODEM_CONNECTED_SECONDS
+        .with_label_values(&labels)
+        .set(t.connected_seconds as f64);
+}
+
+/// Drops series whose labels changed since the last poll: a new serving cell or
+/// operator, or a different stick (IMEI) on the same interface.
+fn forget(cluster: &str, device: &str, prev: &Telemetry, now: &Telemetry) {
+    let old_info = info_labels(cluster, device, prev);
+    if old_info != info_labels(cluster, device, now) {
+        let _ = MODEM_INFO.remove_label_values(&old_info);
+    }
+
+    if prev.imei != now.imei {
+        let labels = [cluster, device, prev.imei.as_str()];
+        for gauge in [
+            &*MODEM_UP,
+            &*MODEM_CONNECTED,
+            &*MODEM_RSRP,
+            &*MODEM_RSRQ,
+            &*MODEM_SINR,
+            &*MODEM_RSSI,
+            &*MODEM_UPLOAD,
+            &*MODEM_DOWNLOAD,
+            &*MODEM_CONNECTED_SECONDS,
+        ] {
+            let _ = gauge.remove_label_values(&labels);
+        }
+    }
+}
+
+/// Drops every reading but `modem_up` after a failed poll, so dashboards show a gap
+/// rather than the last values the modem reported.
+fn clear(cluster: &str, device: &str, prev: &Telemetry) {
+    let _ = MODEM_INFO.remove_label_values(&info_labels(cluster, device, prev));
+    let labels = [cluster, device, prev.imei.as_str()];
+    for gauge in [
+        &*MODEM_CONNECTED,
+        &*MODEM_RSRP,
+        &*MODEM_RSRQ,
+        &*MODEM_SINR,
+        &*MODEM_RSSI,
+        &*MODEM_UPLOAD,
+        &*MODEM_DOWNLOAD,
+        &*MODEM_CONNECTED_SECONDS,
+    ] {
+        let _ = gauge.remove_label_values(&labels);
+    }
+}
+
+fn info_labels<'a>(cluster: &'a str, device: &'a str, t: &'a Telemetry) -> [&'a str; 6] {
+    [
+        cluster,
+        device,
+        &t.imei,
+        &t.network_type,
+        &t.operator,
+        &t.cell_id,
+    ]
+}
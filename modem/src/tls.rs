@@ -0,0 +1,262 @@
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use rustls::{
+    crypto::ring::{default_provider, sign::any_supported_type},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    RootCertStore, ServerConfig,
+};
+use slog::{debug, error, info, Logger};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+};
+use tokio_rustls::server::TlsStream;
+pub use tokio_rustls::TlsAcceptor;
+
+/// How long a client gets to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A PEM certificate chain and key that can be swapped at runtime (e.g. on SIGHUP).
+pub struct ReloadableCert {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl fmt::Debug for ReloadableCert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReloadableCert")
+            .field("cert_path", &self.cert_path)
+            .field("key_path", &self.key_path)
+            .finish()
+    }
+}
+
+impl ReloadableCert {
+    pub fn load(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Result<Arc<Self>> {
+        let cert_path = cert_path.into();
+        let key_path = key_path.into();
+        let key = load_certified_key(&cert_path, &key_path)?;
+        Ok(Arc::new(ReloadableCert {
+            cert_path,
+            key_path,
+            current: RwLock::new(key),
+        }))
+    }
+
+    /// Re-read the files. On error the previous certificate keeps being served.
+    pub fn reload(&self) -> Result<()> {
+        let key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = key;
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<Arc<CertifiedKey>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|it| it.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("read certificate {}", cert_path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!(
+            "no certificate found in {}",
+            cert_path.display()
+        ));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("read private key {}", key_path.display()))?;
+    let signing_key = any_supported_type(&key)
+        .with_context(|| format!("unsupported private key {}", key_path.display()))?;
+    Ok(Arc::new(CertifiedKey::new(certs, signing_key)))
+}
+
+/// Build a TLS acceptor serving `cert`. With `client_ca` set, clients must present
+/// a certificate signed by it (mTLS).
+pub fn acceptor(cert: Arc<ReloadableCert>, client_ca: Option<&Path>) -> Result<TlsAcceptor> {
+    let provider = Arc::new(default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .context("TLS protocol versions")?;
+
+    let builder = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for ca in CertificateDer::pem_file_iter(path)
+                .with_context(|| format!("read client CA {}", path.display()))?
+            {
+                let ca = ca.with_context(|| format!("parse client CA {}", path.display()))?;
+                roots.add(ca).context("add client CA")?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .context("build client verifier")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder.with_cert_resolver(cert);
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Reload the given certificates whenever the process receives SIGHUP.
+pub fn spawn_reload_on_sighup(certs: Vec<Arc<ReloadableCert>>, logger: Logger) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup()).context("install SIGHUP handler")?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            for cert in &certs {
+                match cert.reload() {
+                    Ok(()) => info!(logger, "TLS certificate reloaded";
+                        "cert" => %cert.cert_path.display()),
+                    Err(e) => error!(logger, "TLS certificate reload failed";
+                        "cert" => %cert.cert_path.display(), "error" => %e),
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// A client connection, either in cleartext or TLS-terminated.
+pub enum MaybeTlsStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl MaybeTlsStream {
+    /// Complete the TLS handshake if `acceptor` is set; otherwise pass the stream through.
+    pub async fn accept(stream: TcpStream, acceptor: Option<&TlsAcceptor>) -> io::Result<Self> {
+        let Some(acceptor) = acceptor else {
+            return Ok(MaybeTlsStream::Plain(stream));
+        };
+        let tls = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))??;
+        Ok(MaybeTlsStream::Tls(Box::new(tls)))
+    }
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_flush(cx),
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// An axum listener that optionally terminates TLS. Handshakes run in their own
+/// tasks so a slow client can't hold up the accept loop.
+pub struct HttpListener {
+    local_addr: SocketAddr,
+    incoming: mpsc::Receiver<(MaybeTlsStream, SocketAddr)>,
+}
+
+impl HttpListener {
+    pub async fn bind(
+        addr: SocketAddr,
+        acceptor: Option<TlsAcceptor>,
+        logger: Logger,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let (tx, incoming) = mpsc::channel(64);
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        error!(logger, "accept failed"; "addr" => %local_addr, "error" => %e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                let tx = tx.clone();
+                let acceptor = acceptor.clone();
+                let logger = logger.clone();
+                tokio::spawn(async move {
+                    match MaybeTlsStream::accept(stream, acceptor.as_ref()).await {
+                        Ok(stream) => {
+                            let _ = tx.send((stream, peer)).await;
+                        }
+                        Err(e) => debug!(logger, "TLS handshake failed";
+                            "peer" => %peer, "error" => %e),
+                    }
+                });
+            }
+        });
+
+        Ok(HttpListener {
+            local_addr,
+            incoming,
+        })
+    }
+}
+
+impl axum::serve::Listener for HttpListener {
+    type Io = MaybeTlsStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.incoming.recv().await {
+            Some(conn) => conn,
+            // the accept task only stops if the runtime is shutting down
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
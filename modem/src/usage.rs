@@ -0,0 +1,573 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    pin::Pin,
+    sync::{Arc, Mutex, RwLock},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+use serde::{Deserialize, Serialize};
+use slog::{error, Logger};
+use time::OffsetDateTime;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::events::{EventBus, EventKind};
+
+/// Byte usage of a single user or device, split into the current day,
+/// the current month and the lifetime total.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub day: String,   // e.g. "2025-05-15"
+    pub month: String, // e.g. "2025-05"
+    pub daily_sent: u64,
+    pub daily_recv: u64,
+    pub monthly_sent: u64,
+    pub monthly_recv: u64,
+    pub total_sent: u64,
+    pub total_recv: u64,
+}
+
+impl Usage {
+    /// Reset the daily/monthly counters when the calendar moved on.
+    fn roll(&mut self, now: OffsetDateTime) {
+        let (day, month) = periods(now);
+        if self.day != day {
+            self.day = day;
+            self.daily_sent = 0;
+            self.daily_recv = 0;
+        }
+        if self.month != month {
+            self.month = month;
+            self.monthly_sent = 0;
+            self.monthly_recv = 0;
+        }
+    }
+
+    fn add(&mut self, sent: u64, recv: u64) {
+        self.roll(OffsetDateTime::now_utc());
+        self.daily_sent += sent;
+        self.daily_recv += recv;
+        self.monthly_sent += sent;
+        self.monthly_recv += recv;
+        self.total_sent += sent;
+        self.total_recv += recv;
+    }
+
+    pub fn daily(&self) -> u64 {
+        self.daily_sent + self.daily_recv
+    }
+
+    pub fn monthly(&self) -> u64 {
+        self.monthly_sent + self.monthly_recv
+    }
+}
+
+fn periods(now: OffsetDateTime) -> (String, String) {
+    let date = now.date();
+    (
+        format!(
+            "{:04}-{:02}-{:02}",
+            date.year(),
+            date.month() as u8,
+            date.day()
+        ),
+        format!("{:04}-{:02}", date.year(), date.month() as u8),
+    )
+}
+
+/// Daily and monthly byte limits (sent + received). `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Quota {
+    pub daily: Option<u64>,
+    pub monthly: Option<u64>,
+}
+
+impl Quota {
+    pub fn new(daily: u64, monthly: u64) -> Self {
+        Quota {
+            daily: (daily > 0).then_some(daily),
+            monthly: (monthly > 0).then_some(monthly),
+        }
+    }
+
+    /// The used-up period (`daily` or `monthly`) and the day or month it covers.
+    fn exhausted<'a>(&self, usage: &'a Usage) -> Option<(&'static str, &'a str)> {
+        if self.daily.is_some_and(|l| usage.daily() >= l) {
+            Some(("daily", &usage.day))
+        } else if self.monthly.is_some_and(|l| usage.monthly() >= l) {
+            Some(("monthly", &usage.month))
+        } else {
+            None
+        }
+    }
+}
+
+/// The quotas in force. Named users and devices may have their own; everyone
+/// else gets the `user` or `device` default.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Limits {
+    #[serde(default)]
+    pub user: Quota,
+    #[serde(default)]
+    pub device: Quota,
+    #[serde(default)]
+    pub users: HashMap<String, Quota>,
+    #[serde(default)]
+    pub devices: HashMap<String, Quota>,
+}
+
+impl Limits {
+    pub fn new(user: Quota, device: Quota) -> Self {
+        Limits {
+            user,
+            device,
+            ..Default::default()
+        }
+    }
+
+    pub fn user(&self, name: &str) -> Quota {
+        self.users.get(name).copied().unwrap_or(self.user)
+    }
+
+    pub fn device(&self, id: &str) -> Quota {
+        self.devices.get(id).copied().unwrap_or(self.device)
+    }
+
+    /// Reject zero limits, which would block everything; leaving a limit out means unlimited.
+    pub fn validate(&self) -> Result<()> {
+        let named = self.users.iter().map(|(n, q)| (format!("user `{}`", n), q));
+        let named = named.chain(self.devices.iter().map(|(d, q)| (format!("device `{}`", d), q)));
+        let defaults = [
+            ("user default".to_string(), &self.user),
+            ("device default".to_string(), &self.device),
+        ];
+        for (what, quota) in defaults.into_iter().chain(named) {
+            if quota.daily == Some(0) || quota.monthly == Some(0) {
+                return Err(anyhow::anyhow!(
+                    "quota for {}: limits must be greater than 0, leave them out for unlimited",
+                    what
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Which quota refused a new connection or ended an open tunnel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QuotaExceeded {
+    User(String),
+    Device(String),
+}
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuotaExceeded::User(name) => write!(f, "user `{}`", name),
+            QuotaExceeded::Device(id) => write!(f, "device `{}`", id),
+        }
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+type Entry = Arc<Mutex<Usage>>;
+
+#[derive(Default, Serialize, Deserialize)]
+struct Snapshot {
+    users: HashMap<String, Usage>,
+    devices: HashMap<String, Usage>,
+}
+
+/// Per-user and per-device traffic counters, optionally persisted to a JSON file.
+#[derive(Default)]
+pub struct UsageStore {
+    path: Option<PathBuf>,
+    limits: RwLock<Limits>,
+    users: Mutex<HashMap<String, Entry>>,
+    devices: Mutex<HashMap<String, Entry>>,
+    events: Arc<EventBus>,
+    /// Day or month each exhausted quota was last announced for, by `user:`/`device:` key
+    announced: Mutex<HashMap<String, String>>,
+}
+
+impl UsageStore {
+    /// Open the store at `path`, loading previously persisted counters if the file exists.
+    pub fn open(path: impl Into<PathBuf>, limits: Limits) -> Result<Self> {
+        let path = path.into();
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("create usage directory {}", dir.display()))?;
+        }
+        let snapshot: Snapshot = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .with_context(|| format!("parse usage file {}", path.display()))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Snapshot::default(),
+            Err(e) => return Err(e).with_context(|| format!("read usage file {}", path.display())),
+        };
+
+        let wrap = |m: HashMap<String, Usage>| {
+            m.into_iter()
+                .map(|(k, v)| (k, Arc::new(Mutex::new(v))))
+                .collect()
+        };
+
+        Ok(UsageStore {
+            path: Some(path),
+            limits: RwLock::new(limits),
+            users: Mutex::new(wrap(snapshot.users)),
+            devices: Mutex::new(wrap(snapshot.devices)),
+            events: Arc::default(),
+            announced: Mutex::default(),
+        })
+    }
+
+    /// Publish `quota_exhausted` to `events` the first time a quota refuses a connection in a period.
+    pub fn with_events(mut self, events: Arc<EventBus>) -> Self {
+        self.events = events;
+        self
+    }
+
+    fn entry(map: &Mutex<HashMap<String, Entry>>, key: &str) -> Entry {
+        map.lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .clone()
+    }
+
+    fn get(map: &Mutex<HashMap<String, Entry>>, key: &str) -> Option<Usage> {
+        let entry = map.lock().unwrap().get(key).cloned()?;
+        let mut usage = entry.lock().unwrap().clone();
+        usage.roll(OffsetDateTime::now_utc());
+        Some(usage)
+    }
+
+    pub fn user(&self, name: &str) -> Option<Usage> {
+        Self::get(&self.users, name)
+    }
+
+    pub fn device(&self, id: &str) -> Option<Usage> {
+        Self::get(&self.devices, id)
+    }
+
+    pub fn user_quota(&self, name: &str) -> Quota {
+        self.limits.read().unwrap().user(name)
+    }
+
+    pub fn device_quota(&self, id: &str) -> Quota {
+        self.limits.read().unwrap().device(id)
+    }
+
+    /// Replace the quotas; open tunnels keep running, new connections are checked
+    /// against the new limits.
+    pub fn set_limits(&self, limits: Limits) {
+        *self.limits.write().unwrap() = limits;
+    }
+
+    /// Refuse a new connection if either the user's or the device's quota is used up.
+    pub fn check_quota(&self, user: &str, device: &str) -> Result<(), QuotaExceeded> {
+        if let Some(usage) = self.user(user) {
+            if let Some((period, covers)) = self.user_quota(user).exhausted(&usage) {
+                self.announce("user", user, None, period, covers);
+                return Err(QuotaExceeded::User(user.to_string()));
+            }
+        }
+        if let Some(usage) = self.device(device) {
+            if let Some((period, covers)) = self.device_quota(device).exhausted(&usage) {
+                self.announce("device", device, Some(device), period, covers);
+                return Err(QuotaExceeded::Device(device.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    fn announce(
+        &self,
+        scope: &'static str,
+        name: &str,
+        device: Option<&str>,
+        period: &'static str,
+        covers: &str,
+    ) {
+        let key = format!("{}:{}", scope, name);
+        let mut announced = self.announced.lock().unwrap();
+        if announced.get(&key).is_some_and(|c| c == covers) {
+            return;
+        }
+        announced.insert(key, covers.to_string());
+        self.events.publish(
+            device,
+            EventKind::QuotaExhausted {
+                scope,
+                name: name.to_string(),
+                period,
+            },
+        );
+    }
+
+    /// Start accounting a tunnel; bytes are added while it is still open, and the
+    /// tunnel is cut once they use up a quota in force when it opened.
+    pub fn meter(&self, user: &str, device: &str) -> Meter {
+        Meter {
+            user: Self::entry(&self.users, user),
+            device: Self::entry(&self.devices, device),
+            user_name: user.to_string(),
+            device_id: device.to_string(),
+            user_quota: self.user_quota(user),
+            device_quota: self.device_quota(device),
+        }
+    }
+
+    /// Write all counters to the backing file (no-op for an in-memory store).
+    pub fn flush(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let unwrap = |m: &Mutex<HashMap<String, Entry>>| {
+            m.lock()
+                .unwrap()
+                .iter()
+                .map(|(k, v)| (k.clone(), v.lock().unwrap().clone()))
+                .collect()
+        };
+        let snapshot = Snapshot {
+            users: unwrap(&self.users),
+            devices: unwrap(&self.devices),
+        };
+
+        // write to a temp file and rename so a crash never leaves a truncated file
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&snapshot)?)
+            .with_context(|| format!("write usage file {}", tmp.display()))?;
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("rename usage file {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// Periodically persist the usage counters.
+pub fn spawn_usage_flush_loop(store: Arc<UsageStore>, interval: Duration, logger: Logger) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(e) = store.flush() {
+                error!(logger, "flush usage"; "error" => %e);
+            }
+        }
+    });
+}
+
+/// Live accounting handle for one tunnel.
+#[derive(Clone)]
+pub struct Meter {
+    user: Entry,
+    device: Entry,
+    user_name: String,
+    device_id: String,
+    user_quota: Quota,
+    device_quota: Quota,
+}
+
+impl Meter {
+    /// Count the bytes; fails once the user's or the device's quota is used up.
+    fn add(&self, sent: u64, recv: u64) -> Result<(), QuotaExceeded> {
+        let mut user = self.user.lock().unwrap();
+        user.add(sent, recv);
+        let mut device = self.device.lock().unwrap();
+        device.add(sent, recv);
+
+        if self.user_quota.exhausted(&user).is_some() {
+            return Err(QuotaExceeded::User(self.user_name.clone()));
+        }
+        if self.device_quota.exhausted(&device).is_some() {
+            return Err(QuotaExceeded::Device(self.device_id.clone()));
+        }
+        Ok(())
+    }
+}
+
+/// Wraps the client side of a tunnel: bytes read from it are counted as sent,
+/// bytes written to it as received. Once a quota is used up every further read
+/// and write fails with an `io::Error` wrapping [`QuotaExceeded`].
+pub struct Metered<S> {
+    inner: S,
+    meter: Meter,
+    exceeded: Option<QuotaExceeded>,
+}
+
+impl<S> Metered<S> {
+    pub fn new(inner: S, meter: Meter) -> Self {
+        Metered {
+            inner,
+            meter,
+            exceeded: None,
+        }
+    }
+
+    fn check(&self) -> io::Result<()> {
+        match &self.exceeded {
+            Some(e) => Err(io::Error::other(e.clone())),
+            None => Ok(()),
+        }
+    }
+
+    fn count(&mut self, sent: u64, recv: u64) {
+        if let Err(e) = self.meter.add(sent, recv) {
+            self.exceeded = Some(e);
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.check()?;
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - before;
+        if n > 0 {
+            self.count(n as u64, 0);
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.check()?;
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.count(0, n as u64);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::{Date, Month};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    fn at(year: i32, month: Month, day: u8) -> OffsetDateTime {
+        Date::from_calendar_date(year, month, day)
+            .unwrap()
+            .midnight()
+            .assume_utc()
+    }
+
+    fn quota_error(e: io::Error) -> QuotaExceeded {
+        e.into_inner()
+            .and_then(|e| e.downcast::<QuotaExceeded>().ok())
+            .map(|e| *e)
+            .expect("a quota error")
+    }
+
+    #[tokio::test]
+    async fn cuts_the_tunnel_at_the_quota() {
+        let store = UsageStore::default();
+        store.set_limits(Limits::new(Quota::new(100, 0), Quota::default()));
+        let (client, mut remote) = duplex(1024);
+        let mut metered = Metered::new(client, store.meter("alice", "dev"));
+
+        metered.write_all(&[0; 60]).await.unwrap();
+        remote.write_all(&[0; 30]).await.unwrap();
+        let mut buf = [0; 30];
+        metered.read_exact(&mut buf).await.unwrap();
+        assert!(store.check_quota("alice", "dev").is_ok());
+
+        // the write that crosses the limit goes through, everything after it fails
+        metered.write_all(&[0; 20]).await.unwrap();
+        let err = metered.write_all(&[0; 1]).await.unwrap_err();
+        assert_eq!(quota_error(err), QuotaExceeded::User("alice".to_string()));
+        remote.write_all(&[0; 1]).await.unwrap();
+        let err = metered.read(&mut buf).await.unwrap_err();
+        assert_eq!(quota_error(err), QuotaExceeded::User("alice".to_string()));
+
+        assert_eq!(store.user("alice").unwrap().daily(), 110);
+        assert_eq!(store.device("dev").unwrap().daily(), 110);
+        assert_eq!(
+            store.check_quota("alice", "dev"),
+            Err(QuotaExceeded::User("alice".to_string()))
+        );
+        // another user of the same device is not limited by alice's quota
+        assert!(store.check_quota("bob", "dev").is_ok());
+    }
+
+    #[test]
+    fn device_quota_cuts_every_user() {
+        let store = UsageStore::default();
+        store.set_limits(Limits::new(Quota::default(), Quota::new(0, 100)));
+        assert!(store.meter("alice", "dev").add(50, 0).is_ok());
+        assert_eq!(
+            store.meter("bob", "dev").add(0, 50),
+            Err(QuotaExceeded::Device("dev".to_string()))
+        );
+        assert_eq!(
+            store.check_quota("carol", "dev"),
+            Err(QuotaExceeded::Device("dev".to_string()))
+        );
+    }
+
+    #[test]
+    fn counters_reset_when_the_period_rolls_over() {
+        let mut usage = Usage::default();
+        usage.roll(at(2025, Month::May, 15));
+        usage.daily_sent = 10;
+        usage.daily_recv = 20;
+        usage.monthly_sent = 100;
+        usage.monthly_recv = 200;
+        usage.total_sent = 1000;
+        usage.total_recv = 2000;
+
+        usage.roll(at(2025, Month::May, 15));
+        assert_eq!((usage.daily(), usage.monthly()), (30, 300));
+
+        usage.roll(at(2025, Month::May, 16));
+        assert_eq!(usage.day, "2025-05-16");
+        assert_eq!((usage.daily(), usage.monthly()), (0, 300));
+
+        usage.roll(at(2025, Month::June, 1));
+        assert_eq!(usage.month, "2025-06");
+        assert_eq!((usage.daily(), usage.monthly()), (0, 0));
+        assert_eq!((usage.total_sent, usage.total_recv), (1000, 2000));
+    }
+
+    #[test]
+    fn exhausted_quota_lifts_on_a_new_day() {
+        let store = UsageStore::default();
+        store.set_limits(Limits::new(Quota::new(100, 0), Quota::default()));
+        let meter = store.meter("alice", "dev");
+        assert!(meter.add(100, 0).is_err());
+        assert!(store.check_quota("alice", "dev").is_err());
+
+        // pretend the bytes were counted yesterday
+        meter.user.lock().unwrap().day = "2000-01-01".to_string();
+        assert!(store.check_quota("alice", "dev").is_ok());
+        assert!(meter.add(10, 0).is_ok());
+        assert_eq!(store.user("alice").unwrap().daily(), 10);
+        assert_eq!(store.user("alice").unwrap().total_sent, 110);
+    }
+}
@@ -0,0 +1,327 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        RwLock,
+    },
+};
+
+use anyhow::{Context, Result};
+use ipnet::IpNet;
+use openssl::{memcmp, sha::sha256};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::acl::{canonical, SourceAllowlist};
+
+/// The built-in proxy account: authenticates with any device id as password.
+pub const DEFAULT_USER: &str = "modem";
+
+/// A proxy account.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct User {
+    #[serde(default)]
+    pub name: String,
+    /// Client addresses this user may connect from (empty = anywhere).
+    #[serde(default)]
+    pub allowed_sources: Vec<IpNet>,
+    /// Authenticate purely by source address: clients from `allowed_sources`
+    /// may skip USER/PASS and are routed through `device`.
+    #[serde(default)]
+    pub ip_auth: bool,
+    /// Device id used for IP-authenticated sessions.
+    #[serde(default)]
+    pub device: Option<String>,
+    /// Hex-encoded SHA-256 of the user's secret. When set, the proxy password
+    /// must be `<device id>:<secret>` instead of the bare device id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_sha256: Option<String>,
+}
+
+impl User {
+    fn allows(&self, ip: IpAddr) -> bool {
+        SourceAllowlist::new(self.allowed_sources.clone()).allows(ip)
+    }
+
+    /// Whether `secret` is what the user needs to send: nothing without a password.
+    fn verify(&self, secret: Option<&str>) -> bool {
+        match (&self.password_sha256, secret) {
+            (None, None) => true,
+            (Some(expected), Some(secret)) => hex::decode(expected)
+                .is_ok_and(|expected| memcmp::eq(&sha256(secret.as_bytes()), &expected[..])),
+            _ => false,
+        }
+    }
+}
+
+/// Why a user could not be created, changed or removed.
+#[derive(Debug, Error)]
+pub enum UserError {
+    #[error("{0:#}")]
+    Invalid(anyhow::Error),
+
+    /// The change was not applied.
+    #[error("save users: {0:#}")]
+    Save(anyhow::Error),
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct UsersFile {
+    #[serde(default)]
+    users: Vec<User>,
+}
+
+/// Known proxy accounts. The [`DEFAULT_USER`] account exists while no users are
+/// configured, or always with `default_user`, unless overridden.
+#[derive(Default)]
+pub struct UserStore {
+    path: Option<PathBuf>,
+    users: RwLock<HashMap<String, User>>,
+    /// Keep [`DEFAULT_USER`] next to the configured users
+    default_user: bool,
+    /// Users come from the config file's `[users]` section, which API writes would
+    /// not survive
+    read_only: AtomicBool,
+}
+
+impl UserStore {
+    pub fn new(users: Vec<User>) -> Self {
+        UserStore {
+            path: None,
+            users: RwLock::new(users.into_iter().map(|u| (u.name.clone(), u)).collect()),
+            default_user: false,
+            read_only: AtomicBool::new(false),
+        }
+    }
+
+    /// Keep the [`DEFAULT_USER`] account even when other users are configured.
+    pub fn with_default_user(mut self, enabled: bool) -> Self {
+        self.default_user = enabled;
+        self
+    }
+
+    /// Load accounts from a JSON file of the form `{"users": [...]}`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data =
+            std::fs::read(path).with_context(|| format!("read users file {}", path.display()))?;
+        let file: UsersFile = serde_json::from_slice(&data)
+            .with_context(|| format!("parse users file {}", path.display()))?;
+        let users =
+            by_name(file.users).with_context(|| format!("users file {}", path.display()))?;
+        Ok(UserStore {
+            path: Some(path.to_path_buf()),
+            users: RwLock::new(users),
+            ..UserStore::default()
+        })
+    }
+
+    /// Swap in a new set of users without persisting it, e.g. on a config reload.
+    /// Nothing changes if any of them is invalid.
+    pub fn replace(&self, users: Vec<User>) -> Result<()> {
+        *self.users.write().unwrap() = by_name(users)?;
+        Ok(())
+    }
+
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::Relaxed);
+    }
+
+    /// Whether users may only change through the config file.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// All configured users, sorted by name.
+    pub fn list(&self) -> Vec<User> {
+        let mut users: Vec<User> = self.users.read().unwrap().values().cloned().collect();
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        users
+    }
+
+    /// Create or replace a user. The change is persisted first and only then applied.
+    pub fn upsert(&self, user: User) -> Result<(), UserError> {
+        validate(&user).map_err(UserError::Invalid)?;
+        let mut users = self.users.write().unwrap();
+        let mut updated = users.clone();
+        updated.insert(user.name.clone(), user);
+        self.save(&updated).map_err(UserError::Save)?;
+        *users = updated;
+        Ok(())
+    }
+
+    /// Remove a user, persisting the change before applying it. Returns whether it existed.
+    pub fn remove(&self, name: &str) -> Result<bool, UserError> {
+        let mut users = self.users.write().unwrap();
+        if !users.contains_key(name) {
+            return Ok(false);
+        }
+        let mut updated = users.clone();
+        updated.remove(name);
+        self.save(&updated).map_err(UserError::Save)?;
+        *users = updated;
+        Ok(true)
+    }
+
+    /// Write `users` to the file the store was loaded from (no-op in memory).
+    fn save(&self, users: &HashMap<String, User>) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut users: Vec<User> = users.values().cloned().collect();
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        let file = UsersFile { users };
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&file)?)
+            .with_context(|| format!("write users file {}", tmp.display()))?;
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("rename users file {}", path.display()))?;
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<User> {
+        let users = self.users.read().unwrap();
+        users.get(name).cloned().or_else(|| {
+            let builtin = name == DEFAULT_USER && (self.default_user || users.is_empty());
+            builtin.then(|| User {
+                name: DEFAULT_USER.to_string(),
+                ..Default::default()
+            })
+        })
+    }
+
+    /// Whether `name` is a known user allowed to connect from `ip` with `secret`.
+    pub fn authorize(&self, name: &str, secret: Option<&str>, ip: IpAddr) -> bool {
+        self.get(name).is_some_and(|u| u.verify(secret) && u.allows(ip))
+    }
+
+    /// The IP-authenticated user whose sources include `ip`, if any. When sources
+    /// overlap, the user with the longest matching prefix wins, then the first by name.
+    pub fn ip_user(&self, ip: IpAddr) -> Option<User> {
+        let ip = canonical(ip);
+        self.users
+            .read()
+            .unwrap()
+            .values()
+            .filter(|u| u.ip_auth)
+            .filter_map(|u| {
+                let prefix = u
+                    .allowed_sources
+                    .iter()
+                    .filter(|net| net.contains(&ip))
+                    .map(|net| net.prefix_len())
+                    .max()?;
+                Some((prefix, u))
+            })
+            .max_by(|(a, u), (b, v)| a.cmp(b).then_with(|| v.name.cmp(&u.name)))
+            .map(|(_, u)| u.clone())
+    }
+}
+
+/// Validate `users` and index them by name; every name may appear only once.
+fn by_name(users: Vec<User>) -> Result<HashMap<String, User>> {
+    let mut named = HashMap::new();
+    for user in users {
+        validate(&user)?;
+        if let Some(user) = named.insert(user.name.clone(), user) {
+            return Err(anyhow::anyhow!("user `{}` is defined twice", user.name));
+        }
+    }
+    Ok(named)
+}
+
+fn validate(user: &User) -> Result<()> {
+    if user.name.is_empty() {
+        return Err(anyhow::anyhow!("user name must not be empty"));
+    }
+    if user.ip_auth && (user.device.is_none() || user.allowed_sources.is_empty()) {
+        return Err(anyhow::anyhow!(
+            "user `{}`: ip_auth needs both `device` and `allowed_sources`",
+            user.name
+        ));
+    }
+    if let Some(hash) = &user.password_sha256 {
+        if hex::decode(hash).map_or(true, |d| d.len() != 32) {
+            return Err(anyhow::anyhow!(
+                "user `{}`: password_sha256 must be 64 hex chars",
+                user.name
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip_user(name: &str, device: &str, sources: &[&str]) -> User {
+        User {
+            name: name.to_string(),
+            allowed_sources: sources.iter().map(|s| s.parse().unwrap()).collect(),
+            ip_auth: true,
+            device: Some(device.to_string()),
+            password_sha256: None,
+        }
+    }
+
+    /// Name of the user a client from `ip` is authenticated as.
+    fn matched(store: &UserStore, ip: &str) -> Option<String> {
+        store.ip_user(ip.parse().unwrap()).map(|u| u.name)
+    }
+
+    #[test]
+    fn most_specific_source_wins() {
+        let store = UserStore::new(vec![
+            ip_user("office", "dev-a", &["198.51.100.0/24"]),
+            ip_user("server", "dev-b", &["198.51.100.7/32"]),
+        ]);
+        assert_eq!(matched(&store, "198.51.100.7").as_deref(), Some("server"));
+        assert_eq!(matched(&store, "198.51.100.8").as_deref(), Some("office"));
+        assert_eq!(matched(&store, "203.0.113.1"), None);
+        // IPv4-mapped clients of a dual-stack listener match IPv4 sources
+        assert_eq!(
+            matched(&store, "::ffff:198.51.100.7").as_deref(),
+            Some("server")
+        );
+    }
+
+    #[test]
+    fn matches_ipv6_sources() {
+        let store = UserStore::new(vec![
+            ip_user("lab", "dev-a", &["2001:db8::/32"]),
+            ip_user("host", "dev-b", &["2001:db8:1::/48", "198.51.100.0/24"]),
+        ]);
+        assert_eq!(matched(&store, "2001:db8:1::5").as_deref(), Some("host"));
+        assert_eq!(matched(&store, "2001:db8:2::5").as_deref(), Some("lab"));
+        assert_eq!(matched(&store, "2001:db9::1"), None);
+    }
+
+    #[test]
+    fn ignores_users_without_ip_auth() {
+        let mut user = ip_user("office", "dev-a", &["198.51.100.0/24"]);
+        user.ip_auth = false;
+        let store = UserStore::new(vec![user]);
+        assert_eq!(matched(&store, "198.51.100.7"), None);
+    }
+
+    #[test]
+    fn rejects_duplicate_names_in_the_file() {
+        let path =
+            std::env::temp_dir().join(format!("proxymodem-users-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"users": [{"name": "alice"}, {"name": "bob"}, {"name": "alice"}]}"#,
+        )
+        .unwrap();
+        let err = UserStore::from_file(&path).err().unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(
+            format!("{:#}", err).contains("user `alice` is defined twice"),
+            "{:#}",
+            err
+        );
+    }
+}
@@ -0,0 +1,613 @@
+//! Admin subcommands: talk to a running instance's API, or straight to a modem for one-off jobs.
+
+use anyhow::{Context, Result};
+use clap::{Args, Subcommand, ValueEnum};
+use ipnet::IpNet;
+use modem::{
+    auth::{hash_token, read_secret_file},
+    modem::Modem,
+    modem_huaweie337::HuaweiE337,
+};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, PercentEncode, utf8_percent_encode};
+use reqwest::{Method, StatusCode};
+use serde_json::{Value, json};
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// List, reboot and rotate devices
+    Devices {
+        #[command(flatten)]
+        client: ClientArgs,
+        #[command(subcommand)]
+        command: DevicesCommand,
+    },
+    /// Send and read text messages
+    Sms {
+        #[command(flatten)]
+        client: ClientArgs,
+        #[command(flatten)]
+        direct: DirectArgs,
+        #[command(subcommand)]
+        command: SmsCommand,
+    },
+    /// Run a USSD code such as `*100#` and print the network's reply
+    Ussd {
+        #[command(flatten)]
+        client: ClientArgs,
+        #[command(flatten)]
+        direct: DirectArgs,
+        /// Device id or alias (not needed with --iface)
+        #[arg(long, required_unless_present = "iface")]
+        device: Option<String>,
+        code: String,
+    },
+    /// Manage proxy users
+    Users {
+        #[command(flatten)]
+        client: ClientArgs,
+        #[command(subcommand)]
+        command: UsersCommand,
+    },
+    /// Traffic and quotas of a user, a device, or every device
+    Usage {
+        #[command(flatten)]
+        client: ClientArgs,
+        #[arg(long, conflicts_with = "device")]
+        user: Option<String>,
+        #[arg(long)]
+        device: Option<String>,
+    },
+    /// Validate the flags and the config, users, ACL, rotation, tokens and TLS files, then exit
+    CheckConfig,
+    /// Check privileges, routes, interfaces, modem APIs and connectivity of this rig
+    Diagnose {
+        /// `http://` echo endpoint for the test connections (default: --health-check-url,
+        /// else http://api.ipify.org/)
+        #[arg(long, default_value = "")]
+        url: String,
+    },
+}
+
+impl Command {
+    /// Whether the command talks to a running instance or a single modem, rather
+    /// than needing the server's flags and config file.
+    pub fn is_client(&self) -> bool {
+        !matches!(self, Command::CheckConfig | Command::Diagnose { .. })
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DevicesCommand {
+    List,
+    Reboot { device: String },
+    /// Rotate the IP now: drain open tunnels, then reconnect per the device's policy
+    Rotate { device: String },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SmsCommand {
+    /// Inbox messages, newest first
+    List {
+        /// Device id or alias (not needed with --iface)
+        #[arg(long, required_unless_present = "iface")]
+        device: Option<String>,
+        #[arg(long, default_value = "20")]
+        count: u32,
+    },
+    Send {
+        /// Device id or alias (not needed with --iface)
+        #[arg(long, required_unless_present = "iface")]
+        device: Option<String>,
+        to: String,
+        text: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum UsersCommand {
+    List,
+    /// Create a user; fails if it exists
+    Add {
+        name: String,
+        /// Client CIDR the user may connect from; repeat for more (none = anywhere)
+        #[arg(long = "allowed-source")]
+        allowed_sources: Vec<IpNet>,
+        /// Authenticate by source address alone and route through --device
+        #[arg(long, requires = "device")]
+        ip_auth: bool,
+        #[arg(long)]
+        device: Option<String>,
+    },
+    Remove { name: String },
+    /// Set the user's secret to a random one and print it; proxy passwords become
+    /// `<device id>:<secret>`. Secrets are stored as plain SHA-256, so only generated
+    /// ones are accepted.
+    Passwd {
+        name: String,
+        /// Generate a random secret and print it
+        #[arg(long, required_unless_present = "clear", conflicts_with = "clear")]
+        generate: bool,
+        /// Remove the password, so the bare device id is accepted again
+        #[arg(long)]
+        clear: bool,
+    },
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Output {
+    Table,
+    Json,
+}
+
+/// How to reach a running instance and how to print its answers.
+#[derive(Args, Debug)]
+pub struct ClientArgs {
+    /// Base URL of the instance's API
+    #[arg(
+        long,
+        env = "PROXYMODEM_API_URL",
+        default_value = "http://127.0.0.1:4444",
+        global = true
+    )]
+    api_url: String,
+
+    /// API bearer token; visible in `ps`, prefer the env var or the file
+    #[arg(
+        long,
+        env = "PROXYMODEM_API_TOKEN",
+        hide_env_values = true,
+        default_value = "",
+        global = true
+    )]
+    api_token: String,
+
+    /// File holding the API bearer token
+    #[arg(
+        long,
+        env = "PROXYMODEM_API_TOKEN_FILE",
+        default_value = "",
+        global = true
+    )]
+    api_token_file: String,
+
+    #[arg(long, default_value = "table", global = true)]
+    output: Output,
+}
+
+/// Talk to a modem directly instead of through the API, e.g. before the proxy runs.
+#[derive(Args, Debug)]
+pub struct DirectArgs {
+    /// Reach the modem through this interface instead of asking the API
+    #[arg(long, default_value = "", global = true)]
+    iface: String,
+
+    #[arg(
+        long,
+        env = "IP_MODEM_API",
+        default_value = "192.168.8.1",
+        global = true
+    )]
+    ip_modem_api: String,
+
+    #[arg(
+        long,
+        env = "TIMEOUT_MODEM_API",
+        default_value = "30",
+        global = true
+    )]
+    timeout_modem_api: u64,
+}
+
+impl DirectArgs {
+    async fn modem(&self) -> Result<Option<HuaweiE337>> {
+        if self.iface.is_empty() {
+            return Ok(None);
+        }
+        let mut modem = HuaweiE337::new(self.ip_modem_api.clone(), self.timeout_modem_api)
+            .with_interface(&self.iface);
+        modem
+            .init()
+            .await
+            .with_context(|| format!("modem on {}", self.iface))?;
+        Ok(Some(modem))
+    }
+}
+
+/// Run an admin subcommand other than `check-config` and `diagnose`.
+pub async fn run(command: Command) -> Result<()> {
+    match command {
+        Command::Devices { client, command } => devices(&client, command).await,
+        Command::Sms {
+            client,
+            direct,
+            command,
+        } => sms(&client, &direct, command).await,
+        Command::Ussd {
+            client,
+            direct,
+            device,
+            code,
+        } => {
+            let reply = match direct.modem().await? {
+                Some(mut modem) => modem.ussd(&code).await.map_err(|e| anyhow::anyhow!("{e}"))?,
+                None => {
+                    let device = device.unwrap_or_default();
+                    let path = format!("/api/v1/devices/{}/ussd", segment(&device));
+                    let reply = client
+                        .call(Method::POST, &path, Some(json!({ "code": code })))
+                        .await?;
+                    text(&reply, "/reply")
+                }
+            };
+            println!("{}", reply);
+            Ok(())
+        }
+        Command::Users { client, command } => users(&client, command).await,
+        Command::Usage {
+            client,
+            user,
+            device,
+        } => usage(&client, user, device).await,
+        Command::CheckConfig | Command::Diagnose { .. } => {
+            unreachable!("check-config and diagnose run in main")
+        }
+    }
+}
+
+async fn devices(client: &ClientArgs, command: DevicesCommand) -> Result<()> {
+    match command {
+        DevicesCommand::List => {
+            let devices = client.call(Method::GET, "/api/v1/devices", None).await?;
+            client.print(&devices, |devices| {
+                table(
+                    &[
+                        "ID", "INTERFACE", "IP", "EXIT IP", "HEALTHY", "OPERATOR", "NETWORK",
+                        "CONNS",
+                    ],
+                    rows(devices, |d| {
+                        vec![
+                            text(d, "/id"),
+                            text(d, "/name"),
+                            text(d, "/ip"),
+                            text(d, "/exit_ip"),
+                            text(d, "/health/healthy"),
+                            text(d, "/operator"),
+                            text(d, "/signal/network_type"),
+                            text(d, "/active_connections"),
+                        ]
+                    }),
+                )
+            });
+        }
+        DevicesCommand::Reboot { device } => {
+            let path = format!("/api/v1/devices/{}/reboot", segment(&device));
+            let reply = client.call(Method::POST, &path, None).await?;
+            client.print(&reply, |r| println!("{}", text(r, "/message")));
+        }
+        DevicesCommand::Rotate { device } => {
+            let path = format!("/api/v1/devices/{}/rotate", segment(&device));
+            let reply = client.call(Method::POST, &path, None).await?;
+            client.print(&reply, |_| {
+                println!("rotation of {} requested; it starts within seconds", device)
+            });
+        }
+    }
+    Ok(())
+}
+
+async fn sms(client: &ClientArgs, direct: &DirectArgs, command: SmsCommand) -> Result<()> {
+    let modem = direct.modem().await?;
+    match command {
+        SmsCommand::List { device, count } => {
+            let messages = match modem {
+                Some(mut modem) => {
+                    let messages = modem
+                        .list_sms(count)
+                        .await
+                        .map_err(|e| anyhow::anyhow!("{e}"))?;
+                    serde_json::to_value(messages)?
+                }
+                None => {
+                    let path = format!(
+                        "/api/v1/devices/{}/sms?count={}",
+                        device.unwrap_or_default(),
+                        count
+                    );
+                    client.call(Method::GET, &path, None).await?
+                }
+            };
+            client.print(&messages, |messages| {
+                table(
+                    &["INDEX", "FROM", "DATE", "UNREAD", "CONTENT"],
+                    rows(messages, |m| {
+                        vec![
+                            text(m, "/index"),
+                            text(m, "/phone"),
+                            text(m, "/date"),
+                            text(m, "/unread"),
+                            text(m, "/content").replace('\n', " "),
+                        ]
+                    }),
+                )
+            });
+        }
+        SmsCommand::Send { device, to, text } => {
+            match modem {
+                Some(mut modem) => modem
+                    .send_sms(&to, &text)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("{e}"))?,
+                None => {
+                    let path = format!(
+                        "/api/v1/devices/{}/sms",
+                        segment(&device.unwrap_or_default())
+                    );
+                    let body = json!({ "recipient": to, "content": text });
+                    client.call(Method::POST, &path, Some(body)).await?;
+                }
+            }
+            println!("SMS sent to {}", to);
+        }
+    }
+    Ok(())
+}
+
+async fn users(client: &ClientArgs, command: UsersCommand) -> Result<()> {
+    match command {
+        UsersCommand::List => {
+            let users = client.call(Method::GET, "/api/v1/users", None).await?;
+            client.print(&users, |users| {
+                table(
+                    &["NAME", "ALLOWED SOURCES", "IP AUTH", "DEVICE", "PASSWORD"],
+                    rows(users, |u| {
+                        let sources = u["allowed_sources"]
+                            .as_array()
+                            .map(|s| s.iter().map(|s| text(s, "")).collect::<Vec<_>>())
+                            .unwrap_or_default();
+                        vec![
+                            text(u, "/name"),
+                            if sources.is_empty() {
+                                "any".to_string()
+                            } else {
+                                sources.join(",")
+                            },
+                            text(u, "/ip_auth"),
+                            text(u, "/device"),
+                            u.get("password_sha256").is_some().to_string(),
+                        ]
+                    }),
+                )
+            });
+        }
+        UsersCommand::Add {
+            name,
+            allowed_sources,
+            ip_auth,
+            device,
+        } => {
+            if client.user(&name).await?.is_some() {
+                return Err(anyhow::anyhow!("user `{}` already exists", name));
+            }
+            let user = json!({
+                "name": name,
+                "allowed_sources": allowed_sources,
+                "ip_auth": ip_auth,
+                "device": device,
+            });
+            client.put_user(&name, user).await?;
+            println!("user {} added", name);
+        }
+        UsersCommand::Remove { name } => {
+            let path = format!("/api/v1/users/{}", segment(&name));
+            client.call(Method::DELETE, &path, None).await?;
+            println!("user {} removed", name);
+        }
+        UsersCommand::Passwd { name, clear, .. } => {
+            let mut user = client
+                .user(&name)
+                .await?
+                .with_context(|| format!("user `{}` not found", name))?;
+            let secret = if clear {
+                None
+            } else {
+                let mut bytes = [0u8; 16];
+                openssl::rand::rand_bytes(&mut bytes)?;
+                Some(hex::encode(bytes))
+            };
+            user["password_sha256"] = secret.as_deref().map(hash_token).into();
+            client.put_user(&name, user).await?;
+            match secret {
+                Some(secret) => println!("{}", secret),
+                None => println!("password of {} removed", name),
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn usage(client: &ClientArgs, user: Option<String>, device: Option<String>) -> Result<()> {
+    let (kind, entries) = match (user, device) {
+        (Some(user), _) => ("USER", vec![user]),
+        (_, Some(device)) => ("DEVICE", vec![device]),
+        (None, None) => {
+            let devices = client.call(Method::GET, "/api/v1/devices", None).await?;
+            let ids = devices
+                .as_array()
+                .map(|d| d.iter().map(|d| text(d, "/id")).collect())
+                .unwrap_or_default();
+            ("DEVICE", ids)
+        }
+    };
+
+    let mut usages = serde_json::Map::new();
+    for name in entries {
+        let path = match kind {
+            "USER" => format!("/api/v1/users/{}/usage", segment(&name)),
+            _ => format!("/api/v1/devices/{}/usage", segment(&name)),
+        };
+        usages.insert(name, client.call(Method::GET, &path, None).await?);
+    }
+
+    client.print(&Value::Object(usages), |usages| {
+        let total = |u: &Value, sent: &str, recv: &str| {
+            bytes(u[sent].as_u64().unwrap_or_default() + u[recv].as_u64().unwrap_or_default())
+        };
+        let quota = |q: &Value| q.as_u64().map_or("-".to_string(), bytes);
+        table(
+            &[kind, "TODAY", "MONTH", "TOTAL", "DAILY QUOTA", "MONTHLY QUOTA"],
+            usages
+                .as_object()
+                .into_iter()
+                .flatten()
+                .map(|(name, u)| {
+                    vec![
+                        name.clone(),
+                        total(u, "daily_sent", "daily_recv"),
+                        total(u, "monthly_sent", "monthly_recv"),
+                        total(u, "total_sent", "total_recv"),
+                        quota(&u["quota"]["daily"]),
+                        quota(&u["quota"]["monthly"]),
+                    ]
+                })
+                .collect(),
+        )
+    });
+    Ok(())
+}
+
+impl ClientArgs {
+    fn token(&self) -> Result<String> {
+        if !self.api_token_file.is_empty() {
+            return read_secret_file(&self.api_token_file);
+        }
+        Ok(self.api_token.clone())
+    }
+
+    /// Call the API and return its JSON answer; error responses become errors.
+    async fn call(&self, method: Method, path: &str, body: Option<Value>) -> Result<Value> {
+        let (status, value) = self.send(method.clone(), path, body).await?;
+        if !status.is_success() {
+            let message = value["error"]
+                .as_str()
+                .map_or_else(|| value.to_string(), str::to_string);
+            return Err(anyhow::anyhow!("{} {}: {} {}", method, path, status, message));
+        }
+        Ok(value)
+    }
+
+    /// The status and JSON body (`null` if there is none) of an API call.
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<(StatusCode, Value)> {
+        let url = format!("{}{}", self.api_url.trim_end_matches('/'), path);
+        let mut request = reqwest::Client::new().request(method.clone(), &url);
+        let token = self.token()?;
+        if !token.is_empty() {
+            request = request.bearer_auth(token);
+        }
+        if let Some(body) = body {
+            request = request
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_vec(&body)?);
+        }
+
+        let response = request
+            .send()
+            .await
+            .with_context(|| format!("{} {}", method, url))?;
+        let status = response.status();
+        let data = response.bytes().await?;
+        Ok((status, serde_json::from_slice(&data).unwrap_or(Value::Null)))
+    }
+
+    async fn user(&self, name: &str) -> Result<Option<Value>> {
+        let path = format!("/api/v1/users/{}", segment(name));
+        let (status, _) = self.send(Method::GET, &path, None).await?;
+        if status == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        self.call(Method::GET, &path, None).await.map(Some)
+    }
+
+    async fn put_user(&self, name: &str, user: Value) -> Result<()> {
+        let path = format!("/api/v1/users/{}", segment(name));
+        self.call(Method::PUT, &path, Some(user)).await?;
+        Ok(())
+    }
+
+    fn print(&self, value: &Value, table: impl FnOnce(&Value)) {
+        match self.output {
+            Output::Json => println!(
+                "{}",
+                serde_json::to_string_pretty(value).unwrap_or_default()
+            ),
+            Output::Table => table(value),
+        }
+    }
+}
+
+/// Characters left as they are in a URL path segment (RFC 3986 unreserved).
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// `value` escaped for use as one path segment, so names with `/`, `?` or `#` stay intact.
+fn segment(value: &str) -> PercentEncode<'_> {
+    utf8_percent_encode(value, PATH_SEGMENT)
+}
+
+/// The value at `pointer` for a table cell: strings as they are, missing or null as `-`.
+fn text(value: &Value, pointer: &str) -> String {
+    match value.pointer(pointer) {
+        None | Some(Value::Null) => "-".to_string(),
+        Some(Value::String(s)) => s.clone(),
+        Some(other) => other.to_string(),
+    }
+}
+
+fn rows(list: &Value, row: impl Fn(&Value) -> Vec<String>) -> Vec<Vec<String>> {
+    list.as_array().into_iter().flatten().map(row).collect()
+}
+
+/// Print left-aligned columns as wide as their widest cell.
+pub(crate) fn table(headers: &[&str], rows: Vec<Vec<String>>) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", padded.join("  ").trim_end());
+    };
+    line(headers.to_vec());
+    for row in &rows {
+        line(row.iter().map(String::as_str).collect());
+    }
+}
+
+/// Bytes in binary units, e.g. `1.5 GiB`.
+fn bytes(n: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", n)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
@@ -0,0 +1,271 @@
+//! `proxymodem diagnose`: the checks a new rig has to pass before it can serve traffic.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use get_if_addrs::get_if_addrs;
+use modem::{
+    config::{DeviceSettings, ModemDriver},
+    device::get_default_interface,
+    health::{ProbeTarget, probe_exit_ip},
+    identity::DeviceIds,
+    modem_huaweie337::HuaweiE337,
+    tcp::OsFingerprint,
+};
+
+use crate::cli::table;
+
+/// Capability bit `SO_BINDTODEVICE` needs, see `capabilities(7)`.
+const CAP_NET_RAW: u32 = 13;
+
+/// What the checks need from the flags and the config file.
+pub struct Rig {
+    pub ids: DeviceIds,
+    /// `[devices]` settings by current device id
+    pub settings: HashMap<String, DeviceSettings>,
+    /// `--ip-modem-api`, for devices without a `modem_host`
+    pub modem_host: String,
+    pub modem_timeout_secs: u64,
+    pub probe: ProbeTarget,
+    pub probe_timeout: Duration,
+    pub fingerprint: OsFingerprint,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Pass,
+    Fail,
+    Skip,
+}
+
+struct Check {
+    outcome: Outcome,
+    name: &'static str,
+    subject: String,
+    detail: String,
+}
+
+#[derive(Default)]
+struct Report(Vec<Check>);
+
+impl Report {
+    fn add(&mut self, name: &'static str, subject: &str, result: Result<String, String>) {
+        let (outcome, detail) = match result {
+            Ok(detail) => (Outcome::Pass, detail),
+            Err(detail) => (Outcome::Fail, detail),
+        };
+        self.push(outcome, name, subject, detail);
+    }
+
+    fn skip(&mut self, name: &'static str, subject: &str, why: impl Into<String>) {
+        self.push(Outcome::Skip, name, subject, why.into());
+    }
+
+    fn push(&mut self, outcome: Outcome, name: &'static str, subject: &str, detail: String) {
+        self.0.push(Check {
+            outcome,
+            name,
+            subject: subject.to_string(),
+            detail,
+        });
+    }
+
+    fn count(&self, outcome: Outcome) -> usize {
+        self.0.iter().filter(|c| c.outcome == outcome).count()
+    }
+
+    fn print(&self) {
+        table(
+            &["RESULT", "CHECK", "SUBJECT", "DETAIL"],
+            self.0
+                .iter()
+                .map(|c| {
+                    let outcome = match c.outcome {
+                        Outcome::Pass => "PASS",
+                        Outcome::Fail => "FAIL",
+                        Outcome::Skip => "SKIP",
+                    };
+                    vec![
+                        outcome.to_string(),
+                        c.name.to_string(),
+                        c.subject.clone(),
+                        c.detail.clone(),
+                    ]
+                })
+                .collect(),
+        );
+        println!(
+            "\n{} passed, {} failed, {} skipped",
+            self.count(Outcome::Pass),
+            self.count(Outcome::Fail),
+            self.count(Outcome::Skip)
+        );
+    }
+}
+
+/// Run every check, print the report and fail if any check did.
+pub async fn run(rig: Rig) -> Result<()> {
+    let mut report = Report::default();
+
+    report.add("privileges", "process", privileges());
+    report.add(
+        "default route",
+        "host",
+        get_default_interface()
+            .map(|iface| format!("via {}", iface))
+            .map_err(|e| format!("{}; add one, the API needs it to list devices", e)),
+    );
+
+    let names = modem_interfaces();
+    if names.is_empty() {
+        report.add(
+            "interfaces",
+            "host",
+            Err("no enx* interface; is the stick plugged in and in HiLink (network) mode?".into()),
+        );
+    }
+    let addresses = addresses().unwrap_or_default();
+    let routes = routes().unwrap_or_default();
+    let ids: HashMap<String, String> = rig
+        .ids
+        .interfaces()
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(id, ifname)| (ifname, id))
+        .collect();
+
+    for ifname in &names {
+        let address = addresses.get(ifname).cloned();
+        report.add(
+            "address",
+            ifname,
+            address
+                .map(|ip| ip.to_string())
+                .ok_or_else(|| "no IPv4 address; check DHCP on the interface".to_string()),
+        );
+        if address.is_none() {
+            report.skip("route", ifname, "no address");
+            report.skip("modem API", ifname, "no address");
+            report.skip("connection", ifname, "no address");
+            continue;
+        }
+        let has_route = routes.get(ifname).is_some_and(|n| *n > 0);
+        report.add(
+            "route",
+            ifname,
+            match routes.get(ifname) {
+                Some(n) if *n > 0 => Ok(format!("{} route(s)", n)),
+                _ => Err("no route in /proc/net/route".to_string()),
+            },
+        );
+
+        let id = ids.get(ifname);
+        report.add(
+            "device id",
+            ifname,
+            id.cloned()
+                .ok_or_else(|| "none derived; see --device-id-strategy".to_string()),
+        );
+        let settings = id.and_then(|id| rig.settings.get(id)).cloned().unwrap_or_default();
+        if settings.driver == ModemDriver::None {
+            report.skip("modem API", ifname, "driver = \"none\"");
+        } else {
+            let host = settings.modem_host.unwrap_or_else(|| rig.modem_host.clone());
+            let mut modem =
+                HuaweiE337::new(host.clone(), rig.modem_timeout_secs).with_interface(ifname);
+            report.add(
+                "modem API",
+                ifname,
+                modem
+                    .init()
+                    .await
+                    .map(|_| format!("{} answers SesTokInfo", host))
+                    .map_err(|e| format!("{}: {:#}", host, e)),
+            );
+        }
+
+        if !has_route {
+            report.skip("connection", ifname, "no route");
+            continue;
+        }
+        let started = Instant::now();
+        report.add(
+            "connection",
+            ifname,
+            probe_exit_ip(&rig.probe, ifname, rig.fingerprint, rig.probe_timeout)
+                .await
+                .map(|ip| {
+                    format!(
+                        "exit IP {} in {} ms",
+                        ip,
+                        started.elapsed().as_millis()
+                    )
+                })
+                .map_err(|e| e.to_string()),
+        );
+    }
+
+    report.print();
+    let failed = report.count(Outcome::Fail);
+    if failed > 0 {
+        return Err(anyhow::anyhow!("{} check(s) failed", failed));
+    }
+    Ok(())
+}
+
+/// Whether the process may bind sockets to an interface.
+fn privileges() -> Result<String, String> {
+    let status = std::fs::read_to_string("/proc/self/status").map_err(|e| e.to_string())?;
+    let caps = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .ok_or("no CapEff in /proc/self/status")?;
+    if caps & (1 << CAP_NET_RAW) == 0 {
+        return Err(
+            "no CAP_NET_RAW, so SO_BINDTODEVICE fails; run as root or grant the capability"
+                .into(),
+        );
+    }
+    Ok("CAP_NET_RAW for SO_BINDTODEVICE".into())
+}
+
+/// Modem interfaces the kernel knows about, up or not.
+fn modem_interfaces() -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir("/sys/class/net")
+        .map(|dir| {
+            dir.filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .filter(|name| name.starts_with("enx"))
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+/// The IPv4 address of every interface that has one.
+fn addresses() -> Result<HashMap<String, IpAddr>> {
+    Ok(get_if_addrs()
+        .context("list interface addresses")?
+        .into_iter()
+        .filter(|iface| iface.ip().is_ipv4())
+        .map(|iface| (iface.name.clone(), iface.ip()))
+        .collect())
+}
+
+/// The number of IPv4 routes through each interface.
+fn routes() -> Result<HashMap<String, usize>> {
+    let data = std::fs::read_to_string("/proc/net/route").context("read /proc/net/route")?;
+    let mut routes = HashMap::new();
+    for line in data.lines().skip(1) {
+        if let Some(iface) = line.split_whitespace().next() {
+            *routes.entry(iface.to_string()).or_default() += 1;
+        }
+    }
+    Ok(routes)
+}
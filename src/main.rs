@@ -1,22 +1,63 @@
 use anyhow::Result;
-use axum::{Router, routing::get};
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use ipnet::IpNet;
 use modem::{
-    api::{API, list_interfaces},
+    acl::SourceAllowlist,
+    api::API,
+    auth::{BasicAuth, HttpAuth, Role, TokenStore, read_secret_file},
+    config::{ConfigFile, DeviceSettings, ModemDriver, ReloaderBuilder},
+    events::{EventBus, spawn_device_watch_loop},
+    health::{HealthCheckerBuilder, HealthRegistry, ProbeTarget},
+    identity::{DeviceAliases, DeviceIdsBuilder, IdStrategy, interface_id},
+    ip_history::{IpHistory, spawn_ip_history_flush_loop},
     jemalloc::spawn_allocator_metrics_loop,
+    live::Live,
     metrics::start_metrics_server,
+    modem::{Modems, SharedModem},
     modem_huaweie337::HuaweiE337,
-    socks5::{Socks5Builder},
+    rotation::{RotationRegistry, RotatorBuilder},
+    shutdown::{CancellationToken, cancel_on_signal},
+    sms::{SmsAction, SmsPollerBuilder},
+    telemetry::{TelemetryRegistry, spawn_modem_metrics_loop},
+    socks5::Socks5Builder,
+    tls::{ReloadableCert, TlsAcceptor, acceptor, spawn_reload_on_sighup},
+    upstream::Upstreams,
+    usage::{Limits, Quota, UsageStore, spawn_usage_flush_loop},
+    users::UserStore,
+};
+use slog::{Drain, FnValue, Logger, PushFnValue, Record, error, info, o, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
-use slog::{Drain, FnValue, Logger, PushFnValue, Record, error, info, o};
-use std::{net::SocketAddr, sync::Arc};
 use tikv_jemallocator::Jemalloc;
-use tokio::{net::TcpListener, sync::Mutex};
+use tokio::sync::Mutex;
 use modem::tcp::OsFingerprint;
 
+mod cli;
+mod diagnose;
+
+use cli::Command;
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Config {
+    /// Admin command to run against a running instance instead of serving
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// TOML file with flag values, per-device settings, users, ACL, limits and rotation
+    /// policies; flags and env vars take precedence over its values
+    #[clap(long, env = "CONFIG_FILE", default_value = "")]
+    config: String,
+
+    /// Seconds between checks of the config, users, ACL and rotation files for changes (0 = SIGHUP only)
+    #[clap(long, env = "CONFIG_WATCH_INTERVAL", default_value = "5")]
+    config_watch_interval: u64,
+
     #[clap(long, env = "CLUSTER", default_value = "ua-1")]
     cluster: String,
 
@@ -29,6 +70,78 @@ struct Config {
     #[clap(long, env = "TIMEOUT_MODEM_API", default_value = "30")]
     timeout_modem_api: u64,
 
+    /// What device ids are derived from: interface, mac, usb-path or imei
+    #[clap(long, env = "DEVICE_ID_STRATEGY", default_value = "interface")]
+    device_id_strategy: IdStrategy,
+
+    /// JSON file mapping old device ids to current ones, still accepted as passwords
+    #[clap(long, env = "DEVICE_ALIASES_FILE", default_value = "")]
+    device_aliases_file: String,
+
+    /// Seconds between checks for added or removed modem interfaces (0 = no device events)
+    #[clap(long, env = "DEVICE_WATCH_INTERVAL", default_value = "5")]
+    device_watch_interval: u64,
+
+    /// Seconds between signal/traffic polls of each modem (0 = no modem metrics)
+    #[clap(long, env = "MODEM_METRICS_INTERVAL", default_value = "15")]
+    modem_metrics_interval: u64,
+
+    /// `http://` endpoint answering with the caller's IP, probed through each device (empty = no health checks)
+    #[clap(long, env = "HEALTH_CHECK_URL", default_value = "")]
+    health_check_url: String,
+
+    /// Seconds between health probes of each device
+    #[clap(long, env = "HEALTH_CHECK_INTERVAL", default_value = "30")]
+    health_check_interval: u64,
+
+    /// Seconds a health probe may take
+    #[clap(long, env = "HEALTH_CHECK_TIMEOUT", default_value = "10")]
+    health_check_timeout: u64,
+
+    /// Consecutive failed probes before a device is taken out of routing and its modem rebooted
+    #[clap(long, env = "HEALTH_CHECK_FAILURES", default_value = "3")]
+    health_check_failures: u32,
+
+    /// Seconds to wait after a reboot before the next one; doubles up to an hour
+    #[clap(long, env = "HEALTH_REBOOT_BACKOFF", default_value = "60")]
+    health_reboot_backoff: u64,
+
+    /// JSON file with per-device and per-pool IP rotation policies (empty = no scheduled rotation)
+    #[clap(long, env = "ROTATION_FILE", default_value = "")]
+    rotation_file: String,
+
+    /// File where each device's exit IPs persist; written with the usage counters
+    #[clap(
+        long,
+        env = "IP_HISTORY_FILE",
+        default_value = "/var/lib/proxymodem/ip_history.json"
+    )]
+    ip_history_file: String,
+
+    /// URLs every inbound SMS is POSTed to as JSON (empty = no SMS polling)
+    #[clap(long, env = "SMS_WEBHOOKS", value_delimiter = ',')]
+    sms_webhooks: Vec<String>,
+
+    /// File holding the HMAC-SHA256 key for the `X-Signature-256` webhook header (empty = unsigned)
+    #[clap(long, env = "SMS_WEBHOOK_SECRET_FILE", default_value = "")]
+    sms_webhook_secret_file: String,
+
+    /// Seconds between inbox polls of each modem
+    #[clap(long, env = "SMS_POLL_INTERVAL", default_value = "10")]
+    sms_poll_interval: u64,
+
+    /// What to do with a delivered SMS on the modem: keep, read or delete
+    #[clap(long, env = "SMS_AFTER_DELIVERY", default_value = "read")]
+    sms_after_delivery: SmsAction,
+
+    /// File where SMS that no webhook accepted are appended as JSON lines
+    #[clap(
+        long,
+        env = "SMS_DEAD_LETTER_FILE",
+        default_value = "/var/lib/proxymodem/sms_dead_letter.jsonl"
+    )]
+    sms_dead_letter_file: String,
+
     #[clap(long, env = "PORT_API", default_value = "4444")]
     port_api: u16,
 
@@ -41,8 +154,128 @@ struct Config {
     #[clap(long, env = "PROMETHEUS_USERNAME", default_value = "")]
     prometheus_username: String,
 
+    /// Plain-text metrics password; visible in `ps`, prefer the file or hash variants
     #[clap(long, env = "PROMETHEUS_PASSWORD", default_value = "")]
     prometheus_password: String,
+
+    /// File holding the metrics password
+    #[clap(long, env = "PROMETHEUS_PASSWORD_FILE", default_value = "")]
+    prometheus_password_file: String,
+
+    /// Hex SHA-256 of the metrics password
+    #[clap(long, env = "PROMETHEUS_PASSWORD_SHA256", default_value = "")]
+    prometheus_password_sha256: String,
+
+    /// JSON file with bearer tokens accepted for scraping (same format as the API tokens file)
+    #[clap(long, env = "PROMETHEUS_TOKENS_FILE", default_value = "")]
+    prometheus_tokens_file: String,
+
+    #[clap(long, env = "USAGE_FILE", default_value = "/var/lib/proxymodem/usage.json")]
+    usage_file: String,
+
+    #[clap(long, env = "USAGE_FLUSH_INTERVAL", default_value = "30")]
+    usage_flush_interval: u64,
+
+    /// Daily per-user quota in bytes (0 = unlimited)
+    #[clap(long, env = "QUOTA_USER_DAILY_BYTES", default_value = "0")]
+    quota_user_daily_bytes: u64,
+
+    /// Monthly per-user quota in bytes (0 = unlimited)
+    #[clap(long, env = "QUOTA_USER_MONTHLY_BYTES", default_value = "0")]
+    quota_user_monthly_bytes: u64,
+
+    /// Daily per-device quota in bytes (0 = unlimited)
+    #[clap(long, env = "QUOTA_DEVICE_DAILY_BYTES", default_value = "0")]
+    quota_device_daily_bytes: u64,
+
+    /// Monthly per-device quota in bytes (0 = unlimited)
+    #[clap(long, env = "QUOTA_DEVICE_MONTHLY_BYTES", default_value = "0")]
+    quota_device_monthly_bytes: u64,
+
+    /// JSON file with destination ACL rules (empty = built-in deny-list only)
+    #[clap(long, env = "ACL_FILE", default_value = "")]
+    acl_file: String,
+
+    /// JSON file with proxy users (source allowlists, IP-authenticated users)
+    #[clap(long, env = "USERS_FILE", default_value = "")]
+    users_file: String,
+
+    /// Keep the built-in `modem` user (any device id as password) when users are configured
+    #[clap(long, env = "DEFAULT_USER")]
+    default_user: bool,
+
+    /// JSON file mapping device ids to upstream proxies (`socks5://` or `http://` URLs)
+    #[clap(long, env = "UPSTREAMS_FILE", default_value = "")]
+    upstreams_file: String,
+
+    /// Client CIDRs allowed to connect to the SOCKS5 listener (empty = anyone)
+    #[clap(long, env = "ALLOWED_SOURCES", value_delimiter = ',')]
+    allowed_sources: Vec<IpNet>,
+
+    /// Also accept HTTP CONNECT on the cleartext proxy port (always on for the TLS port)
+    #[clap(long, env = "PROXY_HTTP_CONNECT")]
+    proxy_http_connect: bool,
+
+    /// Load balancer CIDRs whose connections may start with a PROXY protocol v1/v2 header
+    #[clap(long, env = "PROXY_PROTOCOL_TRUSTED", value_delimiter = ',')]
+    proxy_protocol_trusted: Vec<IpNet>,
+
+    /// JSON file with hashed API tokens and roles; required unless --api-insecure
+    #[clap(long, env = "API_TOKENS_FILE", default_value = "")]
+    api_tokens_file: String,
+
+    /// Serve the API without authentication when no tokens file is set; anyone who can
+    /// reach it may reboot modems, send SMS and edit users
+    #[clap(long, env = "API_INSECURE")]
+    api_insecure: bool,
+
+    /// Client CIDRs allowed to call the HTTP API (empty = anyone)
+    #[clap(long, env = "API_ALLOWED_SOURCES", value_delimiter = ',')]
+    api_allowed_sources: Vec<IpNet>,
+
+    /// Seconds from accept until a client's proxy request is read, PROXY header, TLS and auth included (0 = unlimited)
+    #[clap(long, env = "TIMEOUT_SOCKS5_HANDSHAKE", default_value = "10")]
+    timeout_socks5_handshake: u64,
+
+    /// Seconds to wait for the outbound connection over the modem (0 = kernel default)
+    #[clap(long, env = "TIMEOUT_SOCKS5_CONNECT", default_value = "10")]
+    timeout_socks5_connect: u64,
+
+    /// Seconds without traffic before a tunnel is closed (0 = never)
+    #[clap(long, env = "TIMEOUT_SOCKS5_IDLE", default_value = "300")]
+    timeout_socks5_idle: u64,
+
+    /// PEM certificate chain for the TLS listeners (reloaded on SIGHUP)
+    #[clap(long, env = "TLS_CERT", default_value = "")]
+    tls_cert: String,
+
+    /// PEM private key for the TLS listeners (reloaded on SIGHUP)
+    #[clap(long, env = "TLS_KEY", default_value = "")]
+    tls_key: String,
+
+    /// Serve the API over HTTPS
+    #[clap(long, env = "API_TLS")]
+    api_tls: bool,
+
+    /// PEM CA bundle; API clients must present a certificate signed by it (mTLS)
+    #[clap(long, env = "API_TLS_CLIENT_CA", default_value = "")]
+    api_tls_client_ca: String,
+
+    /// Serve metrics over HTTPS
+    #[clap(long, env = "PROMETHEUS_TLS")]
+    prometheus_tls: bool,
+
+    /// PEM CA bundle; scrapers must present a certificate signed by it (mTLS)
+    #[clap(long, env = "PROMETHEUS_TLS_CLIENT_CA", default_value = "")]
+    prometheus_tls_client_ca: String,
+
+    /// Seconds open tunnels may keep running after SIGTERM/SIGINT (0 = until they finish)
+    #[clap(long, env = "DRAIN_TIMEOUT", default_value = "30")]
+    drain_timeout: u64,
+
+    /// Port for the TLS-wrapped proxy listener: SOCKS5 over TLS or HTTPS CONNECT (0 = disabled)
+    #[clap(long, env = "PORT_PROXY_TLS", default_value = "0")]
+    port_proxy_tls: u16,
 }
 
 #[cfg(not(target_env = "msvc"))]
@@ -51,9 +284,222 @@ static GLOBAL: Jemalloc = Jemalloc;
 
 const DEFAULT_FINGERPRINT: OsFingerprint = OsFingerprint::Windows;
 
+/// Seconds from a CLI flag, where 0 disables the timeout.
+fn seconds(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// A path from a CLI flag, where an empty string means unset.
+fn path(flag: &str) -> Option<PathBuf> {
+    (!flag.is_empty()).then(|| PathBuf::from(flag))
+}
+
+/// Parse the flags, falling back to the `--config` file's values for those not given
+/// on the command line or in the environment, and then to the built-in defaults.
+fn parse_config() -> Result<(Config, Option<ConfigFile>)> {
+    let cfg = Config::parse();
+    // admin commands only read their own flags
+    let client = cfg.command.as_ref().is_some_and(Command::is_client);
+    if cfg.config.is_empty() || client {
+        return Ok((cfg, None));
+    }
+    let file = ConfigFile::load(&cfg.config)?;
+
+    let mut command = Config::command();
+    let known: HashSet<String> = command
+        .get_arguments()
+        .map(|arg| arg.get_id().to_string())
+        .collect();
+    for (name, values) in file.flags()? {
+        if name == "config" || !known.contains(&name) {
+            return Err(anyhow::anyhow!(
+                "config file {}: unknown setting `{}`",
+                cfg.config,
+                name
+            ));
+        }
+        command = command.mut_arg(name, |arg| arg.default_values(values));
+    }
+    // the command line already parsed, so errors here come from the file's values
+    let matches = command.try_get_matches().map_err(|e| {
+        let rendered = e.render().to_string();
+        let message = rendered.lines().next().unwrap_or_default();
+        anyhow::anyhow!(
+            "config file {}: {}",
+            cfg.config,
+            message.trim_start_matches("error: ")
+        )
+    })?;
+    Ok((Config::from_arg_matches(&matches)?, Some(file)))
+}
+
+fn load_tokens(path: &str) -> Result<TokenStore> {
+    if path.is_empty() {
+        return Ok(TokenStore::default());
+    }
+    TokenStore::from_file(path)
+}
+
+/// The shared certificate, loaded only if some listener uses TLS.
+fn load_cert(cfg: &Config) -> Result<Option<Arc<ReloadableCert>>> {
+    if !cfg.api_tls && !cfg.prometheus_tls && cfg.port_proxy_tls == 0 {
+        return Ok(None);
+    }
+    if cfg.tls_cert.is_empty() || cfg.tls_key.is_empty() {
+        return Err(anyhow::anyhow!(
+            "TLS listeners need --tls-cert and --tls-key"
+        ));
+    }
+    Ok(Some(ReloadableCert::load(&cfg.tls_cert, &cfg.tls_key)?))
+}
+
+/// A TLS acceptor for one listener when `enabled`, with optional client-certificate CA.
+fn listener_tls(
+    cert: &Option<Arc<ReloadableCert>>,
+    enabled: bool,
+    client_ca: &str,
+) -> Result<Option<TlsAcceptor>> {
+    match cert {
+        Some(cert) if enabled => {
+            let client_ca = (!client_ca.is_empty()).then(|| Path::new(client_ca));
+            Ok(Some(acceptor(cert.clone(), client_ca)?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Bearer tokens for the API. Having none is an error unless `--api-insecure` opts into an open API.
+fn api_auth(cfg: &Config) -> Result<HttpAuth> {
+    let auth = HttpAuth::new(None, Arc::new(load_tokens(&cfg.api_tokens_file)?));
+    if !auth.is_enabled() && !cfg.api_insecure {
+        return Err(anyhow::anyhow!(
+            "the API has no credentials: set --api-tokens-file, or --api-insecure to serve it open"
+        ));
+    }
+    Ok(auth)
+}
+
+/// Basic credentials (hash, file or plain password) plus optional bearer tokens for `/metrics`.
+fn metrics_auth(cfg: &Config) -> Result<HttpAuth> {
+    let username = &cfg.prometheus_username;
+    let basic = if username.is_empty() {
+        None
+    } else if !cfg.prometheus_password_sha256.is_empty() {
+        Some(BasicAuth::from_sha256(
+            username,
+            &cfg.prometheus_password_sha256,
+            Role::Read,
+        )?)
+    } else if !cfg.prometheus_password_file.is_empty() {
+        let password = read_secret_file(&cfg.prometheus_password_file)?;
+        Some(BasicAuth::new(username, &password, Role::Read))
+    } else if !cfg.prometheus_password.is_empty() {
+        Some(BasicAuth::new(username, &cfg.prometheus_password, Role::Read))
+    } else {
+        None
+    };
+
+    let tokens = load_tokens(&cfg.prometheus_tokens_file)?;
+    Ok(HttpAuth::new(basic, Arc::new(tokens)))
+}
+
+/// Load every file the flags point at, the way startup would, without touching
+/// the usage or IP history files or any device.
+fn check_config(cfg: &Config) -> Result<()> {
+    let logger = Logger::root(slog::Discard, o!());
+    let limits = Limits::new(
+        Quota::new(cfg.quota_user_daily_bytes, cfg.quota_user_monthly_bytes),
+        Quota::new(cfg.quota_device_daily_bytes, cfg.quota_device_monthly_bytes),
+    );
+    let users = Arc::new(if cfg.users_file.is_empty() {
+        UserStore::default()
+    } else {
+        UserStore::from_file(&cfg.users_file)?
+    });
+    ReloaderBuilder::default()
+        .config(path(&cfg.config))
+        .users_file(path(&cfg.users_file))
+        .acl_file(path(&cfg.acl_file))
+        .rotation_file(path(&cfg.rotation_file))
+        .limits(limits)
+        .users(users)
+        .acl(Arc::new(Live::default()))
+        .usage(Arc::new(UsageStore::default()))
+        .rotation(Arc::new(Live::default()))
+        .logger(logger)
+        .watch_interval(None)
+        .build()
+        .expect("invalid reloader configuration")
+        .load()?;
+
+    if !cfg.device_aliases_file.is_empty() {
+        DeviceAliases::from_file(&cfg.device_aliases_file)?;
+    }
+    if !cfg.upstreams_file.is_empty() {
+        Upstreams::from_file(&cfg.upstreams_file)?;
+    }
+    api_auth(cfg)?;
+    metrics_auth(cfg)?;
+    let cert = load_cert(cfg)?;
+    listener_tls(&cert, cfg.api_tls, &cfg.api_tls_client_ca)?;
+    listener_tls(&cert, cfg.prometheus_tls, &cfg.prometheus_tls_client_ca)?;
+    if !cfg.sms_webhook_secret_file.is_empty() {
+        read_secret_file(&cfg.sms_webhook_secret_file)?;
+    }
+
+    println!("configuration OK");
+    Ok(())
+}
+
+/// Run the rig checks with the interfaces, modems and probe startup would use.
+async fn run_diagnose(cfg: &Config, config_file: Option<ConfigFile>, url: &str) -> Result<()> {
+    let url = [url, cfg.health_check_url.as_str()]
+        .into_iter()
+        .find(|u| !u.is_empty())
+        .unwrap_or("http://api.ipify.org/");
+    let aliases = if cfg.device_aliases_file.is_empty() {
+        DeviceAliases::default()
+    } else {
+        DeviceAliases::from_file(&cfg.device_aliases_file)?
+    };
+    let ids = DeviceIdsBuilder::default()
+        .strategy(cfg.device_id_strategy)
+        .aliases(aliases)
+        .modem_host(cfg.ip_modem_api.clone())
+        .timeout_secs(cfg.timeout_modem_api)
+        .logger(Logger::root(slog::Discard, o!()))
+        .build()
+        .expect("invalid device id configuration");
+    let settings = config_file
+        .map(|file| {
+            file.devices
+                .into_iter()
+                .map(|(id, settings)| (ids.resolve(&id).to_string(), settings))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    diagnose::run(diagnose::Rig {
+        ids,
+        settings,
+        modem_host: cfg.ip_modem_api.clone(),
+        modem_timeout_secs: cfg.timeout_modem_api,
+        probe: url.parse().map_err(|e: String| anyhow::anyhow!(e))?,
+        probe_timeout: Duration::from_secs(cfg.health_check_timeout.max(1)),
+        fingerprint: DEFAULT_FINGERPRINT,
+    })
+    .await
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let cfg = Config::parse();
+    let (mut cfg, config_file) = parse_config()?;
+    match cfg.command.take() {
+        Some(Command::CheckConfig) => return check_config(&cfg),
+        Some(Command::Diagnose { url }) => return run_diagnose(&cfg, config_file, &url).await,
+        Some(command) => return cli::run(command).await,
+        None => {}
+    }
 
     let drain = slog_json::Json::new(std::io::stdout())
         .add_key_value(o!(
@@ -88,17 +534,160 @@ async fn main() -> Result<()> {
 
     let api_addr = SocketAddr::from(([0, 0, 0, 0], cfg.port_api));
 
-    let mut modem_huaweie337 = HuaweiE337::new(cfg.ip_modem_api, cfg.timeout_modem_api);
-    modem_huaweie337.init().await?;
+    // SIGTERM/SIGINT stops the proxies first; the API and metrics stay up while they drain
+    let shutdown = CancellationToken::new();
+    cancel_on_signal(shutdown.clone(), logger.clone())?;
+    let api_shutdown = CancellationToken::new();
+
+    let events = Arc::new(EventBus::default());
+
+    let limits = Limits::new(
+        Quota::new(cfg.quota_user_daily_bytes, cfg.quota_user_monthly_bytes),
+        Quota::new(cfg.quota_device_daily_bytes, cfg.quota_device_monthly_bytes),
+    );
+    let usage = Arc::new(
+        UsageStore::open(&cfg.usage_file, limits.clone())?.with_events(events.clone()),
+    );
+    spawn_usage_flush_loop(
+        usage.clone(),
+        Duration::from_secs(cfg.usage_flush_interval),
+        logger.clone(),
+    );
+
+    let ip_history = Arc::new(IpHistory::open(&cfg.ip_history_file)?);
+    spawn_ip_history_flush_loop(
+        ip_history.clone(),
+        Duration::from_secs(cfg.usage_flush_interval),
+        logger.clone(),
+    );
+
+    // loaded from the users file so API changes are written back to it
+    let users = Arc::new(
+        if cfg.users_file.is_empty() {
+            UserStore::default()
+        } else {
+            UserStore::from_file(&cfg.users_file)?
+        }
+        .with_default_user(cfg.default_user),
+    );
+    let acl = Arc::new(Live::default());
+    let rotation_policies = Arc::new(Live::default());
+
+    let reloader = ReloaderBuilder::default()
+        .config(path(&cfg.config))
+        .users_file(path(&cfg.users_file))
+        .acl_file(path(&cfg.acl_file))
+        .rotation_file(path(&cfg.rotation_file))
+        .limits(limits)
+        .users(users.clone())
+        .acl(acl.clone())
+        .usage(usage.clone())
+        .rotation(rotation_policies.clone())
+        .logger(logger.clone())
+        .watch_interval(seconds(cfg.config_watch_interval))
+        .build()
+        .expect("invalid reloader configuration");
+    reloader.load()?;
+    reloader.spawn()?;
+
+    let api_auth = api_auth(&cfg)?;
+    let metrics_auth = metrics_auth(&cfg)?;
+
+    let cert = load_cert(&cfg)?;
+    let api_tls = listener_tls(&cert, cfg.api_tls, &cfg.api_tls_client_ca)?;
+    let metrics_tls = listener_tls(&cert, cfg.prometheus_tls, &cfg.prometheus_tls_client_ca)?;
+    let proxy_tls = listener_tls(&cert, cfg.port_proxy_tls > 0, "")?;
+    if let Some(cert) = &cert {
+        spawn_reload_on_sighup(vec![cert.clone()], logger.clone())?;
+    }
+
+    let health = Arc::new(HealthRegistry::default());
+    let rotation = Arc::new(RotationRegistry::default());
+    let telemetry = Arc::new(TelemetryRegistry::default());
+
+    let aliases = if cfg.device_aliases_file.is_empty() {
+        DeviceAliases::default()
+    } else {
+        DeviceAliases::from_file(&cfg.device_aliases_file)?
+    };
+    let ids = Arc::new(
+        DeviceIdsBuilder::default()
+            .strategy(cfg.device_id_strategy)
+            .aliases(aliases.clone())
+            .modem_host(cfg.ip_modem_api.clone())
+            .timeout_secs(cfg.timeout_modem_api)
+            .logger(logger.clone())
+            .build()
+            .expect("invalid device id configuration"),
+    );
+
+    let ifaces = ids.interfaces().await?;
+    for (id, ifname) in &ifaces {
+        // the interface-based id is what to alias when moving to another strategy
+        info!(logger, "Device found";
+            "device" => id,
+            "iface" => ifname,
+            "interface_id" => interface_id(ifname),
+        );
+    }
+
+    // kept current by the device watch loop, read by the proxy, the API and the rotator
+    let interfaces = Arc::new(Live::new(ifaces.clone()));
+
+    // `[devices]` sections of the config file, keyed by current id
+    let device_settings: HashMap<String, DeviceSettings> = config_file
+        .map(|file| {
+            file.devices
+                .into_iter()
+                .map(|(id, settings)| (ids.resolve(&id).to_string(), settings))
+                .collect()
+        })
+        .unwrap_or_default();
+    for id in device_settings.keys().filter(|id| !ifaces.contains_key(*id)) {
+        warn!(logger, "config for unknown device"; "device" => id);
+    }
+    // one handle per stick, shared by the API, health, rotation, telemetry and SMS, so
+    // they keep one HiLink session; sticks plugged in later get one on first use
+    let modems = Arc::new(Modems::new(interfaces.clone(), {
+        let interfaces = interfaces.clone();
+        let settings = device_settings.clone();
+        let default_host = cfg.ip_modem_api.clone();
+        let timeout = cfg.timeout_modem_api;
+        move |id: &str| {
+            let settings = settings.get(id).cloned().unwrap_or_default();
+            if settings.driver == ModemDriver::None {
+                return None;
+            }
+            let host = settings.modem_host.unwrap_or_else(|| default_host.clone());
+            let modem =
+                HuaweiE337::new(host.clone(), timeout).with_interfaces(interfaces.clone(), id);
+            let modem: SharedModem = Arc::new(Mutex::new(modem));
+            Some((format!("http://{}/api", host), modem))
+        }
+    }));
 
     let api = API::builder()
-        .modem(Arc::new(Mutex::new(modem_huaweie337)))
+        .ids(ids.clone())
+        .interfaces(interfaces.clone())
         .addr(api_addr)
         .logger(Option::from(logger.clone()))
+        .usage(usage.clone())
+        .allowed_sources(SourceAllowlist::new(cfg.api_allowed_sources.clone()))
+        .users(users.clone())
+        .auth(Arc::new(api_auth))
+        .insecure(cfg.api_insecure)
+        .tls(api_tls)
+        .shutdown(api_shutdown.clone())
+        .health(health.clone())
+        .rotation(rotation.clone())
+        .ip_history(ip_history.clone())
+        .telemetry(telemetry.clone())
+        .modems(modems.clone())
+        .events(events.clone())
         .build()
         .expect("build API");
 
-    tokio::spawn(async move {
+    let api_task = tokio::spawn(async move {
         api.run().await.expect("API run error");
     });
     info!(logger, "API Started"; "addr" => %api_addr);
@@ -108,25 +697,172 @@ async fn main() -> Result<()> {
 
     let prometheus_addr = SocketAddr::from(([0, 0, 0, 0], cfg.port_prometheus));
 
-    let shutdown_metrics = start_metrics_server(
-        prometheus_addr,
-        cfg.prometheus_username,
-        cfg.prometheus_password,
-        logger.clone(),
-    )
-    .await;
+    let shutdown_metrics =
+        start_metrics_server(prometheus_addr, metrics_auth, metrics_tls, logger.clone()).await?;
 
     info!(logger, "Prometheus Started"; "addr" => %prometheus_addr);
 
-    let ifaces = list_interfaces();
+    if let Some(every) = seconds(cfg.device_watch_interval) {
+        spawn_device_watch_loop(
+            ids.clone(),
+            interfaces.clone(),
+            events.clone(),
+            every,
+            logger.clone(),
+        );
+    }
+
+    if let Some(every) = seconds(cfg.modem_metrics_interval) {
+        spawn_modem_metrics_loop(
+            cfg.cluster.clone(),
+            interfaces.clone(),
+            modems.clone(),
+            every,
+            telemetry,
+            logger.clone(),
+        );
+        info!(logger, "Modem metrics loop started");
+    }
+
+    let probe_target: Option<ProbeTarget> = if cfg.health_check_url.is_empty() {
+        None
+    } else {
+        Some(
+            cfg.health_check_url
+                .parse()
+                .map_err(|e: String| anyhow::anyhow!(e))?,
+        )
+    };
+
+    if let Some(target) = probe_target.clone() {
+        HealthCheckerBuilder::default()
+            .interfaces(interfaces.clone())
+            .modems(modems.clone())
+            .target(target)
+            .registry(health.clone())
+            .rotation(rotation.clone())
+            .history(ip_history.clone())
+            .events(events.clone())
+            .logger(logger.clone())
+            .fingerprint(DEFAULT_FINGERPRINT)
+            .interval(Duration::from_secs(cfg.health_check_interval.max(1)))
+            .timeout(Duration::from_secs(cfg.health_check_timeout.max(1)))
+            .failure_threshold(cfg.health_check_failures.max(1))
+            .reboot_backoff(Duration::from_secs(cfg.health_reboot_backoff))
+            .build()
+            .expect("invalid health checker configuration")
+            .spawn();
+        info!(logger, "Health checker started"; "url" => &cfg.health_check_url);
+    }
+
+    // runs without policies too, so reloads can add them
+    info!(logger, "Rotation scheduler started";
+        "policies" => rotation_policies.get().len(),
+    );
+    RotatorBuilder::default()
+        .policies(rotation_policies)
+        .modems(modems.clone())
+        .registry(rotation.clone())
+        .usage(usage.clone())
+        .logger(logger.clone())
+        .probe(probe_target)
+        .interfaces(interfaces.clone())
+        .fingerprint(DEFAULT_FINGERPRINT)
+        .probe_timeout(Duration::from_secs(cfg.health_check_timeout.max(1)))
+        .history(ip_history.clone())
+        .events(events.clone())
+        .build()
+        .expect("invalid rotator configuration")
+        .spawn();
+
+    if !cfg.sms_webhooks.is_empty() {
+        let webhooks = cfg
+            .sms_webhooks
+            .iter()
+            .map(|url| {
+                url.parse()
+                    .map_err(|e| anyhow::anyhow!("invalid SMS webhook URL: {}", e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let secret = if cfg.sms_webhook_secret_file.is_empty() {
+            None
+        } else {
+            Some(read_secret_file(&cfg.sms_webhook_secret_file)?)
+        };
+        SmsPollerBuilder::default()
+            .interfaces(interfaces.clone())
+            .modems(modems)
+            .webhooks(webhooks)
+            .secret(secret)
+            .after_delivery(cfg.sms_after_delivery)
+            .dead_letter(Some(cfg.sms_dead_letter_file.clone().into()))
+            .events(events.clone())
+            .logger(logger.clone())
+            .interval(Duration::from_secs(cfg.sms_poll_interval.max(1)))
+            .build()
+            .expect("invalid SMS poller configuration")
+            .spawn()?;
+        info!(logger, "SMS poller started"; "webhooks" => cfg.sms_webhooks.len());
+    }
+
+    let upstreams = if cfg.upstreams_file.is_empty() {
+        Upstreams::default()
+    } else {
+        Upstreams::from_file(&cfg.upstreams_file)?
+    };
 
     let socks5_addr = SocketAddr::from(([0, 0, 0, 0], cfg.port_socks5));
 
-    let socks5_server = Socks5Builder::default()
+    let fingerprints = device_settings
+        .iter()
+        .filter_map(|(id, settings)| Some((id.clone(), settings.fingerprint?)))
+        .collect();
+
+    let socks5_builder = Socks5Builder::default()
         .fingerprint(DEFAULT_FINGERPRINT)
+        .fingerprints(fingerprints)
         .listen_addr(socks5_addr)
-        .iface_map(ifaces.clone())
+        .iface_map(interfaces)
+        .aliases(aliases)
         .logger(logger.clone())
+        .usage(usage.clone())
+        .acl(acl)
+        .users(users)
+        .upstreams(Arc::new(upstreams))
+        .health(health)
+        .rotation(rotation)
+        .http_connect(cfg.proxy_http_connect)
+        .allowed_sources(SourceAllowlist::new(cfg.allowed_sources))
+        .proxy_protocol(
+            (!cfg.proxy_protocol_trusted.is_empty())
+                .then(|| SourceAllowlist::new(cfg.proxy_protocol_trusted)),
+        )
+        .handshake_timeout(seconds(cfg.timeout_socks5_handshake))
+        .connect_timeout(seconds(cfg.timeout_socks5_connect))
+        .idle_timeout(seconds(cfg.timeout_socks5_idle))
+        .shutdown(shutdown.clone())
+        .drain_timeout(seconds(cfg.drain_timeout));
+
+    let mut tls_task = None;
+    if let Some(tls) = proxy_tls {
+        let tls_addr = SocketAddr::from(([0, 0, 0, 0], cfg.port_proxy_tls));
+        let tls_server = socks5_builder
+            .clone()
+            .listen_addr(tls_addr)
+            .tls(Some(tls))
+            .http_connect(true)
+            .build()
+            .expect("invalid SOCKS5 builder configuration");
+        let tls_logger = logger.clone();
+        tls_task = Some(tokio::spawn(async move {
+            if let Err(e) = tls_server.run().await {
+                error!(tls_logger, "TLS proxy error"; "error" => %e);
+            }
+        }));
+        info!(logger, "TLS Proxy Started"; "addr" => %tls_addr);
+    }
+
+    let socks5_server = socks5_builder
         .build()
         .expect("invalid SOCKS5 builder configuration");
 
@@ -135,6 +871,24 @@ async fn main() -> Result<()> {
     if let Err(e) = socks5_server.run().await {
         error!(logger, "socks5 error"; "error" => %e);
     };
+    if let Some(task) = tls_task {
+        let _ = task.await;
+    }
+    info!(logger, "Proxies drained");
+
+    api_shutdown.cancel();
+    let _ = api_task.await;
+    info!(logger, "API stopped");
+
+    if let Err(e) = usage.flush() {
+        error!(logger, "flush usage"; "error" => %e);
+    }
+    if let Err(e) = ip_history.flush() {
+        error!(logger, "flush IP history"; "error" => %e);
+    }
+
+    let _ = shutdown_metrics.send(());
+    info!(logger, "Shutdown complete");
 
     Ok(())
 }